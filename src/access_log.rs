@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use hyper::{Method, StatusCode, Version};
+use serde_derive::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Off,
+    /// The NCSA common log format with the duration in milliseconds appended.
+    Common,
+    /// One JSON object per request.
+    Json,
+}
+
+/// The target of the events access log lines are logged as.
+pub const TARGET: &str = "access_log";
+
+/// One handled request.
+pub struct Entry<'a> {
+    /// When the request was received.
+    pub time: DateTime<Utc>,
+    pub client_ip: IpAddr,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub version: Version,
+    /// `None` if the request failed without a response being sent.
+    pub status: Option<StatusCode>,
+    /// `None` if the size of the body isn't known up front.
+    pub bytes: Option<u64>,
+    pub duration: Duration,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    client_ip: IpAddr,
+    method: &'a str,
+    path: &'a str,
+    status: Option<u16>,
+    bytes: Option<u64>,
+    duration_ms: u64,
+}
+
+impl Entry<'_> {
+    pub fn format(&self, format: AccessLogFormat) -> Option<String> {
+        match format {
+            AccessLogFormat::Off => None,
+            AccessLogFormat::Common => Some(self.common()),
+            AccessLogFormat::Json => Some(self.json()),
+        }
+    }
+
+    fn common(&self) -> String {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_owned());
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} {}",
+            self.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.path,
+            self.version,
+            or_dash(self.status.map(|status| status.as_str().to_owned())),
+            or_dash(self.bytes.map(|bytes| bytes.to_string())),
+            self.duration.as_millis(),
+        )
+    }
+
+    fn json(&self) -> String {
+        serde_json::to_string(&JsonEntry {
+            time: self.time.to_rfc3339(),
+            client_ip: self.client_ip,
+            method: self.method.as_str(),
+            path: self.path,
+            status: self.status.map(|status| status.as_u16()),
+            bytes: self.bytes,
+            duration_ms: self.duration.as_millis() as u64,
+        }).unwrap()
+    }
+}
+
+/// Logs `entry` as an event of its own target, which the subscriber writes
+/// with the same writer as the application logs, but as it is.
+pub fn log(format: AccessLogFormat, entry: &Entry) {
+    if let Some(line) = entry.format(format) {
+        tracing::info!(target: TARGET, "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_format() {
+        let entry = Entry {
+            time: Utc.with_ymd_and_hms(2020, 10, 10, 13, 55, 36).unwrap(),
+            client_ip: "127.0.0.1".parse().unwrap(),
+            method: &Method::GET,
+            path: "/basic",
+            version: Version::HTTP_11,
+            status: Some(StatusCode::OK),
+            bytes: Some(15),
+            duration: Duration::from_millis(12),
+        };
+
+        assert_eq!(entry.format(AccessLogFormat::Off), None);
+        assert_eq!(
+            entry.format(AccessLogFormat::Common).unwrap(),
+            r#"127.0.0.1 - - [10/Oct/2020:13:55:36 +0000] "GET /basic HTTP/1.1" 200 15 12"#
+        );
+        assert_eq!(
+            entry.format(AccessLogFormat::Json).unwrap(),
+            r#"{"time":"2020-10-10T13:55:36+00:00","client_ip":"127.0.0.1","method":"GET","path":"/basic","status":200,"bytes":15,"duration_ms":12}"#
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use crate::config::{AdminCredentials, ApiKeys, ServerCfg};
+use crate::error::AppError;
+use crate::jwt::Claims;
+use crate::state::AppState;
+use crate::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+
+pub const X_API_KEY: &str = "x-api-key";
+
+/// Checks the credentials `route` needs, if any: an API key or, failing
+/// that, a JWT. Returns the claims of the JWT if one was used.
+pub async fn authenticate(headers: &HeaderMap, route: &str, cfg: &ServerCfg, state: &AppState) -> Result<Option<Claims>> {
+    let needs_jwt = cfg.jwt.protects(route);
+    if cfg.api_keys.protects(route) {
+        match api_key(headers, &cfg.api_keys) {
+            Ok(name) => {
+                state.metrics.api_key_requests.with_label_values(&[name]).inc();
+                return Ok(None);
+            }
+            Err(err) if !needs_jwt => return Err(err),
+            Err(_) => {}
+        }
+    }
+    if !needs_jwt {
+        return Ok(None);
+    }
+    let token = bearer(headers).ok_or(AppError::Unauthorized)?;
+    let claims = state.jwt.verify(token, &cfg.jwt, &state.client).await?;
+    Ok(Some(claims))
+}
+
+/// Checks the Basic auth credentials the admin endpoints need; without any
+/// configured the admin endpoints aren't served at all.
+pub fn admin(headers: &HeaderMap, credentials: Option<&AdminCredentials>) -> Result<()> {
+    let credentials = credentials.ok_or(AppError::NotFound)?;
+    let sent = headers.get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value.trim()).ok())
+        .ok_or(AppError::AdminUnauthorized)?;
+    let colon = sent.iter().position(|b| *b == b':').ok_or(AppError::AdminUnauthorized)?;
+    let (username, password) = (&sent[..colon], &sent[colon + 1..]);
+    // Checks both so the time taken doesn't tell whether the username was
+    // right.
+    let username_ok = constant_time_eq(username, credentials.username.as_bytes());
+    let password_ok = constant_time_eq(password, credentials.password.expose().as_bytes());
+    if !(username_ok & password_ok) {
+        return Err(AppError::AdminUnauthorized);
+    }
+    Ok(())
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers.get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// API keys as they're compared, both the configured ones and those sent:
+/// surrounding whitespace, like the newline a key file ends with, isn't part
+/// of the key.
+pub fn normalize_api_key(key: &str) -> &str {
+    key.trim()
+}
+
+/// The name of the API key a request was sent with, from either `X-Api-Key`
+/// or a bearer token.
+fn api_key<'a>(headers: &HeaderMap, api_keys: &'a ApiKeys) -> Result<&'a str> {
+    let sent = headers.get(X_API_KEY)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| bearer(headers))
+        .map(normalize_api_key)
+        .ok_or(AppError::Unauthorized)?;
+    // Compares against every key so the time taken doesn't tell which ones
+    // exist.
+    let mut found = None;
+    for (name, key) in &api_keys.keys {
+        if constant_time_eq(sent.as_bytes(), key.expose().as_bytes()) {
+            found = Some(name.as_str());
+        }
+    }
+    found.ok_or(AppError::Unauthorized)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::Secret;
+
+    #[test]
+    fn test_api_key() {
+        let api_keys = ApiKeys {
+            keys: vec![("mobile".to_owned(), Secret::from("s3cret"))],
+            routes: Vec::new(),
+        };
+        let headers = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(api_key(&headers(X_API_KEY, "s3cret"), &api_keys).unwrap(), "mobile");
+        assert_eq!(api_key(&headers("authorization", "Bearer s3cret"), &api_keys).unwrap(), "mobile");
+        assert_eq!(api_key(&headers(X_API_KEY, " s3cret "), &api_keys).unwrap(), "mobile");
+        assert!(matches!(api_key(&headers(X_API_KEY, "s3cre"), &api_keys), Err(AppError::Unauthorized)));
+        assert!(matches!(api_key(&HeaderMap::new(), &api_keys), Err(AppError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_admin() {
+        let credentials = AdminCredentials { username: "ops".to_owned(), password: Secret::from("pa:ss") };
+        let basic = |user_pass: &str| {
+            let mut headers = HeaderMap::new();
+            let value = format!("Basic {}", STANDARD.encode(user_pass));
+            headers.insert(AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+
+        assert!(admin(&basic("ops:pa:ss"), Some(&credentials)).is_ok());
+        for headers in [basic("ops:pass"), basic("admin:pa:ss"), basic("ops"), HeaderMap::new()] {
+            assert!(matches!(admin(&headers, Some(&credentials)), Err(AppError::AdminUnauthorized)));
+        }
+        // Without credentials configured the admin endpoints are hidden.
+        assert!(matches!(admin(&basic("ops:pa:ss"), None), Err(AppError::NotFound)));
+    }
+}
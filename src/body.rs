@@ -0,0 +1,115 @@
+use crate::error::AppError;
+use crate::Result;
+use futures::stream::StreamExt;
+use hyper::body::{to_bytes, Bytes};
+use crate::ws;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Request};
+use std::error::Error;
+use std::fmt;
+
+/// The error a limited body fails with once it exceeds its limit.
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request body too large")
+    }
+}
+
+impl Error for BodyTooLarge {}
+
+/// Rejects requests that declare a body larger than `max_bytes`, and makes
+/// reading the body of the others fail once it exceeds that, so that
+/// handlers can read bodies without trusting the client.
+pub fn limit(req: Request<Body>, max_bytes: u64) -> Result<Request<Body>> {
+    let declared = req.headers().get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max_bytes) {
+        return Err(AppError::PayloadTooLarge(max_bytes));
+    }
+    // What follows a WebSocket handshake is the WebSocket's, and hyper hands
+    // the connection over through the body, so it's left as it is. Other
+    // requests asking to upgrade don't get one, and are limited like any.
+    if ws::is_handshake(&req) {
+        return Ok(req);
+    }
+    let (parts, body) = req.into_parts();
+    let mut read = 0;
+    let body = body.map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len() as u64;
+        match read > max_bytes {
+            true => Err(Box::new(BodyTooLarge) as Box<dyn Error + Send + Sync>),
+            false => Ok(chunk),
+        }
+    });
+    Ok(Request::from_parts(parts, Body::wrap_stream(body)))
+}
+
+/// Reads a whole body, which fails with a 413 if it was limited by `limit`
+/// and turned out too large.
+pub async fn read(body: Body, max_bytes: u64) -> Result<Bytes> {
+    to_bytes(body).await.map_err(|err| {
+        match err.source().is_some_and(|source| source.is::<BodyTooLarge>()) {
+            true => AppError::PayloadTooLarge(max_bytes),
+            false => err.into(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use hyper::header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, TRANSFER_ENCODING, UPGRADE};
+    use hyper::Method;
+    use tokio::runtime::Runtime;
+
+    fn chunked(chunks: &[&'static str]) -> Request<Body> {
+        let chunks: Vec<std::result::Result<_, std::io::Error>> = chunks.iter().map(|chunk| Ok(*chunk)).collect();
+        Request::new(Body::wrap_stream(stream::iter(chunks)))
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut rt = Runtime::new().unwrap();
+
+        let req = limit(chunked(&["1234", "5678"]), 8).unwrap();
+        assert_eq!(rt.block_on(read(req.into_body(), 8)).unwrap(), "12345678");
+
+        let req = limit(chunked(&["1234", "5678", "9"]), 8).unwrap();
+        assert!(matches!(rt.block_on(read(req.into_body(), 8)), Err(AppError::PayloadTooLarge(8))));
+
+        let req = Request::builder().header(CONTENT_LENGTH, "9").body(Body::empty()).unwrap();
+        assert!(matches!(limit(req, 8), Err(AppError::PayloadTooLarge(8))));
+    }
+
+    #[test]
+    fn test_limit_upgrade() {
+        let mut rt = Runtime::new().unwrap();
+        let upgrade = |method: Method, path: &str| {
+            let mut req = chunked(&["1234", "5678", "9"]);
+            *req.method_mut() = method;
+            *req.uri_mut() = path.parse().unwrap();
+            let headers = req.headers_mut();
+            headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+            headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+            headers.insert(SEC_WEBSOCKET_KEY, HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="));
+            req
+        };
+
+        // Only a handshake, without a body, is left unlimited.
+        let mut req = upgrade(Method::GET, "/ws");
+        assert!(ws::is_handshake(&req));
+        req.headers_mut().insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        let requests = [req, upgrade(Method::POST, "/ws"), upgrade(Method::GET, "/basic")];
+        for req in requests {
+            let req = limit(req, 8).unwrap();
+            assert!(matches!(rt.block_on(read(req.into_body(), 8)), Err(AppError::PayloadTooLarge(8))));
+        }
+    }
+}
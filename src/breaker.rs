@@ -0,0 +1,178 @@
+use crate::config::BreakerPolicy;
+use crate::metrics::Metrics;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+/// Where an upstream's circuit is at. Exported as a gauge with the values in
+/// parentheses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go through (0).
+    Closed,
+    /// Requests fail fast until the policy's `open_for` has passed (1).
+    Open,
+    /// A single probe went through to decide whether to close the circuit
+    /// again (2).
+    HalfOpen,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Circuit {
+    pub state: CircuitState,
+    /// Consecutive failures seen while closed.
+    pub failures: u32,
+    /// When the circuit was opened or the last probe was let through.
+    #[serde(skip)]
+    since: Instant,
+}
+
+impl Default for Circuit {
+    fn default() -> Circuit {
+        Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            since: Instant::now(),
+        }
+    }
+}
+
+/// Returned instead of sending a request to an upstream whose circuit is
+/// open.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub upstream: &'static str,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "circuit for upstream {} is open", self.upstream)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// The circuits of all upstreams. Policies come with each call so that they
+/// follow config reloads while the circuits themselves don't reset.
+pub struct Breakers {
+    circuits: Mutex<BTreeMap<&'static str, Circuit>>,
+    state: IntGaugeVec,
+    rejections: IntCounterVec,
+}
+
+impl Breakers {
+    pub fn new(metrics: &Metrics) -> Breakers {
+        Breakers {
+            circuits: Mutex::new(BTreeMap::new()),
+            state: metrics.upstream_circuit_state.clone(),
+            rejections: metrics.upstream_circuit_rejections.clone(),
+        }
+    }
+
+    /// Checks whether a request to `upstream` may be sent. Once an open
+    /// circuit has been open for long enough, one probe request is let
+    /// through every `open_for` until one succeeds, so a probe that never
+    /// finishes can't keep the circuit open forever.
+    pub fn acquire(&self, upstream: &'static str, policy: &BreakerPolicy) -> Result<(), CircuitOpen> {
+        if policy.failures == 0 {
+            return Ok(());
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(upstream).or_default();
+        match circuit.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open | CircuitState::HalfOpen if circuit.since.elapsed() >= policy.open_for => {
+                circuit.state = CircuitState::HalfOpen;
+                circuit.since = Instant::now();
+                self.state.with_label_values(&[upstream]).set(2);
+                Ok(())
+            }
+            CircuitState::Open | CircuitState::HalfOpen => {
+                self.rejections.with_label_values(&[upstream]).inc();
+                Err(CircuitOpen { upstream })
+            }
+        }
+    }
+
+    /// Records the outcome of a request that `acquire` let through.
+    pub fn record(&self, upstream: &'static str, policy: &BreakerPolicy, success: bool) {
+        if policy.failures == 0 {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(upstream).or_default();
+        if success {
+            if circuit.state != CircuitState::Closed {
+                info!(upstream, "closing circuit");
+            }
+            circuit.state = CircuitState::Closed;
+            circuit.failures = 0;
+            self.state.with_label_values(&[upstream]).set(0);
+            return;
+        }
+        match circuit.state {
+            CircuitState::Closed => {
+                circuit.failures += 1;
+                if circuit.failures < policy.failures {
+                    return;
+                }
+                warn!(upstream, failures = circuit.failures, "opening circuit");
+            }
+            CircuitState::HalfOpen => warn!(upstream, "probe failed, reopening circuit"),
+            // A request let through before the circuit opened.
+            CircuitState::Open => return,
+        }
+        circuit.state = CircuitState::Open;
+        circuit.since = Instant::now();
+        self.state.with_label_values(&[upstream]).set(1);
+    }
+
+    /// The circuits of all upstreams that have been sent requests, by
+    /// upstream.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, Circuit> {
+        self.circuits.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_transitions() {
+        let breakers = Breakers::new(&Metrics::new());
+        let policy = BreakerPolicy {
+            failures: 2,
+            open_for: Duration::from_millis(20),
+        };
+        let state = || breakers.snapshot()["todo"].state;
+
+        breakers.acquire("todo", &policy).unwrap();
+        breakers.record("todo", &policy, false);
+        assert_eq!(state(), CircuitState::Closed);
+        breakers.record("todo", &policy, false);
+        assert_eq!(state(), CircuitState::Open);
+        assert!(breakers.acquire("todo", &policy).is_err());
+
+        std::thread::sleep(policy.open_for);
+        breakers.acquire("todo", &policy).unwrap();
+        assert_eq!(state(), CircuitState::HalfOpen);
+        // Only one probe at a time.
+        assert!(breakers.acquire("todo", &policy).is_err());
+        breakers.record("todo", &policy, false);
+        assert_eq!(state(), CircuitState::Open);
+
+        std::thread::sleep(policy.open_for);
+        breakers.acquire("todo", &policy).unwrap();
+        breakers.record("todo", &policy, true);
+        assert_eq!(state(), CircuitState::Closed);
+        assert_eq!(breakers.snapshot()["todo"].failures, 0);
+    }
+}
@@ -0,0 +1,511 @@
+use crate::client::Fetched;
+use crate::config::{CacheBackend, CacheCfg, CachePolicy};
+use crate::Result;
+use futures::future::{self, BoxFuture};
+use hyper::header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use moka::sync::Cache;
+use moka::Expiry;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use utoipa::ToSchema;
+
+/// What an upstream response's headers say about caching it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    /// Nothing, so the upstream's configured TTL applies.
+    Unspecified,
+    /// Fresh for this much longer, which may be nothing.
+    For(Duration),
+    /// Must not be stored by a shared cache.
+    Uncacheable,
+}
+
+/// Reads `Cache-Control`, preferring `s-maxage` since this is a shared cache,
+/// and `Expires`, accounting for the `Age` of the response.
+pub fn freshness(headers: &HeaderMap) -> Freshness {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let directives = headers.get_all(CACHE_CONTROL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for directive in directives {
+        let mut parts = directive.trim().splitn(2, '=');
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let value = parts.next().map(|value| value.trim_matches('"'));
+        match (name.as_str(), value) {
+            ("no-store", _) | ("no-cache", _) | ("private", _) => return Freshness::Uncacheable,
+            ("max-age", Some(secs)) => max_age = secs.parse::<u64>().ok(),
+            ("s-maxage", Some(secs)) => s_maxage = secs.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+    if let Some(secs) = s_maxage.or(max_age) {
+        let age = headers.get(AGE)
+            .and_then(|age| age.to_str().ok())
+            .and_then(|age| age.parse().ok())
+            .unwrap_or(0);
+        return Freshness::For(Duration::from_secs(secs.saturating_sub(age)));
+    }
+    if let Some(expires) = headers.get(EXPIRES) {
+        // Invalid dates, like the common `0`, mean already expired.
+        let expires = expires.to_str().ok().and_then(|expires| httpdate::parse_http_date(expires).ok());
+        let left = expires.and_then(|expires| expires.duration_since(SystemTime::now()).ok());
+        return Freshness::For(left.unwrap_or_default());
+    }
+    Freshness::Unspecified
+}
+
+/// What an upstream response can be revalidated with once it expires.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<HeaderValue>,
+    pub last_modified: Option<HeaderValue>,
+}
+
+impl Validators {
+    pub fn new(headers: &HeaderMap) -> Validators {
+        Validators {
+            etag: headers.get(ETAG).cloned(),
+            last_modified: headers.get(LAST_MODIFIED).cloned(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Makes a request with `headers` conditional on the response having
+    /// changed since.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = &self.etag {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    /// These, updated with those of a `304 Not Modified`.
+    pub fn update(&self, newer: Validators) -> Validators {
+        Validators {
+            etag: newer.etag.or_else(|| self.etag.clone()),
+            last_modified: newer.last_modified.or_else(|| self.last_modified.clone()),
+        }
+    }
+}
+
+impl CachePolicy {
+    /// How long to cache a response for, if at all.
+    pub fn ttl(&self, freshness: Freshness) -> Option<Duration> {
+        let ttl = match freshness {
+            Freshness::Unspecified => self.ttl,
+            Freshness::For(ttl) => ttl.max(self.min_ttl).min(self.max_ttl),
+            Freshness::Uncacheable => return None,
+        };
+        Some(ttl).filter(|ttl| *ttl > Duration::from_secs(0))
+    }
+}
+
+/// A cached response as listed at `/admin/cache`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CacheEntry {
+    pub key: String,
+    pub age_ms: u64,
+    pub expires_in_ms: u64,
+}
+
+impl CacheEntry {
+    fn new(key: &str, inserted_at: Instant, expires_at: Instant) -> CacheEntry {
+        let now = Instant::now();
+        CacheEntry {
+            key: key.to_owned(),
+            age_ms: now.saturating_duration_since(inserted_at).as_millis() as u64,
+            expires_in_ms: expires_at.saturating_duration_since(now).as_millis() as u64,
+        }
+    }
+}
+
+/// Where successful upstream responses are kept, by uri, each for its
+/// upstream's TTL. A store that fails treats it as a miss rather than failing
+/// the request.
+pub trait CacheStore: Send + Sync {
+    /// The response cached for `uri` unless it has expired.
+    fn get<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Option<Fetched>>;
+
+    fn insert<'a>(&'a self, uri: &'a str, fetched: Fetched, ttl: Duration) -> BoxFuture<'a, ()>;
+
+    /// The entries that haven't expired, in no particular order.
+    fn entries(&self) -> BoxFuture<'_, Vec<CacheEntry>>;
+
+    /// Returns whether there was an entry for `uri`.
+    fn remove<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, bool>;
+
+    fn clear(&self) -> BoxFuture<'_, ()>;
+}
+
+pub type ResponseCache = Box<dyn CacheStore>;
+
+/// The part of `max_entries` and `max_bytes` kept for [`Revalidations`], as
+/// a fraction: a quarter.
+const REVALIDATIONS_SHARE: u64 = 4;
+
+/// How `max_entries` and `max_bytes` are split, as `(entries, bytes)`, between
+/// the responses that are still fresh and the [`Revalidations`], so that both
+/// together stay within the configured limits.
+fn budgets(cfg: &CacheCfg) -> ((u64, u64), (u64, u64)) {
+    let entries = (cfg.max_entries / REVALIDATIONS_SHARE).max(1);
+    let bytes = (cfg.max_bytes / REVALIDATIONS_SHARE).max(1);
+    let fresh = (cfg.max_entries.saturating_sub(entries).max(1), cfg.max_bytes.saturating_sub(bytes).max(1));
+    (fresh, (entries, bytes))
+}
+
+pub fn new_cache(cfg: &CacheCfg) -> Result<ResponseCache> {
+    Ok(match cfg.backend {
+        CacheBackend::Memory => Box::new(MemoryStore::default()),
+        CacheBackend::Lru => {
+            let ((max_entries, max_bytes), _) = budgets(cfg);
+            Box::new(LruStore::new(max_entries, max_bytes))
+        }
+        #[cfg(feature = "redis-cache")]
+        CacheBackend::Redis => Box::new(crate::redis_cache::RedisStore::new(cfg)?),
+    })
+}
+
+/// Below this many entries, inserting never sweeps out the expired ones.
+const MIN_SWEEP_LEN: usize = 64;
+
+/// Keeps everything until it expires, however much that is. Expired entries
+/// are dropped when looked up, and all at once whenever inserting has doubled
+/// the number of entries since the last sweep, so the ones that are never
+/// looked up again don't pile up.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    stored: HashMap<String, Stored>,
+    /// How many entries there can be before the next sweep.
+    sweep_at: usize,
+}
+
+struct Stored {
+    inserted_at: Instant,
+    expires_at: Instant,
+    fetched: Fetched,
+}
+
+impl MemoryStore {
+    fn lookup(&self, uri: &str) -> Option<Fetched> {
+        let stored = &mut self.entries.lock().unwrap().stored;
+        match stored.get(uri) {
+            Some(entry) if Instant::now() < entry.expires_at => Some(entry.fetched.clone()),
+            Some(_) => {
+                stored.remove(uri);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+impl Entries {
+    fn insert(&mut self, uri: &str, entry: Stored) {
+        if self.stored.len() >= self.sweep_at {
+            let now = Instant::now();
+            self.stored.retain(|_, entry| now < entry.expires_at);
+            self.sweep_at = (self.stored.len() * 2).max(MIN_SWEEP_LEN);
+        }
+        self.stored.insert(uri.to_owned(), entry);
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Option<Fetched>> {
+        Box::pin(future::ready(self.lookup(uri)))
+    }
+
+    fn insert<'a>(&'a self, uri: &'a str, fetched: Fetched, ttl: Duration) -> BoxFuture<'a, ()> {
+        let inserted_at = Instant::now();
+        let stored = Stored { inserted_at, expires_at: inserted_at + ttl, fetched };
+        self.entries.lock().unwrap().insert(uri, stored);
+        Box::pin(future::ready(()))
+    }
+
+    fn entries(&self) -> BoxFuture<'_, Vec<CacheEntry>> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap().stored.iter()
+            .filter(|(_, stored)| now < stored.expires_at)
+            .map(|(uri, stored)| CacheEntry::new(uri, stored.inserted_at, stored.expires_at))
+            .collect();
+        Box::pin(future::ready(entries))
+    }
+
+    fn remove<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, bool> {
+        let removed = self.entries.lock().unwrap().stored.remove(uri);
+        Box::pin(future::ready(removed.is_some_and(|stored| Instant::now() < stored.expires_at)))
+    }
+
+    fn clear(&self) -> BoxFuture<'_, ()> {
+        self.entries.lock().unwrap().stored.clear();
+        Box::pin(future::ready(()))
+    }
+}
+
+/// Evicts the least valuable entries, as judged by moka's TinyLFU policy, to
+/// stay within both a number of entries and an approximate size.
+pub struct LruStore {
+    cache: Cache<String, Timed>,
+}
+
+/// A response along with when it was inserted and for how long.
+#[derive(Clone)]
+struct Timed {
+    inserted_at: Instant,
+    ttl: Duration,
+    fetched: Fetched,
+}
+
+impl LruStore {
+    /// Every entry weighs at least `max_bytes / max_entries`, so the
+    /// capacity in bytes also caps the number of entries.
+    pub fn new(max_entries: u64, max_bytes: u64) -> LruStore {
+        let min_weight = max_bytes / max_entries.max(1);
+        let cache = Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(move |uri: &String, timed: &Timed| {
+                let size = (uri.len() + timed.fetched.body.len()) as u64;
+                size.max(min_weight).min(u32::MAX as u64) as u32
+            })
+            .expire_after(PerEntryTtl)
+            .build();
+        LruStore { cache }
+    }
+}
+
+impl CacheStore for LruStore {
+    fn get<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Option<Fetched>> {
+        Box::pin(future::ready(self.cache.get(uri).map(|timed| timed.fetched)))
+    }
+
+    fn insert<'a>(&'a self, uri: &'a str, fetched: Fetched, ttl: Duration) -> BoxFuture<'a, ()> {
+        self.cache.insert(uri.to_owned(), Timed { inserted_at: Instant::now(), ttl, fetched });
+        Box::pin(future::ready(()))
+    }
+
+    fn entries(&self) -> BoxFuture<'_, Vec<CacheEntry>> {
+        let entries = self.cache.iter()
+            .map(|(uri, timed)| CacheEntry::new(&uri, timed.inserted_at, timed.inserted_at + timed.ttl))
+            .collect();
+        Box::pin(future::ready(entries))
+    }
+
+    fn remove<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, bool> {
+        Box::pin(future::ready(self.cache.remove(uri).is_some()))
+    }
+
+    fn clear(&self) -> BoxFuture<'_, ()> {
+        self.cache.invalidate_all();
+        Box::pin(future::ready(()))
+    }
+}
+
+/// The last response with validators fetched for each uri, kept past its
+/// expiry so that refreshing it can be a conditional request. Always local,
+/// whatever the cache's backend, and bounded like the `lru` one by a quarter
+/// of the cache's limits, the `lru` store getting the rest.
+pub struct Revalidations {
+    cache: Cache<String, Fetched>,
+}
+
+impl Revalidations {
+    pub fn new(cfg: &CacheCfg) -> Revalidations {
+        let (_, (max_entries, max_bytes)) = budgets(cfg);
+        let min_weight = max_bytes / max_entries;
+        let cache = Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(move |uri: &String, fetched: &Fetched| {
+                let size = (uri.len() + fetched.body.len()) as u64;
+                size.max(min_weight).min(u32::MAX as u64) as u32
+            })
+            .build();
+        Revalidations { cache }
+    }
+
+    pub fn get(&self, uri: &str) -> Option<Fetched> {
+        self.cache.get(uri)
+    }
+
+    /// Keeps `fetched` if it can be revalidated at all.
+    pub fn insert(&self, uri: &str, fetched: &Fetched) {
+        if !fetched.validators.is_empty() {
+            self.cache.insert(uri.to_owned(), fetched.clone());
+        }
+    }
+
+    pub fn remove(&self, uri: &str) {
+        self.cache.invalidate(uri);
+    }
+
+    pub fn clear(&self) {
+        self.cache.invalidate_all();
+    }
+}
+
+struct PerEntryTtl;
+
+impl Expiry<String, Timed> for PerEntryTtl {
+    fn expire_after_create(&self, _: &String, timed: &Timed, _: Instant) -> Option<Duration> {
+        Some(timed.ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+    use tokio::runtime::Runtime;
+
+    fn fetched(body: &'static str) -> Fetched {
+        Fetched {
+            status: StatusCode::OK,
+            body: body.into(),
+            freshness: Freshness::Unspecified,
+            validators: Validators::default(),
+        }
+    }
+
+    fn headers(headers: &[(&'static str, &str)]) -> HeaderMap {
+        headers.iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_freshness() {
+        assert_eq!(freshness(&headers(&[])), Freshness::Unspecified);
+        assert_eq!(
+            freshness(&headers(&[("cache-control", "public, max-age=60")])),
+            Freshness::For(Duration::from_secs(60)),
+        );
+        assert_eq!(
+            freshness(&headers(&[("cache-control", "max-age=60, s-maxage=30"), ("age", "10")])),
+            Freshness::For(Duration::from_secs(20)),
+        );
+        assert_eq!(
+            freshness(&headers(&[("cache-control", "max-age=60, no-store")])),
+            Freshness::Uncacheable,
+        );
+        assert_eq!(freshness(&headers(&[("expires", "0")])), Freshness::For(Duration::from_secs(0)));
+        let expires = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        match freshness(&headers(&[("expires", &expires)])) {
+            Freshness::For(ttl) => assert!(ttl > Duration::from_secs(100)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validators() {
+        let first = Validators::new(&headers(&[("etag", r#""v1""#), ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")]));
+        let mut conditional = HeaderMap::new();
+        first.apply(&mut conditional);
+        assert_eq!(conditional[IF_NONE_MATCH], r#""v1""#);
+        assert_eq!(conditional[IF_MODIFIED_SINCE], "Wed, 21 Oct 2015 07:28:00 GMT");
+
+        let updated = first.update(Validators::new(&headers(&[("etag", r#""v2""#)])));
+        assert_eq!(updated.etag.unwrap(), r#""v2""#);
+        assert_eq!(updated.last_modified, first.last_modified);
+        assert!(Validators::new(&headers(&[])).is_empty());
+    }
+
+    #[test]
+    fn test_ttl_clamps() {
+        let policy = CachePolicy {
+            ttl: Duration::from_secs(5),
+            min_ttl: Duration::from_secs(1),
+            max_ttl: Duration::from_secs(60),
+        };
+        assert_eq!(policy.ttl(Freshness::Unspecified), Some(Duration::from_secs(5)));
+        assert_eq!(policy.ttl(Freshness::For(Duration::from_secs(0))), Some(Duration::from_secs(1)));
+        assert_eq!(policy.ttl(Freshness::For(Duration::from_secs(3600))), Some(Duration::from_secs(60)));
+        assert_eq!(policy.ttl(Freshness::Uncacheable), None);
+    }
+
+    #[test]
+    fn test_budgets() {
+        let cfg = CacheCfg { max_entries: 100, max_bytes: 4096, ..CacheCfg::default() };
+        assert_eq!(budgets(&cfg), ((75, 3072), (25, 1024)));
+        let revalidations = Revalidations::new(&cfg);
+        assert_eq!(revalidations.cache.policy().max_capacity(), Some(1024));
+        let cfg = CacheCfg { max_entries: 1, max_bytes: 1, ..CacheCfg::default() };
+        assert_eq!(budgets(&cfg), ((1, 1), (1, 1)));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut rt = Runtime::new().unwrap();
+        for cache in [new_cache(&CacheCfg::default()).unwrap(), Box::new(MemoryStore::default())] {
+            rt.block_on(async {
+                cache.insert("http://todo/todos/1", fetched("{}"), Duration::from_secs(60)).await;
+                cache.insert("http://cats/facts/random", fetched("{}"), Duration::from_millis(0)).await;
+
+                assert_eq!(cache.get("http://todo/todos/1").await.unwrap().body, "{}");
+                assert!(cache.get("http://cats/facts/random").await.is_none());
+                assert!(cache.get("http://todo/todos/2").await.is_none());
+            });
+        }
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut rt = Runtime::new().unwrap();
+        for cache in [new_cache(&CacheCfg::default()).unwrap(), Box::new(MemoryStore::default())] {
+            rt.block_on(async {
+                cache.insert("http://todo/todos/1", fetched("{}"), Duration::from_secs(60)).await;
+                cache.insert("http://todo/todos/2", fetched("{}"), Duration::from_secs(60)).await;
+                cache.insert("http://cats/facts/random", fetched("{}"), Duration::from_millis(0)).await;
+                let mut keys: Vec<_> = cache.entries().await.into_iter().map(|entry| entry.key).collect();
+                keys.sort();
+                assert_eq!(keys, ["http://todo/todos/1", "http://todo/todos/2"]);
+
+                assert!(cache.remove("http://todo/todos/1").await);
+                assert!(!cache.remove("http://todo/todos/1").await);
+                assert!(cache.get("http://todo/todos/1").await.is_none());
+                assert!(cache.get("http://todo/todos/2").await.is_some());
+
+                cache.clear().await;
+                assert!(cache.get("http://todo/todos/2").await.is_none());
+            });
+        }
+    }
+
+    #[test]
+    fn test_memory_sweep() {
+        let mut rt = Runtime::new().unwrap();
+        let store = MemoryStore::default();
+        rt.block_on(async {
+            for i in 0..1000 {
+                store.insert(&format!("http://todo/todos/{}", i), fetched("{}"), Duration::from_millis(0)).await;
+            }
+            store.insert("http://todo/todos/kept", fetched("{}"), Duration::from_secs(60)).await;
+        });
+        // The expired entries were swept out without being looked up.
+        assert!(store.entries.lock().unwrap().stored.len() <= MIN_SWEEP_LEN);
+        assert!(rt.block_on(store.get("http://todo/todos/kept")).is_some());
+    }
+
+    #[test]
+    fn test_bounded() {
+        let store = LruStore::new(10, 1024);
+        for i in 0..100 {
+            let timed = Timed { inserted_at: Instant::now(), ttl: Duration::from_secs(60), fetched: fetched("{}") };
+            store.cache.insert(format!("http://todo/todos/{}", i), timed);
+        }
+        store.cache.run_pending_tasks();
+        assert!(store.cache.entry_count() <= 10);
+        assert!(store.cache.weighted_size() <= 1024);
+    }
+}
@@ -0,0 +1,641 @@
+use crate::cache::{freshness, Freshness, Validators};
+use crate::config::{ClientCfg, Http2, RetryPolicy, UpstreamCfg, UpstreamTlsCfg};
+use crate::deadline;
+use crate::dns::Resolver;
+use crate::egress::{ProxyConnector, Proxies};
+use crate::metrics::Metrics;
+use crate::propagation;
+use crate::state::AppState;
+use crate::error::{AppError, BoxError};
+use crate::singleflight::Singleflight;
+use crate::tls::{self, UpstreamConnector};
+use crate::Result;
+use futures::future::{select, BoxFuture, Either};
+use futures::FutureExt;
+use hyper::service::Service;
+use hyper::body::{to_bytes, Bytes};
+use hyper::header::{HeaderMap, HeaderName, AUTHORIZATION, CONTENT_TYPE, COOKIE, LOCATION, PROXY_AUTHORIZATION, RETRY_AFTER};
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Response, StatusCode, Uri};
+use prometheus::HistogramVec;
+use rand::Rng;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::{delay_for, timeout};
+use tracing::{debug, instrument, Span};
+
+pub type HttpClient = Client<TimedConnector<UpstreamConnector>>;
+
+pub fn init_client(metrics: &Metrics, cfg: &ClientCfg) -> Result<HttpClient> {
+    Ok(build_client(metrics, cfg, UpstreamConnector::new(connector(cfg)?), Http2::Off))
+}
+
+/// A client for an upstream with TLS settings of its own, like a client
+/// certificate, or that speaks HTTP/2.
+pub fn init_upstream_client(metrics: &Metrics, cfg: &ClientCfg, tls: Option<&UpstreamTlsCfg>, http2: Http2) -> Result<HttpClient> {
+    Ok(build_client(metrics, cfg, tls::upstream_connector(tls, http2, connector(cfg)?)?, http2))
+}
+
+/// Connects over TCP, through a proxy if there's one, to https uris too,
+/// which `UpstreamConnector` wraps in TLS.
+fn connector(cfg: &ClientCfg) -> Result<ProxyConnector> {
+    let mut http = HttpConnector::new_with_resolver(Resolver::new(&cfg.hosts));
+    http.enforce_http(false);
+    http.set_keepalive(Some(Duration::from_millis(cfg.tcp_keepalive_ms)).filter(|interval| *interval > Duration::from_millis(0)));
+    Ok(ProxyConnector::new(http, Proxies::new(cfg)?))
+}
+
+fn build_client(metrics: &Metrics, cfg: &ClientCfg, connector: UpstreamConnector, http2: Http2) -> HttpClient {
+    let connector = TimedConnector {
+        inner: connector,
+        connect_duration: metrics.upstream_connect_duration.clone(),
+    };
+    let idle_timeout = Some(Duration::from_millis(cfg.pool_idle_timeout_ms)).filter(|timeout| *timeout > Duration::from_millis(0));
+    Client::builder()
+        .pool_max_idle_per_host(if cfg.keep_alive { cfg.pool_max_idle_per_host } else { 0 })
+        .pool_idle_timeout(idle_timeout)
+        .http2_only(http2 == Http2::PriorKnowledge)
+        .build::<_, Body>(connector)
+}
+
+/// Records how long it takes to establish new connections, by host. Which
+/// upstream a connection is for isn't known at this level, so the connect
+/// timeout is picked up from `CONNECT_TIMEOUT` of the request that triggered
+/// the connection.
+#[derive(Clone)]
+pub struct TimedConnector<C> {
+    inner: C,
+    connect_duration: HistogramVec,
+}
+
+impl<C> Service<Uri> for TimedConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, std::result::Result<C::Response, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<std::result::Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host().unwrap_or_default().to_owned();
+        let connect_duration = self.connect_duration.clone();
+        let connect_timeout = CONNECT_TIMEOUT.try_with(|timeout| *timeout).ok();
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            let start = Instant::now();
+            let conn = match connect_timeout {
+                Some(connect_timeout) => timeout(connect_timeout, connecting).await?,
+                None => connecting.await,
+            };
+            let conn = conn.map_err(Into::into)?;
+            connect_duration.with_label_values(&[&host]).observe(start.elapsed().as_secs_f64());
+            Ok(conn)
+        })
+    }
+}
+
+/// How long one upstream request made while handling a request took.
+pub struct UpstreamTiming {
+    pub upstream: String,
+    pub status: String,
+    pub latency: Duration,
+}
+
+impl fmt::Display for UpstreamTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}ms ({})", self.upstream, self.latency.as_millis(), self.status)
+    }
+}
+
+tokio::task_local! {
+    static TIMINGS: Arc<Mutex<Vec<UpstreamTiming>>>;
+    static CONNECT_TIMEOUT: Duration;
+    static INBOUND_HEADERS: HeaderMap;
+}
+
+/// Runs `fut` and collects the timings of the upstream requests it makes.
+pub async fn record_timings<F: Future>(fut: F) -> (F::Output, Vec<UpstreamTiming>) {
+    let timings = Arc::new(Mutex::new(Vec::new()));
+    let output = TIMINGS.scope(timings.clone(), fut).await;
+    let timings = std::mem::take(&mut *timings.lock().unwrap());
+    (output, timings)
+}
+
+/// Copies the `allowed` headers from `inbound` that `headers` doesn't have
+/// already.
+fn forward_headers(inbound: &HeaderMap, headers: &mut HeaderMap, allowed: &[HeaderName]) {
+    for name in allowed {
+        if headers.contains_key(name) {
+            continue;
+        }
+        for value in inbound.get_all(name) {
+            headers.append(name, value.clone());
+        }
+    }
+}
+
+/// Whether the request being handled has credentials that `upstream`'s
+/// requests forward, like its `Authorization`.
+pub fn forwards_credentials(upstream: &UpstreamCfg) -> bool {
+    INBOUND_HEADERS.try_with(|inbound| {
+        upstream.forward_headers.iter()
+            .any(|name| [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE].contains(name) && inbound.contains_key(name))
+    }).unwrap_or(false)
+}
+
+/// Runs `fut`, the handling of a request with `headers`, so that the
+/// upstream requests it makes can forward those their upstream allows.
+pub async fn with_inbound_headers<F: Future>(headers: HeaderMap, fut: F) -> F::Output {
+    INBOUND_HEADERS.scope(headers, fut).await
+}
+
+pub(crate) fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// An upstream response read in full, so that it can be shared.
+#[derive(Clone)]
+pub struct Fetched {
+    pub status: StatusCode,
+    pub body: Bytes,
+    pub freshness: Freshness,
+    pub validators: Validators,
+}
+
+/// The upstream requests in flight, by uri, and whether they revalidated a
+/// stale response.
+pub type InFlight = Singleflight<String, std::result::Result<(Fetched, bool), Arc<AppError>>>;
+
+/// Like `do_get_req` but reads the whole response, which is cached as per the
+/// upstream's policy if successful and shared with the identical requests made
+/// while it's in flight. Once a cached response with validators expires, it's
+/// refreshed with a conditional request, and kept if the upstream answers
+/// `304 Not Modified`. Requests forwarding a client's credentials are
+/// neither cached nor shared, as one client's answers aren't another's.
+pub async fn get_coalesced(state: &AppState, upstream: &UpstreamCfg, uri: &str) -> Result<Fetched> {
+    if forwards_credentials(upstream) {
+        let res = do_conditional_get(state, upstream, uri, &Validators::default()).await?;
+        return read_fetched(upstream, res).await;
+    }
+    if let Some(policy) = &upstream.cache {
+        if let Some(fetched) = state.cache.get(uri).await {
+            state.metrics.upstream_cache.with_label_values(&[upstream.name, "hit"]).inc();
+            return Ok(fetched);
+        }
+        let (fetched, revalidated) = get_shared(state, upstream, uri, state.revalidations.get(uri)).await?;
+        let result = if revalidated { "revalidated" } else { "miss" };
+        state.metrics.upstream_cache.with_label_values(&[upstream.name, result]).inc();
+        if fetched.status.is_success() {
+            // Even responses expiring right away are worth revalidating.
+            if fetched.freshness != Freshness::Uncacheable {
+                state.revalidations.insert(uri, &fetched);
+            }
+            if let Some(ttl) = policy.ttl(fetched.freshness) {
+                state.cache.insert(uri, fetched.clone(), ttl).await;
+            }
+        }
+        return Ok(fetched);
+    }
+    Ok(get_shared(state, upstream, uri, None).await?.0)
+}
+
+/// Also returns whether `stale` was revalidated rather than replaced. The
+/// shared request only has the upstream's timeout, each caller waits for it
+/// for up to its own deadline.
+async fn get_shared(state: &AppState, upstream: &UpstreamCfg, uri: &str, stale: Option<Fetched>) -> Result<(Fetched, bool)> {
+    let fetch = async {
+        let validators = stale.as_ref().map(|stale| stale.validators.clone()).unwrap_or_default();
+        let res = deadline::detached(do_conditional_get(state, upstream, uri, &validators)).await?;
+        let freshness = freshness(res.headers());
+        let fresh = Validators::new(res.headers());
+        if let (StatusCode::NOT_MODIFIED, Some(stale)) = (res.status(), &stale) {
+            debug!("upstream response not modified");
+            let validators = stale.validators.update(fresh);
+            return Ok((Fetched { freshness, validators, ..stale.clone() }, true));
+        }
+        Ok::<_, AppError>((read_fetched(upstream, res).await?, false))
+    };
+    let run = state.in_flight.run(uri.to_owned(), async { fetch.await.map_err(Arc::new) }.boxed());
+    let (res, shared) = match deadline::remaining() {
+        Some(remaining) => timeout(remaining, run).await.map_err(|_| AppError::Timeout)?,
+        None => run.await,
+    };
+    if shared {
+        state.metrics.upstream_coalesced.with_label_values(&[upstream.name]).inc();
+    }
+    res.map_err(|err| Arc::try_unwrap(err).unwrap_or_else(AppError::Coalesced))
+}
+
+/// Sends a GET to `uri` on `upstream`, retrying connection errors and
+/// responses indicating a temporary failure as per the upstream's policy,
+/// and following redirects.
+#[instrument(skip(state, upstream), fields(upstream = upstream.name))]
+pub async fn do_get_req(state: &AppState, upstream: &UpstreamCfg, uri: &str) -> Result<Response<Body>> {
+    do_conditional_get(state, upstream, uri, &Validators::default()).await
+}
+
+/// Reads `res`, from `upstream`, in full.
+async fn read_fetched(upstream: &UpstreamCfg, res: Response<Body>) -> Result<Fetched> {
+    let freshness = freshness(res.headers());
+    let validators = Validators::new(res.headers());
+    let status = res.status();
+    let body = to_bytes(res.into_body()).await
+        .map_err(|err| AppError::upstream_bad_body(upstream.name, err))?;
+    Ok(Fetched { status, body, freshness, validators })
+}
+
+/// Like `do_get_req`, but only asks for the response if it has changed since
+/// the one with `validators`.
+async fn do_conditional_get(state: &AppState, upstream: &UpstreamCfg, uri: &str, validators: &Validators) -> Result<Response<Body>> {
+    within_budget(upstream, get_following(state, upstream, uri, validators)).await
+}
+
+/// Runs `fut`, a request to `upstream`, for up to the upstream's timeout or
+/// what's left of the client's deadline, whichever is shorter. Running out
+/// of the latter times out the whole request.
+async fn within_budget<T>(upstream: &UpstreamCfg, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    let (budget, is_deadline) = deadline::budget(upstream.timeout);
+    timeout(budget, fut).await.map_err(|_| match is_deadline {
+        true => AppError::Timeout,
+        false => AppError::UpstreamTimeout(upstream.name),
+    })?
+}
+
+/// Follows up to `max_redirects` redirects, but not from HTTPS to HTTP, nor
+/// to another host if the upstream's credentials would be sent there.
+async fn get_following(state: &AppState, upstream: &UpstreamCfg, uri: &str, validators: &Validators) -> Result<Response<Body>> {
+    let mut uri = uri.to_owned();
+    let mut redirects = 0;
+    loop {
+        let res = get_with_retries(state, upstream, &uri, validators).await?;
+        let location = match redirect_location(&res, &uri) {
+            Some(location) if upstream.max_redirects > 0 => location,
+            _ => return Ok(res),
+        };
+        if let Some(reason) = refusal(upstream, &uri, &location, redirects) {
+            return Err(AppError::UpstreamRedirect { upstream: upstream.name, location, reason });
+        }
+        debug!(%location, "following upstream redirect");
+        redirects += 1;
+        uri = location;
+    }
+}
+
+/// Why a redirect from `uri` to `location`, after `redirects` others, isn't
+/// followed, if it isn't.
+fn refusal(upstream: &UpstreamCfg, uri: &str, location: &str, redirects: u32) -> Option<&'static str> {
+    let (from, to) = (origin(uri), origin(location));
+    if redirects == upstream.max_redirects {
+        Some("too many redirects")
+    } else if from.0 == "https" && to.0 != "https" {
+        Some("not following a redirect from https to http")
+    } else if from != to && upstream.sends_credentials() {
+        Some("not sending credentials to another host")
+    } else {
+        None
+    }
+}
+
+/// Where `res` redirects a GET of `uri` to, resolved against it.
+fn redirect_location(res: &Response<Body>, uri: &str) -> Option<String> {
+    match res.status() {
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => {}
+        _ => return None,
+    }
+    resolve(uri, res.headers().get(LOCATION)?.to_str().ok()?)
+}
+
+/// `reference` resolved against the absolute `base`, as per RFC 3986 apart
+/// from leaving dot segments alone, or `None` if that's not an http(s) uri.
+fn resolve(base: &str, reference: &str) -> Option<String> {
+    let base = base.parse::<Uri>().ok()?;
+    let (scheme, authority) = (base.scheme_str()?, base.authority()?);
+    // Relative references like `2` parse as an authority, if at all.
+    let resolved = if reference.parse::<Uri>().is_ok_and(|uri| uri.scheme().is_some()) {
+        reference.to_owned()
+    } else if reference.starts_with("//") {
+        format!("{}:{}", scheme, reference)
+    } else if reference.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, reference)
+    } else if reference.starts_with('?') {
+        format!("{}://{}{}{}", scheme, authority, base.path(), reference)
+    } else {
+        let dir = &base.path()[..base.path().rfind('/').map_or(0, |slash| slash + 1)];
+        format!("{}://{}{}{}", scheme, authority, dir, reference)
+    };
+    let uri = resolved.parse::<Uri>().ok()?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") if uri.host().is_some() => Some(resolved),
+        _ => None,
+    }
+}
+
+/// The scheme, host and port of `uri`.
+fn origin(uri: &str) -> (String, String, Option<u16>) {
+    let uri = uri.parse::<Uri>().unwrap_or_default();
+    let scheme = uri.scheme_str().unwrap_or_default().to_ascii_lowercase();
+    let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+    let port = uri.port_u16().or(match scheme.as_str() {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    });
+    (scheme, host, port)
+}
+
+async fn get_with_retries(state: &AppState, upstream: &UpstreamCfg, uri: &str, validators: &Validators) -> Result<Response<Body>> {
+    let policy = &upstream.retry;
+    let mut attempt = 1;
+    let mut refreshed = false;
+    loop {
+        state.throttles.acquire(upstream.name, upstream.throttle.as_ref()).await?;
+        state.breakers.acquire(upstream.name, &upstream.breaker)?;
+        let res = get_hedged(state, upstream, uri, validators).await;
+        if let Ok(res) = &res {
+            if res.status() == StatusCode::TOO_MANY_REQUESTS {
+                if let Some(duration) = retry_after(res.headers()) {
+                    state.throttles.cool_down(upstream.name, duration.min(upstream.max_cool_down));
+                }
+            }
+        }
+        state.breakers.record(upstream.name, &upstream.breaker, !is_failure(&res));
+        // The token or credential was dropped, so it's retried once with a
+        // new one, whether retries are configured or not.
+        let token_rejected = !refreshed
+            && (upstream.oauth.is_some() || upstream.vault.is_some())
+            && res.as_ref().is_ok_and(|res| res.status() == StatusCode::UNAUTHORIZED);
+        if token_rejected {
+            refreshed = true;
+        } else if attempt >= policy.max_attempts || !is_retryable(&res) {
+            return res;
+        }
+        let delay = backoff(policy, attempt);
+        debug!(attempt, ?delay, "retrying upstream request");
+        state.metrics.upstream_retries.with_label_values(&[upstream.name]).inc();
+        delay_for(delay).await;
+        attempt += 1;
+    }
+}
+
+/// The delay a `Retry-After` header asks for, given in seconds or as a date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+fn is_retryable(res: &Result<Response<Body>>) -> bool {
+    match res {
+        Ok(res) => matches!(
+            res.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(_) => true,
+    }
+}
+
+/// How long to wait before the retry following `attempt`.
+pub(crate) fn backoff(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let exp = policy.base_delay
+        .checked_mul(1 << (attempt - 1).min(16))
+        .unwrap_or(policy.max_delay);
+    let delay = exp.min(policy.max_delay);
+    if policy.jitter {
+        delay.mul_f64(rand::thread_rng().gen())
+    } else {
+        delay
+    }
+}
+
+/// Whether the upstream failed to answer, or answered with a server error.
+fn is_failure(res: &Result<Response<Body>>) -> bool {
+    match res {
+        Ok(res) => res.status().is_server_error(),
+        Err(_) => true,
+    }
+}
+
+/// Sends a GET to `uri`, and a second one if the upstream's hedging policy
+/// says the first is taking too long. Whichever answers first wins, unless it
+/// failed, in which case the other one's answer is waited for.
+async fn get_hedged(state: &AppState, upstream: &UpstreamCfg, uri: &str, validators: &Validators) -> Result<Response<Body>> {
+    let policy = match &upstream.hedge {
+        Some(policy) => policy,
+        None => return get_conditional(state, upstream, uri, validators).await,
+    };
+    let delay = state.metrics.upstream_latency(upstream.name, policy.percentile)
+        .map_or(policy.min_delay, |latency| latency.max(policy.min_delay));
+
+    // Boxed, as they're both held while the other one is waited for.
+    let first = get_conditional(state, upstream, uri, validators).boxed();
+    let first = match select(first, delay_for(delay)).await {
+        Either::Left((res, _)) => return res,
+        Either::Right(((), first)) => first,
+    };
+    if !state.throttles.try_acquire(upstream.name, upstream.throttle.as_ref()) {
+        return first.await;
+    }
+    debug!(?delay, "hedging upstream request");
+    state.metrics.upstream_hedges.with_label_values(&[upstream.name]).inc();
+    let second = get_conditional(state, upstream, uri, validators).boxed();
+    let wins = state.metrics.upstream_hedge_wins.with_label_values(&[upstream.name]);
+    match select(first, second).await {
+        Either::Left((res, second)) if is_failure(&res) => {
+            debug!("first upstream request failed, waiting for the hedged one");
+            let hedged = second.await;
+            if is_failure(&hedged) {
+                return res;
+            }
+            wins.inc();
+            hedged
+        }
+        Either::Left((res, _)) => res,
+        Either::Right((res, first)) if is_failure(&res) => {
+            debug!("hedged upstream request failed, waiting for the first one");
+            first.await
+        }
+        Either::Right((res, _)) => {
+            wins.inc();
+            res
+        }
+    }
+}
+
+/// Sends a request with `body` to `uri` on `upstream`. Unlike GETs it isn't
+/// retried or hedged, since it may not be idempotent; a non-empty body is
+/// sent as JSON.
+#[instrument(skip(state, upstream, body), fields(upstream = upstream.name))]
+pub async fn do_req(state: &AppState, upstream: &UpstreamCfg, method: Method, uri: &str, body: Bytes) -> Result<Response<Body>> {
+    let mut request = Request::builder().method(method).uri(uri);
+    if !body.is_empty() {
+        request = request.header(CONTENT_TYPE, "application/json");
+    }
+    send(state, upstream, request.body(body.into())?).await
+}
+
+/// Sends `request` to `upstream` once, subject to its throttle and circuit
+/// breaker. The timeout only covers getting the response's head, so its body
+/// can be streamed.
+pub async fn send(state: &AppState, upstream: &UpstreamCfg, request: Request<Body>) -> Result<Response<Body>> {
+    let send = async {
+        state.throttles.acquire(upstream.name, upstream.throttle.as_ref()).await?;
+        state.breakers.acquire(upstream.name, &upstream.breaker)?;
+        let res = send_once(state, upstream, request).await;
+        state.breakers.record(upstream.name, &upstream.breaker, !is_failure(&res));
+        res
+    };
+    within_budget(upstream, send).await
+}
+
+/// Sends a single GET to `uri`, counting it against the `upstream` it
+/// belongs to.
+pub async fn get_once(state: &AppState, upstream: &UpstreamCfg, uri: &str) -> Result<Response<Body>> {
+    get_conditional(state, upstream, uri, &Validators::default()).await
+}
+
+async fn get_conditional(state: &AppState, upstream: &UpstreamCfg, uri: &str, validators: &Validators) -> Result<Response<Body>> {
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .body(Body::empty())?;
+    validators.apply(request.headers_mut());
+    send_once(state, upstream, request).await
+}
+
+#[instrument(
+    skip(state, upstream, request),
+    fields(otel.kind = "client", upstream = upstream.name, method = %request.method(), uri = %request.uri(), status, latency_ms),
+)]
+async fn send_once(state: &AppState, upstream: &UpstreamCfg, mut request: Request<Body>) -> Result<Response<Body>> {
+    let _ = INBOUND_HEADERS.try_with(|inbound| forward_headers(inbound, request.headers_mut(), &upstream.forward_headers));
+    propagation::inject(request.headers_mut());
+    deadline::inject(request.headers_mut());
+    let authorization = match &upstream.oauth {
+        Some(oauth) => {
+            // Boxed since fetching a token makes for a large future, and this
+            // one is nested in every upstream request.
+            let header = Box::pin(state.tokens.authorization(upstream.name, oauth, &state.client)).await?;
+            request.headers_mut().insert(AUTHORIZATION, header.clone());
+            Some((oauth, header))
+        }
+        None => None,
+    };
+    let credential = match &upstream.vault {
+        Some(vault) => {
+            let header = Box::pin(state.vault.header(upstream.name, vault, &state.client)).await?;
+            request.headers_mut().insert(vault.header.clone(), header.clone());
+            Some((vault, header))
+        }
+        None => None,
+    };
+    let start = Instant::now();
+    let res = CONNECT_TIMEOUT.scope(upstream.connect_timeout, state.upstream_client(upstream).request(request)).await;
+    let latency = start.elapsed();
+    let status = match &res {
+        Ok(res) => res.status().as_str().to_owned(),
+        Err(_) => "error".to_owned(),
+    };
+    let span = Span::current();
+    span.record("status", status.as_str());
+    span.record("latency_ms", latency.as_millis() as u64);
+    debug!("upstream responded");
+
+    let class = match &res {
+        Ok(res) => status_class(res.status()),
+        Err(_) => "error",
+    };
+    let _ = TIMINGS.try_with(|timings| timings.lock().unwrap().push(UpstreamTiming {
+        upstream: upstream.name.to_owned(),
+        status,
+        latency,
+    }));
+    if let (Ok(res), Some((oauth, header))) = (&res, &authorization) {
+        if res.status() == StatusCode::UNAUTHORIZED {
+            state.tokens.invalidate(upstream.name, oauth, header);
+        }
+    }
+    if let (Ok(res), Some((vault, header))) = (&res, &credential) {
+        if res.status() == StatusCode::UNAUTHORIZED {
+            state.vault.invalidate(upstream.name, vault, header);
+        }
+    }
+    state.metrics.upstream_requests.with_label_values(&[upstream.name, class]).inc();
+    state.metrics.observe_upstream(upstream.name, latency);
+    res.map_err(|source| match source.is_connect() {
+        true => AppError::UpstreamUnreachable { upstream: upstream.name, source },
+        false => AppError::UpstreamFailed { upstream: upstream.name, source },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let base = "https://api.example/v1/todos/1?x=1";
+        assert_eq!(resolve(base, "http://other.example/a").as_deref(), Some("http://other.example/a"));
+        assert_eq!(resolve(base, "//cdn.example/a").as_deref(), Some("https://cdn.example/a"));
+        assert_eq!(resolve(base, "/v2/todos/1").as_deref(), Some("https://api.example/v2/todos/1"));
+        assert_eq!(resolve(base, "2").as_deref(), Some("https://api.example/v1/todos/2"));
+        assert_eq!(resolve(base, "?x=2").as_deref(), Some("https://api.example/v1/todos/1?x=2"));
+        assert_eq!(
+            resolve(base, "/login?next=https://api.example/").as_deref(),
+            Some("https://api.example/login?next=https://api.example/"),
+        );
+        assert_eq!(resolve(base, "ftp://files.example/a"), None);
+        assert_eq!(resolve(base, "/bad path"), None);
+    }
+
+    #[test]
+    fn test_origin() {
+        assert_eq!(origin("https://API.example/a"), origin("https://api.example:443/b"));
+        assert_ne!(origin("https://api.example/a"), origin("https://api.example:8443/a"));
+        assert_ne!(origin("https://api.example/a"), origin("http://api.example/a"));
+    }
+
+    #[test]
+    fn test_refusal() {
+        let mut upstream = crate::config::Config::default().validate().unwrap().todo;
+        let (uri, max) = ("https://api.example/todos/1", upstream.max_redirects);
+        assert_eq!(refusal(&upstream, uri, "https://api.example/v2/todos/1", 0), None);
+        assert_eq!(refusal(&upstream, uri, "https://cdn.example/todos/1", 0), None);
+        assert_eq!(refusal(&upstream, uri, "https://api.example/v2/todos/1", max), Some("too many redirects"));
+        let downgraded = Some("not following a redirect from https to http");
+        assert_eq!(refusal(&upstream, uri, "http://api.example/todos/1", 0), downgraded);
+        assert_eq!(refusal(&upstream, "http://api.example/todos/1", "http://cdn.example/todos/1", 0), None);
+
+        // Anything only meant for the upstream keeps it on its origin.
+        let elsewhere = Some("not sending credentials to another host");
+        upstream.forward_headers = vec![HeaderName::from_static("x-tenant")];
+        assert_eq!(refusal(&upstream, uri, "https://cdn.example/todos/1", 0), elsewhere);
+        assert_eq!(refusal(&upstream, uri, "https://api.example:8443/todos/1", 0), elsewhere);
+        assert_eq!(refusal(&upstream, uri, "https://API.example/v2/todos/1", 0), None);
+        upstream.forward_headers.clear();
+        upstream.tls = Some(UpstreamTlsCfg { identity: Some(("cert.pem".into(), "key.pem".into())), ca_bundle: None });
+        assert_eq!(refusal(&upstream, uri, "https://cdn.example/todos/1", 0), elsewhere);
+    }
+}
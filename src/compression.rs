@@ -0,0 +1,181 @@
+use crate::config::Compression;
+use crate::negotiate::parse_quality;
+use crate::Result;
+use flate2::write::GzEncoder;
+use hyper::body::{to_bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use hyper::{Body, Response, StatusCode};
+use std::io::Write;
+
+/// The codings responses can be compressed with, in the order they're
+/// preferred when a client accepts several equally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Coding {
+    Brotli,
+    Gzip,
+}
+
+impl Coding {
+    const ALL: [Coding; 2] = [Coding::Brotli, Coding::Gzip];
+
+    fn name(self) -> &'static str {
+        match self {
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Coding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(body)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            Coding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Marks a response whose body is an upstream's, streamed as it arrives.
+/// It's never compressed, which would mean buffering it in full.
+#[derive(Clone, Copy, Debug)]
+pub struct Relayed;
+
+/// `res` compressed with the coding the request's `headers` accept, if
+/// `cfg` says its body is worth it. Only bodies the response holds in full
+/// are compressed, so streamed and `Relayed` ones are left as they are. The
+/// ETag of a compressed body is weakened, since it's no longer the bytes it
+/// was made of, and its `Content-Length` and `Accept-Ranges` are dropped.
+pub async fn compress(res: Response<Body>, headers: &HeaderMap, cfg: Option<&Compression>) -> Result<Response<Body>> {
+    if !cfg.is_some_and(|cfg| compressible(&res, cfg)) {
+        return Ok(res);
+    }
+    let (mut parts, body) = res.into_parts();
+    parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    let coding = match accepted(headers) {
+        Some(coding) => coding,
+        None => return Ok(Response::from_parts(parts, body)),
+    };
+    let body = coding.encode(&to_bytes(body).await?)?;
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding.name()));
+    // Both are of the uncompressed body, like a proxied upstream's.
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove(ACCEPT_RANGES);
+    let weak = parts.headers.get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .map(|etag| HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat()))
+        .transpose()?;
+    if let Some(weak) = weak {
+        parts.headers.insert(ETAG, weak);
+    }
+    Ok(Response::from_parts(parts, body.into()))
+}
+
+/// Whether `res` has a body of a type `cfg` compresses, that's large enough
+/// and isn't encoded already.
+fn compressible(res: &Response<Body>, cfg: &Compression) -> bool {
+    if res.extensions().get::<Relayed>().is_some()
+        || res.status() == StatusCode::NO_CONTENT
+        || res.status() == StatusCode::NOT_MODIFIED
+        || res.status() == StatusCode::PARTIAL_CONTENT
+        || res.headers().contains_key(CONTENT_ENCODING)
+    {
+        return false;
+    }
+    if !res.body().size_hint().exact().is_some_and(|len| len > 0 && len >= cfg.min_bytes) {
+        return false;
+    }
+    let essence = match res.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) {
+        Some(content_type) => content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase(),
+        None => return false,
+    };
+    cfg.content_types.iter().any(|content_type| match content_type.strip_suffix("/*") {
+        Some(kind) => essence.split_once('/').is_some_and(|(essence_kind, _)| essence_kind == kind),
+        None => *content_type == essence,
+    })
+}
+
+/// The coding `headers` give the highest quality, judged by the entry for
+/// it or else by `*`; `None` if they accept none or don't say.
+fn accepted(headers: &HeaderMap) -> Option<Coding> {
+    let entries = headers.get_all(ACCEPT_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(parse_entry)
+        .collect::<Vec<_>>();
+    let quality = |name: &str| entries.iter().find(|(coding, _)| coding == name).map(|(_, quality)| *quality);
+    let mut best: Option<(Coding, u16)> = None;
+    for coding in Coding::ALL.iter().copied() {
+        let quality = quality(coding.name()).or_else(|| quality("*")).unwrap_or(0);
+        if quality > 0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((coding, quality));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// A coding of an `Accept-Encoding` header and its quality; `None` for
+/// invalid ones, which are ignored.
+fn parse_entry(entry: &str) -> Option<(String, u16)> {
+    let mut params = entry.split(';');
+    let coding = params.next()?.trim();
+    if coding.is_empty() {
+        return None;
+    }
+    let mut quality = 1000;
+    for param in params {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("q") {
+                quality = parse_quality(value.trim())?;
+            }
+        }
+    }
+    Some((coding.to_ascii_lowercase(), quality))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted_by(accept_encoding: &str) -> Option<Coding> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, accept_encoding.parse().unwrap());
+        accepted(&headers)
+    }
+
+    #[test]
+    fn test_accepted() {
+        assert_eq!(accepted(&HeaderMap::new()), None);
+        assert_eq!(accepted_by("gzip, deflate, br"), Some(Coding::Brotli));
+        assert_eq!(accepted_by("GZIP"), Some(Coding::Gzip));
+        assert_eq!(accepted_by("br;q=0.5, gzip"), Some(Coding::Gzip));
+        assert_eq!(accepted_by("*;q=0.1, br;q=0"), Some(Coding::Gzip));
+        assert_eq!(accepted_by("identity, deflate"), None);
+        assert_eq!(accepted_by("gzip;q=0"), None);
+    }
+
+    #[test]
+    fn test_compressible() {
+        let cfg = Compression { min_bytes: 4, content_types: vec!["application/json".to_owned(), "text/*".to_owned()] };
+        let res = |content_type: &str, body: &'static str| {
+            Response::builder().header(CONTENT_TYPE, content_type).body(Body::from(body)).unwrap()
+        };
+        assert!(compressible(&res("application/json", "[1,2]"), &cfg));
+        assert!(compressible(&res("Text/Plain; charset=utf-8", "long enough"), &cfg));
+        assert!(!compressible(&res("application/json", "[1]"), &cfg));
+        assert!(!compressible(&res("application/msgpack", "long enough"), &cfg));
+        let mut encoded = res("text/plain", "long enough");
+        encoded.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert!(!compressible(&encoded, &cfg));
+        let (sender, body) = Body::channel();
+        drop(sender);
+        let streamed = Response::builder().header(CONTENT_TYPE, "text/plain").body(body).unwrap();
+        assert!(!compressible(&streamed, &cfg));
+    }
+}
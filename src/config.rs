@@ -0,0 +1,2521 @@
+use crate::access_log::AccessLogFormat;
+use crate::auth::normalize_api_key;
+use crate::secret::Secret;
+use crate::transform::{StepSection, Transform};
+use clap::ValueEnum;
+use hyper::header::{
+    HeaderName, HeaderValue, AUTHORIZATION, REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS,
+    X_FRAME_OPTIONS,
+};
+use hyper::{Method, Uri};
+use ipnet::IpNet;
+use serde::de::{self, Deserialize as _, Deserializer};
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const CATS_URL: &str = "https://cat-fact.herokuapp.com/";
+
+pub const TODO_URL: &str = "https://jsonplaceholder.typicode.com/";
+
+pub const BIND_ADDR: &str = "127.0.0.1";
+
+pub const PORT: u16 = 3000;
+
+pub const TLS_PORT: u16 = 3443;
+
+pub const REQUEST_TIMEOUT_MS: u64 = 30_000;
+
+pub const SHUTDOWN_TIMEOUT_MS: u64 = 30_000;
+
+pub const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+pub const JWKS_CACHE_MS: u64 = 300_000;
+
+pub const JWT_LEEWAY_MS: u64 = 60_000;
+
+pub const MAX_QUEUED_CONNECTIONS: usize = 128;
+
+pub const CONNECTION_QUEUE_TIMEOUT_MS: u64 = 1_000;
+
+pub const BACKLOG: u32 = 1024;
+
+pub const UPSTREAM_CONNECT_TIMEOUT_MS: u64 = 5_000;
+
+pub const UPSTREAM_TIMEOUT_MS: u64 = 10_000;
+
+pub const MAX_ATTEMPTS: u32 = 3;
+
+pub const RETRY_BASE_DELAY_MS: u64 = 100;
+
+pub const RETRY_MAX_DELAY_MS: u64 = 2_000;
+
+pub const CIRCUIT_FAILURES: u32 = 5;
+
+pub const CIRCUIT_OPEN_MS: u64 = 30_000;
+
+pub const HEDGE_MIN_DELAY_MS: u64 = 50;
+
+pub const THROTTLE_BURST: u32 = 10;
+
+pub const THROTTLE_MAX_WAIT_MS: u64 = 1_000;
+
+pub const MAX_COOL_DOWN_MS: u64 = 60_000;
+
+pub const MAX_REDIRECTS: u32 = 5;
+
+pub const PLACEHOLDER: &str = "unavailable";
+
+pub const STRICT_TRANSPORT_SECURITY_VALUE: &str = "max-age=31536000";
+
+pub const CORS_MAX_AGE_SECS: u64 = 600;
+
+pub const COMPRESSION_MIN_BYTES: u64 = 1024;
+
+pub const COMPRESSIBLE_TYPES: &[&str] = &["application/json", "application/problem+json", "application/xml", "text/*"];
+
+pub const VAULT_MOUNT: &str = "secret";
+
+pub const VAULT_REFRESH_MS: u64 = 300_000;
+
+pub const VAULT_RENEW_MS: u64 = 3_600_000;
+
+pub const VAULT_KEY: &str = "token";
+
+pub const DOCS_ASSETS: &str = "swagger-ui";
+
+/// The settings that differ between the upstreams when not configured.
+struct UpstreamDefaults {
+    name: &'static str,
+    url: &'static str,
+    degrade: bool,
+    cache_ttl_ms: u64,
+    /// The path of the value in the upstream's JSON.
+    extract: &'static str,
+}
+
+const CATS: UpstreamDefaults = UpstreamDefaults {
+    name: "cats",
+    url: CATS_URL,
+    degrade: true,
+    cache_ttl_ms: 5_000,
+    extract: "$.text",
+};
+
+const TODO: UpstreamDefaults = UpstreamDefaults {
+    name: "todo",
+    url: TODO_URL,
+    degrade: false,
+    cache_ttl_ms: 60_000,
+    extract: "$.title",
+};
+
+pub const CACHE_MAX_TTL_MS: u64 = 3_600_000;
+
+pub const CACHE_MAX_ENTRIES: u64 = 10_000;
+
+pub const CACHE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+pub const REDIS_URL: &str = "redis://127.0.0.1/";
+
+pub const REDIS_POOL_SIZE: u32 = 8;
+
+pub const POOL_MAX_IDLE_PER_HOST: usize = 32;
+
+pub const POOL_IDLE_TIMEOUT_MS: u64 = 90_000;
+
+pub const REDIS_TIMEOUT_MS: u64 = 100;
+
+pub const THREAD_NAME: &str = "rust-mockito-example";
+
+pub const MAX_BLOCKING_THREADS: usize = 512;
+
+pub const RATE_LIMIT_BURST: u32 = 10;
+
+/// The lowest rate, per second, other than 0, that rate limits and throttles
+/// can be set to; any lower and the wait for a token could overflow.
+pub const MIN_RATE: f64 = 0.001;
+
+pub const SLOW_REQUEST_MS: u64 = 1_000;
+
+pub const SLO_TARGET: f64 = 0.99;
+
+pub const SLO_LATENCY_MS: u64 = 1_000;
+
+pub const FACTS_MAX_COUNT: usize = 10;
+
+pub const FACTS_CONCURRENCY: usize = 4;
+
+pub const FACTS_STREAM_INTERVAL_MS: u64 = 10_000;
+
+pub const FACTS_POLL_TIMEOUT_MS: u64 = 25_000;
+
+pub const WEBSOCKET_PING_INTERVAL_MS: u64 = 30_000;
+
+pub const GRAPHQL_MAX_DEPTH: usize = 5;
+
+pub const GRAPHQL_MAX_COMPLEXITY: usize = 50;
+
+pub const GRAPHQL_MAX_ROOT_FIELDS: usize = 10;
+
+pub const WEBHOOKS_MAX_ATTEMPTS: u32 = 5;
+
+/// With retries at most 5 minutes apart, the default `max_retry_delay_ms`,
+/// this many attempts already keep a delivery queued for up to about 100
+/// minutes.
+pub const WEBHOOKS_MAX_ATTEMPTS_LIMIT: u32 = 20;
+
+pub const WEBHOOKS_RETRY_DELAY_MS: u64 = 1_000;
+
+pub const WEBHOOKS_MAX_RETRY_DELAY_MS: u64 = 300_000;
+
+pub const WEBHOOKS_TIMEOUT_MS: u64 = 5_000;
+
+pub const WEBHOOKS_QUEUE_CAPACITY: usize = 1024;
+
+pub const WEBHOOKS_POLL_INTERVAL_MS: u64 = 60_000;
+
+/// Roughly the instructions a plugin may execute per call.
+pub const PLUGIN_FUEL: u64 = 10_000_000;
+
+/// How large a plugin's memory may grow per call.
+pub const PLUGIN_MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event.
+    Text,
+    /// Multiple lines per event, for reading logs during development.
+    Pretty,
+    /// One JSON object per event.
+    Json,
+}
+
+/// How `/basic` and `/double` answer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// A JSON object, like `{"todo":"...","cat_fact":"..."}`.
+    Json,
+    /// The todo, or `Todo: ..., Cat Fact: ...`.
+    Text,
+}
+
+/// What's done with requests whose path isn't normalized, like `/basic/` or
+/// `/todos/%35`, when the normalized one has a route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathNormalization {
+    /// They're answered with a 308 to the normalized path.
+    Redirect,
+    /// They're routed as if they had the normalized path.
+    Rewrite,
+    /// They're routed as they are.
+    Off,
+}
+
+/// Whether requests to an upstream use HTTP/2, which multiplexes them over
+/// one connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Http2 {
+    /// HTTP/1.1 only.
+    Off,
+    /// Offered to HTTPS upstreams when connecting, and used if they agree.
+    Alpn,
+    /// Always, over TLS too; for upstreams known to speak it, like internal
+    /// ones served over plain HTTP.
+    PriorKnowledge,
+}
+
+/// The validated configuration the server runs with.
+#[derive(Debug)]
+pub struct ServerCfg {
+    pub cats: UpstreamCfg,
+    pub todo: UpstreamCfg,
+    /// By name.
+    pub sources: Vec<SourceCfg>,
+    /// `None` doesn't use Vault.
+    pub vault: Option<VaultCfg>,
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    /// Only read at startup; whether plain HTTP is served on `port`, which
+    /// can only be off with `unix_socket` set.
+    pub tcp: bool,
+    /// Only read at startup; `None` doesn't serve on a Unix socket.
+    pub unix_socket: Option<UnixSocketCfg>,
+    /// Only read at startup; `None` only serves plain HTTP.
+    pub tls: Option<TlsCfg>,
+    /// Only read at startup; `None` doesn't serve gRPC.
+    pub grpc_port: Option<u16>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub access_log: AccessLogFormat,
+    pub response_format: ResponseFormat,
+    pub normalize_paths: PathNormalization,
+    pub request_timeout: Duration,
+    /// Requests with larger bodies are answered with a 413.
+    pub max_body_bytes: u64,
+    /// Only read at startup, like the listen address; `None` doesn't limit
+    /// connections.
+    pub max_connections: Option<usize>,
+    /// Only read at startup; the sockets HTTP and HTTPS are each accepted on,
+    /// which are bound with `SO_REUSEPORT` if there's more than one.
+    pub acceptors: usize,
+    pub max_queued_connections: usize,
+    pub connection_queue_timeout: Duration,
+    /// Only read at startup; how many connections the kernel holds on to
+    /// until they're accepted, on each socket the server binds itself.
+    pub backlog: u32,
+    /// Only read at startup; whether Nagle's algorithm is disabled on accepted
+    /// TCP connections, so that small responses aren't held back to coalesce.
+    pub tcp_nodelay: bool,
+    /// Only read at startup; how often TCP keepalive probes are sent on idle
+    /// accepted connections, `None` doesn't send them.
+    pub tcp_keepalive: Option<Duration>,
+    /// How long in-flight requests get to finish once shutdown is requested.
+    pub shutdown_timeout: Duration,
+    /// Requests taking at least this long are logged with their upstream
+    /// timings.
+    pub slow_request: Option<Duration>,
+    pub slo: Slo,
+    pub facts: Facts,
+    /// Only read at startup.
+    pub graphql: GraphQl,
+    pub websocket: WebSocket,
+    pub webhooks: Webhooks,
+    /// Whether `/proxy/{upstream}/{*path}` forwards requests.
+    pub proxy: bool,
+    /// The directory of the Swagger UI assets `/docs` serves the API explorer
+    /// with; `None` doesn't serve it.
+    pub docs: Option<PathBuf>,
+    /// Only read at startup; `None` doesn't load a plugin.
+    pub plugin: Option<PluginCfg>,
+    /// The directory of the message catalogs, only read at startup; `None`
+    /// answers in English.
+    pub catalogs: Option<PathBuf>,
+    /// By route; routes without one ignore the query.
+    pub query_mapping: HashMap<String, QueryMapping>,
+    /// By route, then by upstream name; what's not listed is made of the
+    /// upstream's JSON with its own transform.
+    pub transforms: HashMap<String, HashMap<String, Transform>>,
+    pub security_headers: SecurityHeaders,
+    pub cors: Cors,
+    /// `None` doesn't compress responses.
+    pub compression: Option<Compression>,
+    pub ip_filter: IpFilter,
+    pub rate_limit: RateLimits,
+    pub api_keys: ApiKeys,
+    pub jwt: JwtCfg,
+    /// Credentials the admin endpoints need; `None` answers them with a 404.
+    pub admin: Option<AdminCredentials>,
+    /// Only read at startup; the cache isn't rebuilt on reload.
+    pub cache: CacheCfg,
+    /// Only read at startup, like the upstreams' TLS settings.
+    pub client: ClientCfg,
+    /// Only read at startup; the runtime is built before the server starts.
+    pub runtime: RuntimeCfg,
+}
+
+#[derive(Debug)]
+pub struct UpstreamCfg {
+    /// Identifies the upstream in metrics and logs.
+    pub name: &'static str,
+    /// Always ends with a slash.
+    pub url: String,
+    pub connect_timeout: Duration,
+    /// Bounds all attempts of a request together, including the backoff.
+    pub timeout: Duration,
+    pub retry: RetryPolicy,
+    pub breaker: BreakerPolicy,
+    pub hedge: Option<HedgePolicy>,
+    /// `None` doesn't limit the requests sent.
+    pub throttle: Option<ThrottlePolicy>,
+    /// Caps how long requests stop being sent when the upstream answers 429
+    /// with a `Retry-After`.
+    pub max_cool_down: Duration,
+    /// How many redirects a GET follows; 0 answers with the redirect.
+    pub max_redirects: u32,
+    /// `None` disables the cache.
+    pub cache: Option<CachePolicy>,
+    /// `None` sends requests without a token.
+    pub oauth: Option<OAuthCfg>,
+    /// `None` sends requests without a credential from Vault.
+    pub vault: Option<VaultSecretCfg>,
+    /// Copied from the incoming request to the upstream's requests.
+    pub forward_headers: Vec<HeaderName>,
+    /// Makes the value served of the JSON the upstream answers with.
+    pub transform: Transform,
+    /// Only read at startup; `None` connects with the defaults.
+    pub tls: Option<UpstreamTlsCfg>,
+    /// Only read at startup, like `tls`.
+    pub http2: Http2,
+    /// What `/double` shows in place of this upstream's value if it fails
+    /// while the other one doesn't; `None` fails the request instead.
+    pub placeholder: Option<String>,
+}
+
+impl UpstreamCfg {
+    /// Whether its requests carry anything that's only meant for it: a
+    /// token, a credential from Vault, a client certificate, or headers
+    /// forwarded from the client.
+    pub fn sends_credentials(&self) -> bool {
+        self.oauth.is_some()
+            || self.vault.is_some()
+            || self.tls.as_ref().is_some_and(|tls| tls.identity.is_some())
+            || !self.forward_headers.is_empty()
+    }
+}
+
+/// A value fetched from an upstream of its own, served at `/sources/{name}`
+/// and by `/aggregate`.
+#[derive(Debug)]
+pub struct SourceCfg {
+    /// Named like the source.
+    pub upstream: UpstreamCfg,
+    /// Appended to the upstream's url, with its `{param}`s filled from the
+    /// query.
+    pub path: String,
+    pub params: Vec<String>,
+}
+
+/// TLS settings for an upstream that needs other than the defaults, like an
+/// internal deployment that requires mutual TLS.
+#[derive(Clone, Debug)]
+pub struct UpstreamTlsCfg {
+    /// PEM files with the certificate chain and PKCS#8 key presented to the
+    /// upstream.
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// PEM file with the CAs that are trusted instead of the system's.
+    pub ca_bundle: Option<PathBuf>,
+}
+
+/// The OAuth2 client credentials requests to an upstream get a token with.
+#[derive(Clone, Debug)]
+pub struct OAuthCfg {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Secret,
+    pub scope: Option<String>,
+}
+
+/// How to reach Vault, where upstream credentials can be kept instead of in
+/// the config.
+#[derive(Clone, Debug)]
+pub struct VaultCfg {
+    /// Without a trailing slash.
+    pub address: String,
+    pub token: Secret,
+    /// The mount of the KV v2 secrets engine.
+    pub mount: String,
+    /// How long a value read from Vault is used before it's read again.
+    pub refresh: Duration,
+    /// How often the token, and the leases of the values read with it, are
+    /// renewed; `None` doesn't renew them.
+    pub renew: Option<Duration>,
+}
+
+/// A credential kept in Vault that's sent to an upstream in `header`.
+#[derive(Clone, Debug)]
+pub struct VaultSecretCfg {
+    pub vault: VaultCfg,
+    /// The path of the secret under the mount.
+    pub path: String,
+    /// The field of the secret holding the credential.
+    pub key: String,
+    pub header: HeaderName,
+    /// Put in front of the credential, like `Bearer `.
+    pub prefix: String,
+}
+
+/// Failed GETs are retried with exponential backoff: the n-th retry waits
+/// `base_delay * 2^(n-1)`, capped at `max_delay`. With jitter the wait is
+/// picked at random between 0 and that.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Includes the first attempt, so 1 disables retries.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+}
+
+/// After `failures` consecutive failed requests to an upstream, further ones
+/// fail fast for `open_for` before a probe is let through.
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerPolicy {
+    /// 0 disables the circuit breaker.
+    pub failures: u32,
+    pub open_for: Duration,
+}
+
+/// A request still waiting for an answer after the `percentile` of the
+/// upstream's recent latencies, but at least `min_delay`, is sent again and
+/// whichever answers first wins.
+#[derive(Clone, Copy, Debug)]
+pub struct HedgePolicy {
+    pub percentile: f64,
+    pub min_delay: Duration,
+}
+
+/// Requests to the upstream are limited to `limit`; ones that would have to
+/// wait longer than `max_wait` to be sent fail instead.
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottlePolicy {
+    pub limit: RateLimit,
+    pub max_wait: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    /// Unbounded, entries are dropped once they've expired: when they're
+    /// looked up or swept out as others are inserted.
+    Memory,
+    /// Bounded by `max_entries` and `max_bytes`.
+    Lru,
+    /// Shared by all instances using the same Redis.
+    #[cfg(feature = "redis-cache")]
+    Redis,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheCfg {
+    pub backend: CacheBackend,
+    /// Shared with the expired responses kept to revalidate, which get a
+    /// quarter of it, as does `max_bytes`.
+    pub max_entries: u64,
+    /// Approximate, only counts the urls and bodies.
+    pub max_bytes: u64,
+    /// May contain a password.
+    pub redis_url: Secret,
+    pub redis_pool_size: u32,
+    /// How long to wait for a connection to Redis before treating a lookup
+    /// as a miss.
+    pub redis_timeout_ms: u64,
+}
+
+impl Default for CacheCfg {
+    fn default() -> CacheCfg {
+        CacheCfg {
+            backend: CacheBackend::Lru,
+            max_entries: CACHE_MAX_ENTRIES,
+            max_bytes: CACHE_MAX_BYTES,
+            redis_url: Secret::from(REDIS_URL),
+            redis_pool_size: REDIS_POOL_SIZE,
+            redis_timeout_ms: REDIS_TIMEOUT_MS,
+        }
+    }
+}
+
+/// How the connections to the upstreams are made and pooled.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientCfg {
+    /// Idle connections kept per host, beyond which they're closed once
+    /// they're done with.
+    pub pool_max_idle_per_host: usize,
+    /// How long a connection is kept idle; 0 keeps it until the upstream
+    /// closes it.
+    pub pool_idle_timeout_ms: u64,
+    /// Whether connections are reused at all, rather than closed after each
+    /// request.
+    pub keep_alive: bool,
+    /// How often TCP keepalive probes are sent on idle connections; 0 doesn't
+    /// send them.
+    pub tcp_keepalive_ms: u64,
+    /// Addresses hosts are connected to instead of the ones DNS has, like
+    /// `/etc/hosts` entries only this server sees.
+    pub hosts: HashMap<String, IpAddr>,
+    /// The proxies requests to http and https upstreams go through, except
+    /// to the comma-separated hosts, domains and networks in `no_proxy`.
+    /// Default to the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment
+    /// variables; empty disables them. Secrets, as their urls can have a
+    /// password.
+    pub http_proxy: Option<Secret>,
+    pub https_proxy: Option<Secret>,
+    pub no_proxy: Option<String>,
+}
+
+impl Default for ClientCfg {
+    fn default() -> ClientCfg {
+        ClientCfg {
+            pool_max_idle_per_host: POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout_ms: POOL_IDLE_TIMEOUT_MS,
+            keep_alive: true,
+            tcp_keepalive_ms: 0,
+            hosts: HashMap::new(),
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+        }
+    }
+}
+
+/// The threads of the Tokio runtime the server runs on.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeCfg {
+    /// The threads running the server's tasks; 0 is one per core.
+    pub worker_threads: usize,
+    /// What the threads are called, e.g. in `top -H` and core dumps.
+    pub thread_name: String,
+    /// The most threads blocking work like reading files and resolving
+    /// hosts runs on at once, besides the workers.
+    pub max_blocking_threads: usize,
+}
+
+impl Default for RuntimeCfg {
+    fn default() -> RuntimeCfg {
+        RuntimeCfg {
+            worker_threads: 0,
+            thread_name: THREAD_NAME.to_owned(),
+            max_blocking_threads: MAX_BLOCKING_THREADS,
+        }
+    }
+}
+
+/// Successful responses are cached for as long as their `Cache-Control` or
+/// `Expires` headers say, clamped to `min_ttl..=max_ttl`, or for `ttl` if
+/// they don't say.
+#[derive(Clone, Copy, Debug)]
+pub struct CachePolicy {
+    pub ttl: Duration,
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+}
+
+/// A request meets its SLO if it succeeds within the latency objective of its
+/// route; `target` is the fraction of requests that should.
+#[derive(Debug)]
+pub struct Slo {
+    pub target: f64,
+    pub latency: Duration,
+    pub routes: HashMap<String, Duration>,
+}
+
+impl Slo {
+    pub fn latency(&self, route: &str) -> Duration {
+        self.routes.get(route).copied().unwrap_or(self.latency)
+    }
+}
+
+/// `/facts` answers with up to `max_count` facts, fetched with at most
+/// `concurrency` requests to the upstream at a time.
+#[derive(Clone, Copy, Debug)]
+pub struct Facts {
+    pub max_count: usize,
+    pub concurrency: usize,
+    /// How often `/facts/stream` sends a fact, and `/facts/next` fetches
+    /// one when there's no newer one.
+    pub stream_interval: Duration,
+    /// How long `/facts/next` waits for a newer fact.
+    pub poll_timeout: Duration,
+}
+
+/// What a `/graphql` query can ask for: how deeply nested it can be, its
+/// complexity, which is one for every field it selects, and how many root
+/// fields it can select, aliases included, since each is fetched from the
+/// upstreams.
+#[derive(Clone, Copy, Debug)]
+pub struct GraphQl {
+    pub max_depth: usize,
+    pub max_complexity: usize,
+    pub max_root_fields: usize,
+}
+
+#[derive(Debug)]
+pub struct WebSocket {
+    /// How often `/ws` clients are pinged; one that hasn't answered by the
+    /// next ping is disconnected.
+    pub ping_interval: Duration,
+}
+
+/// How the cat facts are POSTed to the webhooks subscribed to them.
+#[derive(Debug)]
+pub struct Webhooks {
+    /// The file the subscriptions are kept in, only read at startup; `None`
+    /// forgets them on restart.
+    pub store: Option<PathBuf>,
+    /// Attempts to deliver a fact before giving up on it, and the waits
+    /// between them, which double from the base delay up to the max one.
+    pub retry: RetryPolicy,
+    pub timeout: Duration,
+    /// Deliveries that can wait to be sent before new ones are dropped; only
+    /// read at startup.
+    pub queue_capacity: usize,
+    /// How often a fact is fetched for the subscribers, while there are any.
+    pub poll_interval: Duration,
+}
+
+/// The WASM module loaded as a plugin, and the fuel and memory each call
+/// gets.
+#[derive(Clone, Debug)]
+pub struct PluginCfg {
+    pub path: PathBuf,
+    pub fuel: u64,
+    pub max_memory_bytes: usize,
+}
+
+/// How the query of a request changes what its route fetches from the
+/// upstreams.
+#[derive(Clone, Debug, Default)]
+pub struct QueryMapping {
+    /// The query parameter with the id of the todo to fetch instead of the
+    /// first one.
+    pub todo_id: Option<String>,
+    /// By upstream, the query parameters passed on to it, and their names
+    /// there.
+    pub query: HashMap<String, Vec<(String, String)>>,
+}
+
+/// Headers added to all responses that don't set them already.
+#[derive(Debug)]
+pub struct SecurityHeaders {
+    /// Only sent over HTTPS, since browsers ignore it otherwise.
+    pub strict_transport_security: Option<HeaderValue>,
+    pub others: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Which responses are compressed for clients that accept it.
+#[derive(Debug)]
+pub struct Compression {
+    /// Smaller bodies aren't worth it.
+    pub min_bytes: u64,
+    /// Media types like `application/json`, or `text/*` for all subtypes.
+    pub content_types: Vec<String>,
+}
+
+/// Which browser origins may call which routes; routes without a policy
+/// don't answer preflight requests.
+#[derive(Debug)]
+pub struct Cors {
+    pub routes: HashMap<String, CorsPolicy>,
+    /// How long browsers may cache the answer to a preflight request.
+    pub max_age: Duration,
+}
+
+impl Cors {
+    pub fn policy(&self, route: &str) -> Option<&CorsPolicy> {
+        self.routes.get(route)
+    }
+}
+
+#[derive(Debug)]
+pub struct CorsPolicy {
+    /// `*` allows any origin.
+    pub origins: Vec<String>,
+    pub methods: Vec<Method>,
+    /// The request headers allowed besides the CORS-safelisted ones.
+    pub headers: Vec<HeaderName>,
+}
+
+impl CorsPolicy {
+    pub fn any_origin(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin() || self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+/// Requests from clients in `deny`, or outside `allow` unless that's empty,
+/// are rejected.
+#[derive(Debug)]
+pub struct IpFilter {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn allows(&self, client: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as mapped addresses.
+        let client = client.to_canonical();
+        let listed = |nets: &[IpNet]| nets.iter().any(|net| net.contains(&client));
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// Each client may make `burst` requests at once, refilled at `rate` per
+/// second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// The rate limits by route, applied per client ip. Requests from
+/// `trusted_proxies` are attributed to the client in their
+/// `X-Forwarded-For` header instead.
+#[derive(Debug)]
+pub struct RateLimits {
+    /// For routes not listed in `routes`; `None` doesn't limit them.
+    pub default: Option<RateLimit>,
+    pub routes: HashMap<String, Option<RateLimit>>,
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Requests to `routes` need one of the `keys`, unless there are none.
+#[derive(Debug)]
+pub struct ApiKeys {
+    /// Names and keys; the names identify the keys in the metrics.
+    pub keys: Vec<(String, Secret)>,
+    pub routes: Vec<String>,
+}
+
+impl ApiKeys {
+    pub fn protects(&self, route: &str) -> bool {
+        !self.keys.is_empty() && self.routes.iter().any(|protected| protected == route)
+    }
+}
+
+/// Requests to `routes` need a bearer token signed with the HS256 secret or,
+/// for RS256, a key from the JWKS url; if neither is set they don't. Routes
+/// that also take API keys accept either.
+#[derive(Debug)]
+pub struct JwtCfg {
+    /// Checked against `iss` if set.
+    pub issuer: Option<String>,
+    /// Checked against `aud` if set.
+    pub audience: Option<String>,
+    pub hs256_secret: Option<Secret>,
+    pub jwks_url: Option<String>,
+    /// How long the key set is used before it's fetched again.
+    pub jwks_cache: Duration,
+    /// Allowed clock skew when checking `exp` and `nbf`.
+    pub leeway: Duration,
+    pub routes: Vec<String>,
+}
+
+/// The Basic auth credentials for `/admin/`, separate from the API keys and
+/// tokens of the public routes.
+#[derive(Debug)]
+pub struct AdminCredentials {
+    pub username: String,
+    pub password: Secret,
+}
+
+impl JwtCfg {
+    pub fn protects(&self, route: &str) -> bool {
+        (self.hs256_secret.is_some() || self.jwks_url.is_some())
+            && self.routes.iter().any(|protected| protected == route)
+    }
+}
+
+impl RateLimits {
+    pub fn limit(&self, route: &str) -> Option<RateLimit> {
+        self.routes.get(route).copied().unwrap_or(self.default)
+    }
+}
+
+/// Plain HTTP is served on a Unix socket too, or instead of TCP, for a proxy
+/// on the same host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnixSocketCfg {
+    /// Replaced if there's a socket there already.
+    pub path: PathBuf,
+    /// The permissions of the socket, like `0o660`; `None` leaves them to the
+    /// umask.
+    pub mode: Option<u32>,
+}
+
+/// HTTPS is served on a port of its own, next to plain HTTP.
+#[derive(Debug)]
+pub struct TlsCfg {
+    pub port: u16,
+    /// PEM files with the certificate chain and its private key.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl ServerCfg {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_addr, self.port)
+    }
+
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls.as_ref().map(|tls| SocketAddr::new(self.bind_addr, tls.port))
+    }
+
+    /// The transform `route` has for `upstream`'s JSON in place of the
+    /// upstream's own, if any.
+    pub fn route_transform(&self, route: &str, upstream: &UpstreamCfg) -> Option<&Transform> {
+        self.transforms.get(route)?.get(upstream.name)
+    }
+
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.grpc_port.map(|port| SocketAddr::new(self.bind_addr, port))
+    }
+}
+
+/// The on-disk representation of the configuration, e.g.
+///
+/// ```toml
+/// [server]
+/// bind = "0.0.0.0"
+/// port = 8080
+///
+/// [upstreams.todo]
+/// url = "http://todos.staging/"
+/// ```
+///
+/// Everything is optional and falls back to the same defaults as the command
+/// line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub upstreams: UpstreamsSection,
+    /// Sources by name, e.g. `[sources.dogs]`.
+    pub sources: BTreeMap<String, SourceSection>,
+    pub slo: SloSection,
+    pub facts: FactsSection,
+    pub graphql: GraphQlSection,
+    pub websocket: WebSocketSection,
+    pub webhooks: WebhooksSection,
+    pub proxy: ProxySection,
+    pub docs: DocsSection,
+    pub plugin: PluginSection,
+    pub i18n: I18nSection,
+    pub query_mapping: QueryMappingSection,
+    pub transform: TransformSection,
+    pub security_headers: SecurityHeadersSection,
+    pub cors: CorsSection,
+    pub compression: CompressionSection,
+    pub ip_filter: IpFilterSection,
+    pub rate_limit: RateLimitSection,
+    pub auth: AuthSection,
+    pub jwt: JwtSection,
+    pub admin: AdminSection,
+    pub vault: VaultSection,
+    pub cache: CacheCfg,
+    pub client: ClientCfg,
+    pub runtime: RuntimeCfg,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Turning it off only serves plain HTTP on `unix_socket`.
+    pub tcp: bool,
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_mode: Option<u32>,
+    /// HTTPS is served if both the certificate and key are set.
+    pub tls_port: u16,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// The gRPC service is served on it if it's set.
+    pub grpc_port: Option<u16>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub access_log: AccessLogFormat,
+    pub response_format: ResponseFormat,
+    pub normalize_paths: PathNormalization,
+    pub request_timeout_ms: u64,
+    pub shutdown_timeout_ms: u64,
+    pub max_body_bytes: u64,
+    /// 0 doesn't limit connections.
+    pub max_connections: usize,
+    pub max_queued_connections: usize,
+    pub connection_queue_timeout_ms: u64,
+    /// 0 is one per core.
+    pub acceptors: usize,
+    pub backlog: u32,
+    pub tcp_nodelay: bool,
+    /// 0 doesn't send keepalive probes.
+    pub tcp_keepalive_ms: u64,
+    /// 0 disables slow request logging.
+    pub slow_request_ms: u64,
+}
+
+impl Default for ServerSection {
+    fn default() -> ServerSection {
+        ServerSection {
+            bind: BIND_ADDR.parse().unwrap(),
+            port: PORT,
+            tcp: true,
+            unix_socket: None,
+            unix_socket_mode: None,
+            tls_port: TLS_PORT,
+            tls_cert: None,
+            tls_key: None,
+            grpc_port: None,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            access_log: AccessLogFormat::Common,
+            response_format: ResponseFormat::Json,
+            normalize_paths: PathNormalization::Redirect,
+            request_timeout_ms: REQUEST_TIMEOUT_MS,
+            shutdown_timeout_ms: SHUTDOWN_TIMEOUT_MS,
+            max_body_bytes: MAX_BODY_BYTES,
+            max_connections: 0,
+            acceptors: 1,
+            max_queued_connections: MAX_QUEUED_CONNECTIONS,
+            connection_queue_timeout_ms: CONNECTION_QUEUE_TIMEOUT_MS,
+            backlog: BACKLOG,
+            tcp_nodelay: false,
+            tcp_keepalive_ms: 0,
+            slow_request_ms: SLOW_REQUEST_MS,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamsSection {
+    pub cats: UpstreamSection,
+    pub todo: UpstreamSection,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceSection {
+    /// The base url of the source's upstream.
+    pub url: String,
+    /// Appended to the url, e.g. `facts?number={count}`, where `{count}` is
+    /// filled from the `count` query parameter.
+    #[serde(default)]
+    pub path: String,
+    /// The field served, with dots between keys and array indices, e.g.
+    /// `facts.0`; short for a transform extracting `$.facts[0]`. Strings are
+    /// served as they are, anything else as JSON.
+    pub field: Option<String>,
+    /// How the value served is made of the upstream's JSON, if not a
+    /// `field`.
+    pub transform: Option<Vec<StepSection>>,
+    /// Any upstream settings but the url, like `timeout_ms` or
+    /// `cache_ttl_ms`.
+    #[serde(default)]
+    pub upstream: UpstreamSection,
+}
+
+impl SourceSection {
+    fn validate(self, name: &str, vault: Option<&VaultCfg>) -> Result<SourceCfg, ConfigError> {
+        let invalid = |detail: &str| ConfigError::Invalid(format!("sources.{}: {}", name, detail));
+        let valid_name = !name.is_empty()
+            && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+        if !valid_name {
+            return Err(invalid("names can only have lowercase letters, digits, '-' and '_'"));
+        }
+        if ["cats", "todo", "todos"].contains(&name) {
+            return Err(invalid("the name is taken by a built-in source"));
+        }
+        if self.upstream.url.is_some() || self.upstream.transform.is_some() {
+            return Err(invalid("the url and transform go in url and transform, not in upstream"));
+        }
+        let params = template_params(&self.path).ok_or_else(|| invalid("path has unbalanced braces or an empty {}"))?;
+        let transform = match (self.field, self.transform) {
+            (Some(field), None) => {
+                if field.split('.').any(str::is_empty) {
+                    return Err(invalid("field must be keys or indices separated by dots"));
+                }
+                let path = field.split('.')
+                    .map(|key| match key.bytes().all(|b| b.is_ascii_digit()) {
+                        true => format!("[{}]", key),
+                        false => format!(".{}", key),
+                    })
+                    .collect::<String>();
+                vec![StepSection::Extract(format!("${}", path))]
+            }
+            (None, Some(transform)) => transform,
+            _ => return Err(invalid("either field or transform must be set")),
+        };
+        let defaults = UpstreamDefaults { name: intern(name), url: "", degrade: false, cache_ttl_ms: 0, extract: "$" };
+        let upstream = UpstreamSection { url: Some(self.url), transform: Some(transform), ..self.upstream };
+        let upstream = upstream.validate(&defaults, vault)?;
+        let path = self.path.trim_start_matches('/').to_owned();
+        Ok(SourceCfg { upstream, path, params })
+    }
+}
+
+/// The names of the `{param}`s in a source's path, or `None` if it isn't a
+/// valid template.
+fn template_params(path: &str) -> Option<Vec<String>> {
+    let mut params = Vec::new();
+    let mut rest = path;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        let name = &rest[start + 1..end];
+        if rest[..start].contains('}') || name.is_empty() || name.contains('{') {
+            return None;
+        }
+        params.push(name.to_owned());
+        rest = &rest[end + 1..];
+    }
+    match rest.contains('}') {
+        true => None,
+        false => Some(params),
+    }
+}
+
+/// Upstream names are `'static`, so those of sources are leaked, but only
+/// once however often the config is reloaded.
+fn intern(name: &str) -> &'static str {
+    static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut names = NAMES.lock().unwrap();
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamSection {
+    /// Defaults to the public api of the upstream.
+    pub url: Option<String>,
+    pub connect_timeout_ms: u64,
+    pub timeout_ms: u64,
+    pub max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub retry_jitter: bool,
+    /// 0 disables the circuit breaker.
+    pub circuit_failures: u32,
+    pub circuit_open_ms: u64,
+    /// Unset disables hedging.
+    pub hedge_percentile: Option<f64>,
+    pub hedge_min_delay_ms: u64,
+    /// Requests per second sent to the upstream; 0 doesn't limit them.
+    pub throttle_rate: f64,
+    pub throttle_burst: u32,
+    pub throttle_max_wait_ms: u64,
+    /// Caps the `Retry-After` of 429 responses, for which no requests are
+    /// sent to the upstream.
+    pub max_cool_down_ms: u64,
+    /// GETs follow redirects, other than from HTTPS to HTTP, up to this many
+    /// times; 0 doesn't follow them.
+    pub max_redirects: u32,
+    /// For responses without caching headers; 0 disables caching. Defaults
+    /// to 5s for cats and 60s for todo.
+    pub cache_ttl_ms: Option<u64>,
+    pub cache_min_ttl_ms: u64,
+    pub cache_max_ttl_ms: u64,
+    /// The client credentials grant a token is requested with; the url, id
+    /// and secret must be set together.
+    pub oauth_token_url: Option<String>,
+    pub oauth_client_id: Option<String>,
+    pub oauth_client_secret: Option<Secret>,
+    pub oauth_scope: Option<String>,
+    /// The secret in Vault whose `vault_key` field is sent in `vault_header`,
+    /// after `vault_prefix`. The prefix defaults to `Bearer ` for the
+    /// `Authorization` header and to nothing for others.
+    pub vault_path: Option<String>,
+    pub vault_key: String,
+    pub vault_header: String,
+    pub vault_prefix: Option<String>,
+    /// Headers copied from the incoming request, like `accept-language`.
+    /// Cached responses are shared regardless of them, except for requests
+    /// forwarding `Authorization`, `Proxy-Authorization` or `Cookie`, which
+    /// aren't cached. The OAuth token or Vault credential replaces a
+    /// forwarded header of the same name.
+    pub forward_headers: Vec<String>,
+    /// How the value served is made of the upstream's JSON, e.g.
+    /// `[{ extract = "$.data" }, { concat = ["$.title", " (#", "$.id", ")"] }]`.
+    /// Defaults to extracting `$.text` for cats and `$.title` for todo.
+    pub transform: Option<Vec<StepSection>>,
+    /// The client certificate and key must be set together.
+    pub tls_client_cert: Option<PathBuf>,
+    pub tls_client_key: Option<PathBuf>,
+    pub tls_ca_bundle: Option<PathBuf>,
+    /// `alpn` or `prior-knowledge` send requests over HTTP/2.
+    pub http2: Http2,
+    /// Whether `/double` degrades to `placeholder` if only this upstream
+    /// fails. Defaults to true for cats and false for todo.
+    pub degrade: Option<bool>,
+    pub placeholder: String,
+}
+
+impl Default for UpstreamSection {
+    fn default() -> UpstreamSection {
+        UpstreamSection {
+            url: None,
+            connect_timeout_ms: UPSTREAM_CONNECT_TIMEOUT_MS,
+            timeout_ms: UPSTREAM_TIMEOUT_MS,
+            max_attempts: MAX_ATTEMPTS,
+            retry_base_delay_ms: RETRY_BASE_DELAY_MS,
+            retry_max_delay_ms: RETRY_MAX_DELAY_MS,
+            retry_jitter: true,
+            circuit_failures: CIRCUIT_FAILURES,
+            circuit_open_ms: CIRCUIT_OPEN_MS,
+            hedge_percentile: None,
+            hedge_min_delay_ms: HEDGE_MIN_DELAY_MS,
+            throttle_rate: 0.0,
+            throttle_burst: THROTTLE_BURST,
+            throttle_max_wait_ms: THROTTLE_MAX_WAIT_MS,
+            max_cool_down_ms: MAX_COOL_DOWN_MS,
+            max_redirects: MAX_REDIRECTS,
+            cache_ttl_ms: None,
+            cache_min_ttl_ms: 0,
+            cache_max_ttl_ms: CACHE_MAX_TTL_MS,
+            oauth_token_url: None,
+            oauth_client_id: None,
+            oauth_client_secret: None,
+            oauth_scope: None,
+            vault_path: None,
+            vault_key: VAULT_KEY.to_owned(),
+            vault_header: "authorization".to_owned(),
+            vault_prefix: None,
+            forward_headers: Vec::new(),
+            transform: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+            tls_ca_bundle: None,
+            http2: Http2::Off,
+            degrade: None,
+            placeholder: PLACEHOLDER.to_owned(),
+        }
+    }
+}
+
+impl UpstreamSection {
+    fn validate(self, defaults: &UpstreamDefaults, vault: Option<&VaultCfg>) -> Result<UpstreamCfg, ConfigError> {
+        let name = defaults.name;
+        if self.connect_timeout_ms == 0 || self.timeout_ms == 0 {
+            return Err(ConfigError::Invalid(format!(
+                "upstreams.{} timeouts must be greater than 0", name
+            )));
+        }
+        if self.max_attempts == 0 {
+            return Err(ConfigError::Invalid(format!(
+                "upstreams.{}.max_attempts must be greater than 0", name
+            )));
+        }
+        if self.hedge_percentile.is_some_and(|p| !(p > 0.0 && p < 1.0)) {
+            return Err(ConfigError::Invalid(format!(
+                "upstreams.{}.hedge_percentile must be between 0 and 1", name
+            )));
+        }
+        if !(self.throttle_rate == 0.0 || self.throttle_rate >= MIN_RATE && self.throttle_rate.is_finite()) {
+            return Err(ConfigError::Invalid(format!(
+                "upstreams.{}.throttle_rate must be 0 or at least {}", name, MIN_RATE
+            )));
+        }
+        if self.throttle_rate > 0.0 && self.throttle_burst == 0 {
+            return Err(ConfigError::Invalid(format!(
+                "upstreams.{}.throttle_burst must be greater than 0", name
+            )));
+        }
+        if self.cache_min_ttl_ms > self.cache_max_ttl_ms {
+            return Err(ConfigError::Invalid(format!(
+                "upstreams.{}.cache_min_ttl_ms must not exceed cache_max_ttl_ms", name
+            )));
+        }
+        let hedge_min_delay = Duration::from_millis(self.hedge_min_delay_ms);
+        let throttle = match self.throttle_rate {
+            rate if rate > 0.0 => Some(ThrottlePolicy {
+                limit: RateLimit { rate, burst: self.throttle_burst },
+                max_wait: Duration::from_millis(self.throttle_max_wait_ms),
+            }),
+            _ => None,
+        };
+        let oauth = match (self.oauth_token_url, self.oauth_client_id, self.oauth_client_secret) {
+            (Some(token_url), Some(client_id), Some(client_secret)) => {
+                if !is_http_url(&token_url) {
+                    return Err(ConfigError::Invalid(format!(
+                        "upstreams.{}.oauth_token_url: {:?} is not an http(s) url", name, token_url
+                    )));
+                }
+                Some(OAuthCfg { token_url, client_id, client_secret, scope: self.oauth_scope })
+            }
+            (None, None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid(format!(
+                    "upstreams.{}: oauth_token_url, oauth_client_id and oauth_client_secret must be set together",
+                    name
+                )))
+            }
+        };
+        let vault = match (self.vault_path, vault) {
+            (Some(path), Some(vault)) => {
+                let vault_header = self.vault_header;
+                let header = vault_header.parse::<HeaderName>().map_err(|_| ConfigError::Invalid(format!(
+                    "upstreams.{}.vault_header: {:?} is not a header name", name, vault_header
+                )))?;
+                if header == AUTHORIZATION && oauth.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "upstreams.{}: the Authorization header can't carry both an OAuth token and a credential from Vault",
+                        name
+                    )));
+                }
+                let prefix = self.vault_prefix.unwrap_or_else(|| match header == AUTHORIZATION {
+                    true => "Bearer ".to_owned(),
+                    false => String::new(),
+                });
+                Some(VaultSecretCfg { vault: vault.clone(), path, key: self.vault_key, header, prefix })
+            }
+            (Some(_), None) => {
+                return Err(ConfigError::Invalid(format!(
+                    "upstreams.{}.vault_path needs vault.address and vault.token", name
+                )))
+            }
+            (None, _) => None,
+        };
+        let forward_headers = self.forward_headers.iter()
+            .map(|header| header.parse::<HeaderName>().map_err(|_| ConfigError::Invalid(format!(
+                "upstreams.{}.forward_headers: {:?} is not a header name", name, header
+            ))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let transform = match self.transform {
+            Some(steps) => Transform::new(steps),
+            None => Transform::extract(defaults.extract),
+        };
+        let transform = transform.map_err(|err| ConfigError::Invalid(format!(
+            "upstreams.{}.transform: {}", name, err
+        )))?;
+        let identity = match (self.tls_client_cert, self.tls_client_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid(format!(
+                    "upstreams.{}: tls_client_cert and tls_client_key must be set together", name
+                )))
+            }
+        };
+        let tls = match (identity, self.tls_ca_bundle) {
+            (None, None) => None,
+            (identity, ca_bundle) => Some(UpstreamTlsCfg { identity, ca_bundle }),
+        };
+        let url = self.url.unwrap_or_else(|| defaults.url.to_owned());
+        Ok(UpstreamCfg {
+            name,
+            url: upstream_url(name, url)?,
+            connect_timeout: Duration::from_millis(self.connect_timeout_ms),
+            timeout: Duration::from_millis(self.timeout_ms),
+            retry: RetryPolicy {
+                max_attempts: self.max_attempts,
+                base_delay: Duration::from_millis(self.retry_base_delay_ms),
+                max_delay: Duration::from_millis(self.retry_max_delay_ms),
+                jitter: self.retry_jitter,
+            },
+            breaker: BreakerPolicy {
+                failures: self.circuit_failures,
+                open_for: Duration::from_millis(self.circuit_open_ms),
+            },
+            hedge: self.hedge_percentile.map(|percentile| HedgePolicy {
+                percentile,
+                min_delay: hedge_min_delay,
+            }),
+            throttle,
+            max_cool_down: Duration::from_millis(self.max_cool_down_ms),
+            max_redirects: self.max_redirects,
+            cache: match self.cache_ttl_ms.unwrap_or(defaults.cache_ttl_ms) {
+                0 => None,
+                ms => Some(CachePolicy {
+                    ttl: Duration::from_millis(ms),
+                    min_ttl: Duration::from_millis(self.cache_min_ttl_ms),
+                    max_ttl: Duration::from_millis(self.cache_max_ttl_ms),
+                }),
+            },
+            oauth,
+            vault,
+            forward_headers,
+            transform,
+            tls,
+            http2: self.http2,
+            placeholder: match self.degrade.unwrap_or(defaults.degrade) {
+                true => Some(self.placeholder),
+                false => None,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SloSection {
+    pub target: f64,
+    /// The latency objective for routes not listed in `routes`.
+    pub latency_ms: u64,
+    /// Latency objectives by route, e.g. `"/double" = 2000`.
+    pub routes: HashMap<String, u64>,
+}
+
+impl Default for SloSection {
+    fn default() -> SloSection {
+        SloSection {
+            target: SLO_TARGET,
+            latency_ms: SLO_LATENCY_MS,
+            routes: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FactsSection {
+    /// The most facts a single request can ask for.
+    pub max_count: usize,
+    pub concurrency: usize,
+    pub stream_interval_ms: u64,
+    /// Shorter than `server.request_timeout_ms`.
+    pub poll_timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphQlSection {
+    pub max_depth: usize,
+    pub max_complexity: usize,
+    pub max_root_fields: usize,
+}
+
+impl Default for GraphQlSection {
+    fn default() -> GraphQlSection {
+        GraphQlSection {
+            max_depth: GRAPHQL_MAX_DEPTH,
+            max_complexity: GRAPHQL_MAX_COMPLEXITY,
+            max_root_fields: GRAPHQL_MAX_ROOT_FIELDS,
+        }
+    }
+}
+
+impl Default for FactsSection {
+    fn default() -> FactsSection {
+        FactsSection {
+            max_count: FACTS_MAX_COUNT,
+            concurrency: FACTS_CONCURRENCY,
+            stream_interval_ms: FACTS_STREAM_INTERVAL_MS,
+            poll_timeout_ms: FACTS_POLL_TIMEOUT_MS,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketSection {
+    pub ping_interval_ms: u64,
+}
+
+impl Default for WebSocketSection {
+    fn default() -> WebSocketSection {
+        WebSocketSection {
+            ping_interval_ms: WEBSOCKET_PING_INTERVAL_MS,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksSection {
+    pub store: Option<PathBuf>,
+    pub max_attempts: u32,
+    pub retry_delay_ms: u64,
+    pub max_retry_delay_ms: u64,
+    pub timeout_ms: u64,
+    pub queue_capacity: usize,
+    pub poll_interval_ms: u64,
+}
+
+impl Default for WebhooksSection {
+    fn default() -> WebhooksSection {
+        WebhooksSection {
+            store: None,
+            max_attempts: WEBHOOKS_MAX_ATTEMPTS,
+            retry_delay_ms: WEBHOOKS_RETRY_DELAY_MS,
+            max_retry_delay_ms: WEBHOOKS_MAX_RETRY_DELAY_MS,
+            timeout_ms: WEBHOOKS_TIMEOUT_MS,
+            queue_capacity: WEBHOOKS_QUEUE_CAPACITY,
+            poll_interval_ms: WEBHOOKS_POLL_INTERVAL_MS,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryMappingSection {
+    /// Mappings by route, e.g. `"/basic" = { path = { id = "id" } }`; only
+    /// `/basic` and `/double` take one.
+    pub routes: HashMap<String, RouteQueryMappingSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteQueryMappingSection {
+    /// Query parameters and the parameters of the upstream path they fill.
+    /// The todo's path, `todos/{id}`, only has `id`.
+    pub path: HashMap<String, String>,
+    /// By upstream, e.g. `todo = { lang = "language" }`, query parameters
+    /// and the names they're passed on to it as. Only the upstreams the
+    /// route fetches from take them.
+    pub query: HashMap<String, HashMap<String, String>>,
+}
+
+impl QueryMappingSection {
+    fn validate(self) -> Result<HashMap<String, QueryMapping>, ConfigError> {
+        let mut routes = HashMap::new();
+        for (route, section) in self.routes {
+            let upstreams: &[&str] = match route.as_str() {
+                "/basic" => &["todo"],
+                "/double" => &["todo", "cats"],
+                _ => {
+                    return Err(ConfigError::Invalid(format!(
+                        "query_mapping.routes: only /basic and /double take a query mapping, not {:?}", route
+                    )))
+                }
+            };
+            let mut todo_id = None;
+            for (param, path_param) in section.path {
+                if path_param != "id" || todo_id.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "query_mapping.routes.{:?}.path: the todo path only has a single parameter, \"id\"", route
+                    )));
+                }
+                todo_id = Some(param);
+            }
+            let mut query = HashMap::new();
+            for (upstream, params) in section.query {
+                if !upstreams.contains(&upstream.as_str()) {
+                    return Err(ConfigError::Invalid(format!(
+                        "query_mapping.routes.{:?}.query: {} doesn't fetch from upstream {:?}", route, route, upstream
+                    )));
+                }
+                // Sorted, so that the same request always makes for the same
+                // upstream uri and cache key.
+                let mut params = params.into_iter().collect::<Vec<_>>();
+                params.sort();
+                query.insert(upstream, params);
+            }
+            routes.insert(route, QueryMapping { todo_id, query });
+        }
+        Ok(routes)
+    }
+}
+
+/// The routes that serve values made of the upstreams' JSON, and the
+/// upstreams they fetch; `None` for any source's.
+const TRANSFORMED_ROUTES: &[(&str, Option<&[&str]>)] = &[
+    ("/basic", Some(&["todo"])),
+    ("/double", Some(&["cats", "todo"])),
+    ("/todos", Some(&["todo"])),
+    ("/todos/{id}", Some(&["todo"])),
+    ("/sources/{name}", None),
+    ("/aggregate", None),
+];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformSection {
+    /// Transforms by route, then by upstream, e.g.
+    /// `"/basic" = { todo = [{ extract = "$.title" }] }`, in place of the
+    /// upstream's.
+    pub routes: HashMap<String, HashMap<String, Vec<StepSection>>>,
+}
+
+impl TransformSection {
+    fn validate(self, sources: &[SourceCfg]) -> Result<HashMap<String, HashMap<String, Transform>>, ConfigError> {
+        let mut routes = HashMap::new();
+        for (route, upstreams) in self.routes {
+            let fetched = match TRANSFORMED_ROUTES.iter().find(|(known, _)| *known == route) {
+                Some((_, fetched)) => fetched,
+                None => {
+                    let known = TRANSFORMED_ROUTES.iter().map(|(route, _)| *route).collect::<Vec<_>>();
+                    return Err(ConfigError::Invalid(format!(
+                        "transform.routes: only {} take a transform, not {:?}", known.join(", "), route
+                    )));
+                }
+            };
+            let mut transforms = HashMap::new();
+            for (upstream, steps) in upstreams {
+                let is_fetched = match fetched {
+                    Some(fetched) => fetched.contains(&upstream.as_str()),
+                    None => ["cats", "todo"].contains(&upstream.as_str())
+                        || sources.iter().any(|source| source.upstream.name == upstream),
+                };
+                if !is_fetched {
+                    return Err(ConfigError::Invalid(format!(
+                        "transform.routes.{:?}: {} doesn't fetch from {:?}", route, route, upstream
+                    )));
+                }
+                let transform = Transform::new(steps).map_err(|err| ConfigError::Invalid(format!(
+                    "transform.routes.{:?}.{}: {}", route, upstream, err
+                )))?;
+                transforms.insert(upstream, transform);
+            }
+            routes.insert(route, transforms);
+        }
+        Ok(routes)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxySection {
+    /// Off by default, since it lets callers send the upstreams anything with
+    /// this service's credentials.
+    pub enabled: bool,
+}
+
+/// Off unless asked for, since the Swagger UI's assets have to be put in
+/// place first.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DocsSection {
+    pub enabled: bool,
+    /// Holds `swagger-ui.css` and `swagger-ui-bundle.js` from the
+    /// swagger-ui-dist package.
+    pub assets: PathBuf,
+}
+
+impl Default for DocsSection {
+    fn default() -> DocsSection {
+        DocsSection { enabled: false, assets: PathBuf::from(DOCS_ASSETS) }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginSection {
+    /// The WASM module, binary or text; needs the `wasm-plugins` feature.
+    pub path: Option<PathBuf>,
+    pub fuel: u64,
+    pub max_memory_bytes: usize,
+}
+
+impl Default for PluginSection {
+    fn default() -> PluginSection {
+        PluginSection {
+            path: None,
+            fuel: PLUGIN_FUEL,
+            max_memory_bytes: PLUGIN_MAX_MEMORY_BYTES,
+        }
+    }
+}
+
+impl PluginSection {
+    fn validate(self) -> Result<Option<PluginCfg>, ConfigError> {
+        let path = match self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        if cfg!(not(feature = "wasm-plugins")) {
+            return Err(ConfigError::Invalid(
+                "plugin.path needs the wasm-plugins feature".to_owned(),
+            ));
+        }
+        if self.fuel == 0 || self.max_memory_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "plugin.fuel and plugin.max_memory_bytes must be greater than 0".to_owned(),
+            ));
+        }
+        Ok(Some(PluginCfg { path, fuel: self.fuel, max_memory_bytes: self.max_memory_bytes }))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct I18nSection {
+    /// A directory with a `<language>.toml` catalog for each language.
+    pub catalogs: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersSection {
+    /// The values of the headers; empty ones aren't sent.
+    pub strict_transport_security: String,
+    pub content_type_options: String,
+    pub frame_options: String,
+    pub referrer_policy: String,
+}
+
+impl Default for SecurityHeadersSection {
+    fn default() -> SecurityHeadersSection {
+        SecurityHeadersSection {
+            strict_transport_security: STRICT_TRANSPORT_SECURITY_VALUE.to_owned(),
+            content_type_options: "nosniff".to_owned(),
+            frame_options: "DENY".to_owned(),
+            referrer_policy: "no-referrer".to_owned(),
+        }
+    }
+}
+
+impl SecurityHeadersSection {
+    fn validate(self) -> Result<SecurityHeaders, ConfigError> {
+        let value = |name: &str, value: String| match value.as_str() {
+            "" => Ok(None),
+            _ => HeaderValue::from_str(&value).map(Some).map_err(|_| {
+                ConfigError::Invalid(format!("security_headers.{}: {:?} is not a valid header value", name, value))
+            }),
+        };
+        let others = vec![
+            (X_CONTENT_TYPE_OPTIONS, value("content_type_options", self.content_type_options)?),
+            (X_FRAME_OPTIONS, value("frame_options", self.frame_options)?),
+            (REFERRER_POLICY, value("referrer_policy", self.referrer_policy)?),
+        ];
+        Ok(SecurityHeaders {
+            strict_transport_security: value("strict_transport_security", self.strict_transport_security)?,
+            others: others.into_iter()
+                .filter_map(|(name, value)| Some((name, value?)))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSection {
+    pub max_age_secs: u64,
+    /// Policies by route, e.g. `"/basic" = { origins = ["https://app.example"] }`.
+    pub routes: HashMap<String, CorsRouteSection>,
+}
+
+impl Default for CorsSection {
+    fn default() -> CorsSection {
+        CorsSection {
+            max_age_secs: CORS_MAX_AGE_SECS,
+            routes: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsRouteSection {
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+}
+
+impl Default for CorsRouteSection {
+    fn default() -> CorsRouteSection {
+        CorsRouteSection {
+            origins: Vec::new(),
+            methods: vec!["GET".to_owned()],
+            headers: Vec::new(),
+        }
+    }
+}
+
+impl CorsSection {
+    fn validate(self) -> Result<Cors, ConfigError> {
+        validate_routes("cors.routes", self.routes.keys())?;
+        let mut routes = HashMap::new();
+        for (route, section) in self.routes {
+            let invalid = |what: &str, value: &str| {
+                ConfigError::Invalid(format!("cors.routes.{:?}: {:?} is not a valid {}", route, value, what))
+            };
+            if let Some(origin) = section.origins.iter().find(|origin| *origin != "*" && !is_origin(origin)) {
+                return Err(invalid("origin", origin));
+            }
+            let methods = section.methods.iter()
+                .map(|method| method.parse::<Method>().map_err(|_| invalid("method", method)))
+                .collect::<Result<_, _>>()?;
+            let headers = section.headers.iter()
+                .map(|header| header.parse::<HeaderName>().map_err(|_| invalid("header", header)))
+                .collect::<Result<_, _>>()?;
+            routes.insert(route, CorsPolicy { origins: section.origins, methods, headers });
+        }
+        Ok(Cors { routes, max_age: Duration::from_secs(self.max_age_secs) })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionSection {
+    pub enabled: bool,
+    pub min_bytes: u64,
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionSection {
+    fn default() -> CompressionSection {
+        CompressionSection {
+            enabled: true,
+            min_bytes: COMPRESSION_MIN_BYTES,
+            content_types: COMPRESSIBLE_TYPES.iter().map(|content_type| content_type.to_string()).collect(),
+        }
+    }
+}
+
+impl CompressionSection {
+    fn validate(self) -> Result<Option<Compression>, ConfigError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        for content_type in &self.content_types {
+            let valid = content_type.split_once('/')
+                .is_some_and(|(kind, subtype)| !kind.is_empty() && kind != "*" && !subtype.is_empty());
+            if !valid {
+                return Err(ConfigError::Invalid(format!(
+                    "compression.content_types: {:?} is not a media type like text/plain or text/*",
+                    content_type
+                )));
+            }
+        }
+        Ok(Some(Compression {
+            min_bytes: self.min_bytes,
+            content_types: self.content_types.iter().map(|content_type| content_type.to_ascii_lowercase()).collect(),
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSection {
+    /// Requests per second per client for routes not listed in `routes`; 0
+    /// doesn't limit them.
+    pub rate: f64,
+    pub burst: u32,
+    /// Limits by route, e.g. `"/double" = { rate = 1, burst = 5 }`.
+    pub routes: HashMap<String, RouteLimitSection>,
+    /// The addresses or networks of proxies whose `X-Forwarded-For` is
+    /// believed.
+    #[serde(deserialize_with = "ip_nets")]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilterSection {
+    /// Empty allows any client that isn't denied.
+    #[serde(deserialize_with = "ip_nets")]
+    pub allow: Vec<IpNet>,
+    #[serde(deserialize_with = "ip_nets")]
+    pub deny: Vec<IpNet>,
+}
+
+/// Networks in CIDR notation, or single addresses.
+fn ip_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter()
+        .map(|net| {
+            net.parse::<IpNet>()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| de::Error::custom(format!("{:?} is not an ip address or network", net)))
+        })
+        .collect()
+}
+
+impl Default for RateLimitSection {
+    fn default() -> RateLimitSection {
+        RateLimitSection {
+            rate: 0.0,
+            burst: RATE_LIMIT_BURST,
+            routes: HashMap::new(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimitSection {
+    /// 0 doesn't limit the route.
+    pub rate: f64,
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+}
+
+fn default_burst() -> u32 {
+    RATE_LIMIT_BURST
+}
+
+impl RateLimitSection {
+    fn validate(self) -> Result<RateLimits, ConfigError> {
+        let limit = |name: &str, rate: f64, burst: u32| {
+            if !(rate == 0.0 || rate >= MIN_RATE && rate.is_finite()) {
+                return Err(ConfigError::Invalid(format!("{}.rate must be 0 or at least {}", name, MIN_RATE)));
+            }
+            if rate > 0.0 && burst == 0 {
+                return Err(ConfigError::Invalid(format!("{}.burst must be greater than 0", name)));
+            }
+            Ok(Some(RateLimit { rate, burst }).filter(|_| rate > 0.0))
+        };
+        validate_routes("rate_limit.routes", self.routes.keys())?;
+        let mut routes = HashMap::new();
+        for (route, section) in self.routes {
+            let name = format!("rate_limit.routes.{:?}", route);
+            routes.insert(route, limit(&name, section.rate, section.burst)?);
+        }
+        Ok(RateLimits {
+            default: limit("rate_limit", self.rate, self.burst)?,
+            routes,
+            trusted_proxies: self.trusted_proxies,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    /// Keys by name, e.g. `mobile = "..."`; none disables authentication.
+    pub api_keys: HashMap<String, Secret>,
+    /// The routes that need a key.
+    pub routes: Vec<String>,
+}
+
+impl Default for AuthSection {
+    fn default() -> AuthSection {
+        AuthSection {
+            api_keys: HashMap::new(),
+            routes: vec!["/basic".to_owned(), "/double".to_owned()],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtSection {
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub hs256_secret: Option<Secret>,
+    pub jwks_url: Option<String>,
+    pub jwks_cache_ms: u64,
+    pub leeway_ms: u64,
+    /// The routes that need a token; none by default.
+    pub routes: Vec<String>,
+}
+
+impl Default for JwtSection {
+    fn default() -> JwtSection {
+        JwtSection {
+            issuer: None,
+            audience: None,
+            hs256_secret: None,
+            jwks_url: None,
+            jwks_cache_ms: JWKS_CACHE_MS,
+            leeway_ms: JWT_LEEWAY_MS,
+            routes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VaultSection {
+    /// Both or neither must be set; neither doesn't use Vault.
+    pub address: Option<String>,
+    pub token: Option<Secret>,
+    pub mount: String,
+    pub refresh_ms: u64,
+    /// 0 doesn't renew the token, e.g. one that doesn't expire.
+    pub renew_ms: u64,
+}
+
+impl Default for VaultSection {
+    fn default() -> VaultSection {
+        VaultSection {
+            address: None,
+            token: None,
+            mount: VAULT_MOUNT.to_owned(),
+            refresh_ms: VAULT_REFRESH_MS,
+            renew_ms: VAULT_RENEW_MS,
+        }
+    }
+}
+
+impl VaultSection {
+    fn validate(self) -> Result<Option<VaultCfg>, ConfigError> {
+        match (self.address, self.token) {
+            (Some(address), Some(token)) => {
+                if !is_http_url(&address) {
+                    return Err(ConfigError::Invalid(format!("vault.address: {:?} is not an http(s) url", address)));
+                }
+                let mount = self.mount.trim_matches('/');
+                if mount.is_empty() || self.refresh_ms == 0 {
+                    return Err(ConfigError::Invalid(
+                        "vault.mount must not be empty and vault.refresh_ms must be greater than 0".to_owned(),
+                    ));
+                }
+                Ok(Some(VaultCfg {
+                    address: address.trim_end_matches('/').to_owned(),
+                    token,
+                    mount: mount.to_owned(),
+                    refresh: Duration::from_millis(self.refresh_ms),
+                    renew: Some(Duration::from_millis(self.renew_ms)).filter(|renew| !renew.is_zero()),
+                }))
+            }
+            (None, None) => Ok(None),
+            _ => Err(ConfigError::Invalid("vault.address and vault.token must be set together".to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSection {
+    /// Both or neither must be set; neither turns the admin endpoints off.
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "reading {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "parsing {}: {}", path.display(), err),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, err) => Some(err),
+            ConfigError::Parse(_, err) => Some(err),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?;
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    /// Checks the values that serde can't and turns the config into the form
+    /// the server uses.
+    pub fn validate(self) -> Result<ServerCfg, ConfigError> {
+        if self.server.request_timeout_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "server.request_timeout_ms must be greater than 0".to_owned(),
+            ));
+        }
+        if self.cache.max_entries == 0 || self.cache.max_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "cache.max_entries and cache.max_bytes must be greater than 0".to_owned(),
+            ));
+        }
+        for (name, key) in &self.auth.api_keys {
+            if normalize_api_key(key.expose()).is_empty() {
+                return Err(ConfigError::Invalid(format!("auth.api_keys.{} must not be empty", name)));
+            }
+        }
+        validate_routes("auth.routes", &self.auth.routes)?;
+        if self.jwt.hs256_secret.as_ref().is_some_and(|secret| secret.expose().is_empty()) {
+            return Err(ConfigError::Invalid("jwt.hs256_secret must not be empty".to_owned()));
+        }
+        if let Some(url) = &self.jwt.jwks_url {
+            if !is_http_url(url) {
+                return Err(ConfigError::Invalid(format!("jwt.jwks_url: {:?} is not an http(s) url", url)));
+            }
+        }
+        validate_routes("jwt.routes", &self.jwt.routes)?;
+        let tls = match (self.server.tls_cert, self.server.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsCfg { port: self.server.tls_port, cert, key }),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "server.tls_cert and server.tls_key must be set together".to_owned(),
+                ))
+            }
+        };
+        if tls.is_some() && self.server.tls_port == self.server.port && self.server.port != 0 {
+            return Err(ConfigError::Invalid(
+                "server.tls_port must differ from server.port".to_owned(),
+            ));
+        }
+        let tls_port = tls.as_ref().map(|tls| tls.port);
+        if cfg!(not(unix)) && self.server.unix_socket.is_some() {
+            return Err(ConfigError::Invalid("server.unix_socket is only supported on Unix".to_owned()));
+        }
+        if self.server.unix_socket_mode.is_some_and(|mode| mode > 0o777) {
+            return Err(ConfigError::Invalid("server.unix_socket_mode must be at most 0o777".to_owned()));
+        }
+        let unix_socket = match (self.server.unix_socket, self.server.unix_socket_mode) {
+            (Some(path), mode) => Some(UnixSocketCfg { path, mode }),
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err(ConfigError::Invalid("server.unix_socket_mode needs server.unix_socket".to_owned()))
+            }
+        };
+        let acceptors = match self.server.acceptors {
+            0 if cfg!(unix) => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+            0 => 1,
+            acceptors => acceptors,
+        };
+        if cfg!(not(unix)) && acceptors > 1 {
+            return Err(ConfigError::Invalid("server.acceptors above 1 is only supported on Unix".to_owned()));
+        }
+        if self.runtime.max_blocking_threads == 0 {
+            return Err(ConfigError::Invalid("runtime.max_blocking_threads must be positive".to_owned()));
+        }
+        if self.server.backlog == 0 || self.server.backlog > i32::MAX as u32 {
+            return Err(ConfigError::Invalid("server.backlog must be between 1 and 2147483647".to_owned()));
+        }
+        if !self.server.tcp && unix_socket.is_none() {
+            return Err(ConfigError::Invalid("server.tcp can only be off with server.unix_socket set".to_owned()));
+        }
+        if let Some(grpc_port) = self.server.grpc_port.filter(|port| *port != 0) {
+            if grpc_port == self.server.port || Some(grpc_port) == tls_port {
+                return Err(ConfigError::Invalid(
+                    "server.grpc_port must differ from server.port and server.tls_port".to_owned(),
+                ));
+            }
+        }
+        let admin = match (self.admin.username, self.admin.password) {
+            (Some(username), Some(password))
+                if !username.is_empty() && !username.contains(':') && !password.expose().is_empty() =>
+            {
+                Some(AdminCredentials { username, password })
+            }
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "admin.username and admin.password must both be set and not empty, and the username can't contain ':'".to_owned(),
+                ))
+            }
+        };
+        let vault = self.vault.validate()?;
+        if self.facts.max_count == 0 || self.facts.concurrency == 0 || self.facts.stream_interval_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "facts.max_count, facts.concurrency and facts.stream_interval_ms must be greater than 0".to_owned(),
+            ));
+        }
+        if self.facts.poll_timeout_ms == 0 || self.facts.poll_timeout_ms >= self.server.request_timeout_ms {
+            return Err(ConfigError::Invalid(
+                "facts.poll_timeout_ms must be greater than 0 and less than server.request_timeout_ms".to_owned(),
+            ));
+        }
+        if self.graphql.max_depth == 0 || self.graphql.max_complexity == 0 || self.graphql.max_root_fields == 0 {
+            return Err(ConfigError::Invalid(
+                "graphql.max_depth, graphql.max_complexity and graphql.max_root_fields must be greater than 0".to_owned(),
+            ));
+        }
+        if self.websocket.ping_interval_ms == 0 {
+            return Err(ConfigError::Invalid("websocket.ping_interval_ms must be greater than 0".to_owned()));
+        }
+        if self.webhooks.max_attempts == 0
+            || self.webhooks.timeout_ms == 0
+            || self.webhooks.queue_capacity == 0
+            || self.webhooks.poll_interval_ms == 0
+        {
+            return Err(ConfigError::Invalid(
+                "webhooks.max_attempts, webhooks.timeout_ms, webhooks.queue_capacity and webhooks.poll_interval_ms must be greater than 0"
+                    .to_owned(),
+            ));
+        }
+        if self.webhooks.max_attempts > WEBHOOKS_MAX_ATTEMPTS_LIMIT {
+            return Err(ConfigError::Invalid(format!(
+                "webhooks.max_attempts must be at most {}", WEBHOOKS_MAX_ATTEMPTS_LIMIT
+            )));
+        }
+        if !(self.slo.target > 0.0 && self.slo.target < 1.0) {
+            return Err(ConfigError::Invalid(
+                "slo.target must be between 0 and 1".to_owned(),
+            ));
+        }
+        let sources = self.sources.into_iter()
+            .map(|(name, source)| source.validate(&name, vault.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let transforms = self.transform.validate(&sources)?;
+        Ok(ServerCfg {
+            cats: self.upstreams.cats.validate(&CATS, vault.as_ref())?,
+            todo: self.upstreams.todo.validate(&TODO, vault.as_ref())?,
+            sources,
+            vault,
+            bind_addr: self.server.bind,
+            port: self.server.port,
+            tcp: self.server.tcp,
+            unix_socket,
+            tls,
+            grpc_port: self.server.grpc_port,
+            log_level: self.server.log_level,
+            log_format: self.server.log_format,
+            access_log: self.server.access_log,
+            response_format: self.server.response_format,
+            normalize_paths: self.server.normalize_paths,
+            request_timeout: Duration::from_millis(self.server.request_timeout_ms),
+            shutdown_timeout: Duration::from_millis(self.server.shutdown_timeout_ms),
+            max_body_bytes: self.server.max_body_bytes,
+            max_connections: Some(self.server.max_connections).filter(|max| *max > 0),
+            acceptors,
+            max_queued_connections: self.server.max_queued_connections,
+            connection_queue_timeout: Duration::from_millis(self.server.connection_queue_timeout_ms),
+            backlog: self.server.backlog,
+            tcp_nodelay: self.server.tcp_nodelay,
+            tcp_keepalive: match self.server.tcp_keepalive_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            slow_request: match self.server.slow_request_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            slo: Slo {
+                target: self.slo.target,
+                latency: Duration::from_millis(self.slo.latency_ms),
+                routes: self.slo.routes.into_iter()
+                    .map(|(route, ms)| (route, Duration::from_millis(ms)))
+                    .collect(),
+            },
+            facts: Facts {
+                max_count: self.facts.max_count,
+                concurrency: self.facts.concurrency,
+                stream_interval: Duration::from_millis(self.facts.stream_interval_ms),
+                poll_timeout: Duration::from_millis(self.facts.poll_timeout_ms),
+            },
+            graphql: GraphQl {
+                max_depth: self.graphql.max_depth,
+                max_complexity: self.graphql.max_complexity,
+                max_root_fields: self.graphql.max_root_fields,
+            },
+            websocket: WebSocket { ping_interval: Duration::from_millis(self.websocket.ping_interval_ms) },
+            webhooks: Webhooks {
+                store: self.webhooks.store,
+                retry: RetryPolicy {
+                    max_attempts: self.webhooks.max_attempts,
+                    base_delay: Duration::from_millis(self.webhooks.retry_delay_ms),
+                    max_delay: Duration::from_millis(self.webhooks.max_retry_delay_ms),
+                    jitter: false,
+                },
+                timeout: Duration::from_millis(self.webhooks.timeout_ms),
+                queue_capacity: self.webhooks.queue_capacity,
+                poll_interval: Duration::from_millis(self.webhooks.poll_interval_ms),
+            },
+            proxy: self.proxy.enabled,
+            docs: match self.docs.enabled {
+                true => Some(self.docs.assets),
+                false => None,
+            },
+            plugin: self.plugin.validate()?,
+            catalogs: self.i18n.catalogs,
+            query_mapping: self.query_mapping.validate()?,
+            transforms,
+            security_headers: self.security_headers.validate()?,
+            cors: self.cors.validate()?,
+            compression: self.compression.validate()?,
+            ip_filter: IpFilter { allow: self.ip_filter.allow, deny: self.ip_filter.deny },
+            rate_limit: self.rate_limit.validate()?,
+            api_keys: ApiKeys {
+                keys: self.auth.api_keys.into_iter()
+                    .map(|(name, key)| (name, Secret::new(normalize_api_key(key.expose()))))
+                    .collect(),
+                routes: self.auth.routes,
+            },
+            jwt: JwtCfg {
+                issuer: self.jwt.issuer,
+                audience: self.jwt.audience,
+                hs256_secret: self.jwt.hs256_secret,
+                jwks_url: self.jwt.jwks_url,
+                jwks_cache: Duration::from_millis(self.jwt.jwks_cache_ms),
+                leeway: Duration::from_millis(self.jwt.leeway_ms),
+                routes: self.jwt.routes,
+            },
+            admin,
+            cache: self.cache,
+            client: self.client,
+            runtime: self.runtime,
+        })
+    }
+}
+
+/// Checks that the routes configured in `section` are the server's route
+/// patterns, like `/todos/{id}` rather than `/todos/1`, since one that's
+/// misspelled would quietly be left out.
+fn validate_routes<'a>(section: &str, routes: impl IntoIterator<Item = &'a String>) -> Result<(), ConfigError> {
+    let router = crate::server::routes();
+    for route in routes {
+        if router.at(route).is_none_or(|(known, _)| known.pattern() != route) {
+            return Err(ConfigError::Invalid(format!("{}: {:?} is not a route", section, route)));
+        }
+    }
+    Ok(())
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.parse::<Uri>().is_ok_and(|uri| matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some())
+}
+
+/// Whether `origin` is a scheme, host and port as browsers send them in
+/// `Origin`.
+fn is_origin(origin: &str) -> bool {
+    !origin.ends_with('/')
+        && origin.parse::<Uri>().is_ok_and(|uri| {
+            uri.scheme().is_some() && uri.host().is_some() && uri.path_and_query().is_none_or(|path| path == "/")
+        })
+}
+
+/// Upstream paths are appended directly to the base url, so make sure it ends
+/// with a slash.
+fn upstream_url(name: &str, mut url: String) -> Result<String, ConfigError> {
+    let invalid = || ConfigError::Invalid(format!("upstreams.{}.url: {:?} is not an http(s) url", name, url));
+    let uri: Uri = url.parse().map_err(|_| invalid())?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") if uri.host().is_some() => {}
+        _ => return Err(invalid()),
+    }
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cfg: Config = toml::from_str(r#"
+            [server]
+            port = 8080
+            log_level = "debug"
+            tcp = false
+            unix_socket = "/run/rust-mockito-example.sock"
+            unix_socket_mode = 0o660
+
+            [upstreams.cats]
+            url = "http://cats.staging"
+            http2 = "prior-knowledge"
+
+            [client]
+            pool_max_idle_per_host = 4
+            hosts = { "todo.staging" = "10.0.0.5" }
+
+            [runtime]
+            worker_threads = 2
+        "#).unwrap();
+        let cfg = cfg.validate().unwrap();
+
+        assert_eq!(cfg.addr(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(cfg.log_level, LogLevel::Debug);
+        assert!(!cfg.tcp);
+        let unix_socket = UnixSocketCfg { path: PathBuf::from("/run/rust-mockito-example.sock"), mode: Some(0o660) };
+        assert_eq!(cfg.unix_socket, Some(unix_socket));
+        assert_eq!(cfg.cats.url, "http://cats.staging/");
+        assert_eq!(cfg.todo.url, TODO_URL);
+        assert_eq!(cfg.todo.retry.max_attempts, MAX_ATTEMPTS);
+        assert_eq!(cfg.cats.placeholder.as_deref(), Some(PLACEHOLDER));
+        assert_eq!(cfg.todo.placeholder, None);
+        assert_eq!(cfg.cats.http2, Http2::PriorKnowledge);
+        assert_eq!(cfg.todo.http2, Http2::Off);
+        assert_eq!(cfg.client.pool_max_idle_per_host, 4);
+        assert_eq!(cfg.client.pool_idle_timeout_ms, POOL_IDLE_TIMEOUT_MS);
+        assert_eq!(cfg.client.hosts["todo.staging"], "10.0.0.5".parse::<IpAddr>().unwrap());
+        assert_eq!(cfg.runtime.worker_threads, 2);
+        assert_eq!(cfg.runtime.thread_name, THREAD_NAME);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let cfg: Config = toml::from_str(r#"
+            [rate_limit]
+            rate = 5
+            trusted_proxies = ["10.0.0.0/8", "::1"]
+            routes = { "/double" = { rate = 1, burst = 2 }, "/healthz" = { rate = 0 } }
+        "#).unwrap();
+        let limits = cfg.validate().unwrap().rate_limit;
+
+        assert_eq!(limits.limit("/basic"), Some(RateLimit { rate: 5.0, burst: RATE_LIMIT_BURST }));
+        assert_eq!(limits.limit("/double"), Some(RateLimit { rate: 1.0, burst: 2 }));
+        assert_eq!(limits.limit("/healthz"), None);
+        assert_eq!(limits.trusted_proxies.len(), 2);
+
+        let cfg: Config = toml::from_str("[rate_limit]\nrate = 1\nburst = 0").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let cfg: Config = toml::from_str(r#"
+            [ip_filter]
+            allow = ["10.0.0.0/8", "192.0.2.1"]
+            deny = ["10.1.0.0/16"]
+        "#).unwrap();
+        let filter = cfg.validate().unwrap().ip_filter;
+        let allows = |ip: &str| filter.allows(ip.parse().unwrap());
+
+        assert!(allows("10.0.0.1"));
+        assert!(allows("::ffff:10.0.0.1"));
+        assert!(allows("192.0.2.1"));
+        assert!(!allows("10.1.0.1"));
+        assert!(!allows("192.0.2.2"));
+        // Without an allow list anyone not denied is.
+        let filter = IpFilter { allow: Vec::new(), deny: filter.deny };
+        assert!(filter.allows("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let cfg: Config = toml::from_str("[auth]\napi_keys = { mobile = \" s3cret\\n\" }").unwrap();
+        let api_keys = cfg.validate().unwrap().api_keys;
+        assert_eq!(api_keys.keys[0].1.expose(), "s3cret");
+
+        let cfg: Config = toml::from_str("[auth]\napi_keys = { mobile = \" \\n\" }").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(toml::from_str::<Config>("[server]\nprot = 8080").is_err());
+
+        let cfg: Config = toml::from_str("[upstreams.todo]\nurl = \"todos\"").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+
+        let cfg: Config = toml::from_str("[admin]\nusername = \"ops\"").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+
+        let cfg: Config = toml::from_str("[server]\ntls_cert = \"cert.pem\"").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+
+        // Something must be served plain HTTP on.
+        let cfg: Config = toml::from_str("[server]\ntcp = false").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+        let cfg: Config = toml::from_str("[server]\nunix_socket_mode = 0o660").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+
+        let cfg: Config = toml::from_str("[runtime]\nmax_blocking_threads = 0").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+        let cfg: Config = toml::from_str("[server]\nbacklog = 0").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+
+        // 0 acceptors is one per core.
+        let cfg: Config = toml::from_str("[server]\nacceptors = 0").unwrap();
+        assert!(cfg.validate().unwrap().acceptors >= 1);
+
+        // Vault must be configured for upstreams to read from it.
+        let cfg: Config = toml::from_str("[upstreams.todo]\nvault_path = \"todo\"").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+        let cfg: Config = toml::from_str("[vault]\naddress = \"http://vault:8200\"\ntoken = \"t\"\n[upstreams.todo]\nvault_path = \"todo\"").unwrap();
+        let cfg = cfg.validate().unwrap();
+        assert_eq!(cfg.todo.vault.unwrap().prefix, "Bearer ");
+        assert_eq!(cfg.vault.unwrap().renew, Some(Duration::from_secs(3600)));
+        let cfg: Config = toml::from_str("[vault]\naddress = \"http://vault:8200\"\ntoken = \"t\"\nrenew_ms = 0").unwrap();
+        assert_eq!(cfg.validate().unwrap().vault.unwrap().renew, None);
+
+        let cfg: Config = toml::from_str("[upstreams.cats]\nforward_headers = [\"accept language\"]").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+        let cfg: Config = toml::from_str("[upstreams.todo]\nforward_headers = [\"Authorization\", \"cookie\"]").unwrap();
+        assert_eq!(cfg.validate().unwrap().todo.forward_headers, [AUTHORIZATION, HeaderName::from_static("cookie")]);
+
+        // The wait for a token would overflow.
+        for section in &["[rate_limit]\nrate = 1e-300", "[rate_limit]\nrate = -1", "[upstreams.cats]\nthrottle_rate = 1e-300"] {
+            let cfg: Config = toml::from_str(section).unwrap();
+            assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))), "{}", section);
+        }
+
+        // Deliveries would be retried for days.
+        let cfg: Config = toml::from_str("[webhooks]\nmax_attempts = 1000").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+
+        let cfg: Config = toml::from_str("[plugin]\npath = \"plugin.wasm\"\nfuel = 0").unwrap();
+        assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))));
+
+        for content_type in &["json", "*/*", "text/"] {
+            let cfg: Config = toml::from_str(&format!("[compression]\ncontent_types = [{:?}]", content_type)).unwrap();
+            assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))), "{}", content_type);
+        }
+
+        let cfg: Config = toml::from_str("[upstreams.todo]\ntransform = [{ extract = \"$.title\" }, { concat = [\"$oops\"] }]").unwrap();
+        match cfg.validate() {
+            Err(ConfigError::Invalid(detail)) => assert!(detail.starts_with("upstreams.todo.transform: step 2: "), "{}", detail),
+            _ => panic!("the transform is invalid"),
+        }
+
+        for transform in [
+            "\"/facts\" = { cats = [{ extract = \"$.text\" }] }",
+            "\"/basic\" = { cats = [{ extract = \"$.text\" }] }",
+            "\"/sources/{name}\" = { dogs = [{ extract = \"$.text\" }] }",
+            "\"/basic\" = { todo = [{ rename = { id = \"name\", title = \"name\" } }] }",
+        ] {
+            let cfg: Config = toml::from_str(&format!("[transform.routes]\n{}", transform)).unwrap();
+            assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))), "{}", transform);
+        }
+        let cfg: Config = toml::from_str(
+            "[sources.dogs]\nurl = \"https://dogs.example/\"\nfield = \"fact\"\n[transform.routes.\"/aggregate\"]\ndogs = [{ extract = \"$.text\" }]",
+        ).unwrap();
+        let cfg = cfg.validate().unwrap();
+        assert!(cfg.route_transform("/aggregate", &cfg.sources[0].upstream).is_some());
+        assert!(cfg.route_transform("/sources/{name}", &cfg.sources[0].upstream).is_none());
+
+        for mapping in [
+            "\"/todos\" = { path = { id = \"id\" } }",
+            "\"/basic\" = { path = { id = \"todo\" } }",
+            "\"/basic\" = { query = { cats = { lang = \"language\" } } }",
+            "\"/double\" = { query = { dogs = { lang = \"language\" } } }",
+        ] {
+            let cfg: Config = toml::from_str(&format!("[query_mapping.routes]\n{}", mapping)).unwrap();
+            assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))), "{}", mapping);
+        }
+
+        for source in [
+            "[sources.Dogs]\nurl = \"https://dogs.example/\"\nfield = \"fact\"",
+            "[sources.todo]\nurl = \"https://dogs.example/\"\nfield = \"fact\"",
+            "[sources.dogs]\nurl = \"https://dogs.example/\"\nfield = \"facts..0\"",
+            "[sources.dogs]\nurl = \"https://dogs.example/\"\npath = \"facts/{}\"\nfield = \"fact\"",
+            "[sources.dogs]\nurl = \"https://dogs.example/\"\npath = \"facts/}{id\"\nfield = \"fact\"",
+            "[sources.dogs]\nurl = \"https://dogs.example/\"\nfield = \"fact\"\n[sources.dogs.upstream]\nurl = \"https://other.example/\"",
+            "[sources.dogs]\nurl = \"https://dogs.example/\"",
+            "[sources.dogs]\nurl = \"https://dogs.example/\"\nfield = \"fact\"\ntransform = [{ extract = \"$.fact\" }]",
+            "[sources.dogs]\nurl = \"https://dogs.example/\"\ntransform = [{ extract = \"fact\" }]",
+        ] {
+            let cfg: Config = toml::from_str(source).unwrap();
+            assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))), "{}", source);
+        }
+        let cfg: Config = toml::from_str(
+            "[sources.dogs]\nurl = \"https://dogs.example/api\"\npath = \"/facts/{id}?lang={lang}\"\nfield = \"facts.0\"",
+        ).unwrap();
+        let dogs = cfg.validate().unwrap().sources.remove(0);
+        assert_eq!(dogs.upstream.name, "dogs");
+        assert_eq!(dogs.upstream.url, "https://dogs.example/api/");
+        assert_eq!(dogs.path, "facts/{id}?lang={lang}");
+        assert_eq!(dogs.params, vec!["id", "lang"]);
+        let facts = serde_json::json!({ "facts": ["dogs are loyal"] });
+        assert_eq!(dogs.upstream.transform.apply(facts), Ok(serde_json::json!("dogs are loyal")));
+
+        for origin in ["https://app.example/", "app.example", "https://app.example/path"] {
+            let cfg: Config = toml::from_str(&format!("[cors.routes.\"/basic\"]\norigins = [{:?}]", origin)).unwrap();
+            assert!(matches!(cfg.validate(), Err(ConfigError::Invalid(_))), "{}", origin);
+        }
+        let cfg: Config = toml::from_str("[cors.routes.\"/basic\"]\norigins = [\"http://localhost:8080\"]").unwrap();
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_routes() {
+        // The settings that take routes, with {route} in place of one.
+        let settings = [
+            "[rate_limit.routes]\n{route} = { rate = 1 }",
+            "[auth]\nroutes = [{route}]",
+            "[jwt]\nroutes = [{route}]",
+            "[cors.routes.{route}]\norigins = [\"*\"]",
+        ];
+        for setting in settings {
+            let cfg = |route: &str| setting.replace("{route}", &format!("{:?}", route));
+            for route in ["/double", "/todos/{id}"] {
+                assert!(toml::from_str::<Config>(&cfg(route)).unwrap().validate().is_ok(), "{}", cfg(route));
+            }
+            // Misspelled, or a path rather than its route.
+            for route in ["/dubble", "/todos/1", "/admin/cache/1"] {
+                let invalid = toml::from_str::<Config>(&cfg(route)).unwrap().validate();
+                assert!(matches!(invalid, Err(ConfigError::Invalid(_))), "{}", cfg(route));
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::config::CorsPolicy;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::time::Duration;
+use tracing::debug;
+
+/// The answer to `req` if it's a preflight request for a route with
+/// `policy`. Which methods and headers it may use is only answered if the
+/// policy allows them all; the origin is left to `allow_origin`, like for any
+/// other response.
+pub fn preflight(req: &Request<Body>, policy: &CorsPolicy, max_age: Duration) -> Option<Response<Body>> {
+    if req.method() != Method::OPTIONS || !req.headers().contains_key(ORIGIN) {
+        return None;
+    }
+    let method = req.headers().get(ACCESS_CONTROL_REQUEST_METHOD)?;
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::NO_CONTENT;
+    let headers = res.headers_mut();
+    headers.insert(VARY, HeaderValue::from_static("access-control-request-method, access-control-request-headers"));
+
+    let method_allowed = method.to_str().ok()
+        .and_then(|method| method.parse::<Method>().ok())
+        .is_some_and(|method| policy.methods.contains(&method));
+    let headers_allowed = requested_headers(req.headers())
+        .is_some_and(|requested| requested.iter().all(|header| policy.headers.contains(header)));
+    if !(method_allowed && headers_allowed) {
+        debug!(?method, "rejected CORS preflight request");
+        return Some(res);
+    }
+    headers.insert(ACCESS_CONTROL_ALLOW_METHODS, join(policy.methods.iter().map(Method::as_str)));
+    if !policy.headers.is_empty() {
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, join(policy.headers.iter().map(HeaderName::as_str)));
+    }
+    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+    Some(res)
+}
+
+/// Lets the browser that sent a request from `origin` read the response if
+/// `policy` allows the origin.
+pub fn allow_origin(headers: &mut HeaderMap, origin: Option<&HeaderValue>, policy: &CorsPolicy) {
+    if policy.any_origin() {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        return;
+    }
+    // The answer depends on the origin, so caches must keep them apart.
+    headers.append(VARY, HeaderValue::from_static("origin"));
+    if let Some(origin) = origin.filter(|origin| origin.to_str().is_ok_and(|origin| policy.allows_origin(origin))) {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    }
+}
+
+/// The headers a preflight request asks to send, or `None` if one of them
+/// isn't a valid header name.
+fn requested_headers(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut requested = Vec::new();
+    for value in headers.get_all(ACCESS_CONTROL_REQUEST_HEADERS) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if !name.is_empty() {
+                requested.push(name.parse().ok()?);
+            }
+        }
+    }
+    Some(requested)
+}
+
+fn join<'a>(values: impl Iterator<Item = &'a str>) -> HeaderValue {
+    let joined = values.collect::<Vec<_>>().join(", ");
+    HeaderValue::from_str(&joined).expect("methods and header names are valid header values")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str]) -> CorsPolicy {
+        CorsPolicy {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: vec![Method::GET],
+            headers: vec![HeaderName::from_static("x-api-key")],
+        }
+    }
+
+    fn preflight_req(method: &str, headers: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/basic")
+            .header(ORIGIN, "https://app.example")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, headers)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_preflight() {
+        let policy = policy(&["https://app.example"]);
+        let max_age = Duration::from_secs(600);
+
+        let res = preflight(&preflight_req("GET", "X-Api-Key"), &policy, max_age).unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET");
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "x-api-key");
+        assert_eq!(res.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+
+        for req in [preflight_req("DELETE", ""), preflight_req("GET", "x-api-key, x-other")] {
+            let res = preflight(&req, &policy, max_age).unwrap();
+            assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+        }
+        // Not a preflight request.
+        let req = Request::get("/basic").header(ORIGIN, "https://app.example").body(Body::empty()).unwrap();
+        assert!(preflight(&req, &policy, max_age).is_none());
+    }
+
+    #[test]
+    fn test_allow_origin() {
+        let origin = HeaderValue::from_static("https://app.example");
+
+        let mut headers = HeaderMap::new();
+        allow_origin(&mut headers, Some(&origin), &policy(&["https://APP.example"]));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example");
+        assert_eq!(headers[VARY], "origin");
+
+        let mut headers = HeaderMap::new();
+        allow_origin(&mut headers, Some(&origin), &policy(&["https://other.example"]));
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let mut headers = HeaderMap::new();
+        allow_origin(&mut headers, None, &policy(&["*"]));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+}
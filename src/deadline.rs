@@ -0,0 +1,119 @@
+//! Deadlines clients set on their requests with `X-Request-Timeout-Ms`, or
+//! `grpc-timeout` as gRPC clients do, which shorten the request timeout and
+//! are propagated to the upstream requests made while handling them.
+
+use hyper::header::{HeaderMap, HeaderValue};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+pub const REQUEST_TIMEOUT_MS: &str = "x-request-timeout-ms";
+
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+tokio::task_local! {
+    static DEADLINE: Option<Instant>;
+}
+
+/// How long the client of a request with `headers` is willing to wait, if it
+/// says; invalid values are ignored.
+pub fn requested(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(value) = headers.get(REQUEST_TIMEOUT_MS) {
+        return value.to_str().ok()?.trim().parse().ok().map(Duration::from_millis);
+    }
+    parse_grpc_timeout(headers.get(GRPC_TIMEOUT)?.to_str().ok()?)
+}
+
+/// Up to eight digits followed by a unit: `H`ours, `M`inutes, `S`econds,
+/// `m`illi-, `u`micro- or `n`anoseconds.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount = amount.parse::<u64>().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Runs `fut`, the handling of a request whose client gives up at
+/// `deadline`.
+pub async fn scope<F: Future>(deadline: Instant, fut: F) -> F::Output {
+    DEADLINE.scope(Some(deadline), fut).await
+}
+
+/// Runs `fut` regardless of the current request's deadline, as when it's
+/// done on behalf of other requests too.
+pub async fn detached<F: Future>(fut: F) -> F::Output {
+    DEADLINE.scope(None, fut).await
+}
+
+/// What's left of the current request's deadline, if its client set one.
+pub fn remaining() -> Option<Duration> {
+    let deadline = DEADLINE.try_with(|deadline| *deadline).ok().flatten()?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// `timeout`, or what's left of the deadline if that's shorter, and whether
+/// it's the deadline.
+pub fn budget(timeout: Duration) -> (Duration, bool) {
+    match remaining() {
+        Some(remaining) if remaining < timeout => (remaining, true),
+        _ => (timeout, false),
+    }
+}
+
+/// Tells an upstream how long it has to answer.
+pub fn inject(headers: &mut HeaderMap) {
+    if let Some(remaining) = remaining() {
+        headers.insert(REQUEST_TIMEOUT_MS, HeaderValue::from(remaining.as_millis() as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_requested() {
+        assert_eq!(requested(&HeaderMap::new()), None);
+        assert_eq!(requested(&headers(REQUEST_TIMEOUT_MS, "250")), Some(Duration::from_millis(250)));
+        assert_eq!(requested(&headers(REQUEST_TIMEOUT_MS, "soon")), None);
+        assert_eq!(requested(&headers(GRPC_TIMEOUT, "250m")), Some(Duration::from_millis(250)));
+        assert_eq!(requested(&headers(GRPC_TIMEOUT, "2S")), Some(Duration::from_secs(2)));
+        assert_eq!(requested(&headers(GRPC_TIMEOUT, "1H")), Some(Duration::from_secs(3600)));
+        for invalid in &["m", "250", "250x", "-1S", "123456789m"] {
+            assert_eq!(parse_grpc_timeout(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(budget(Duration::from_secs(1)), (Duration::from_secs(1), false));
+        rt.block_on(scope(Instant::now() + Duration::from_secs(60), async {
+            assert_eq!(budget(Duration::from_secs(1)), (Duration::from_secs(1), false));
+            let (left, deadline) = budget(Duration::from_secs(120));
+            assert!(deadline && left <= Duration::from_secs(60));
+            detached(async {
+                assert_eq!(remaining(), None);
+                assert_eq!(budget(Duration::from_secs(120)), (Duration::from_secs(120), false));
+            }).await;
+        }));
+    }
+}
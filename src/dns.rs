@@ -0,0 +1,65 @@
+//! Resolves the upstreams' hosts, to the addresses in `[client.hosts]` for
+//! those listed there, like for testing against a staging cluster under the
+//! production names or where DNS doesn't work.
+
+use futures::future::BoxFuture;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::vec;
+
+#[derive(Clone)]
+pub struct Resolver {
+    /// Keyed by lowercased host.
+    hosts: Arc<HashMap<String, IpAddr>>,
+    gai: GaiResolver,
+}
+
+impl Resolver {
+    pub fn new(hosts: &HashMap<String, IpAddr>) -> Resolver {
+        Resolver {
+            hosts: Arc::new(hosts.iter().map(|(host, addr)| (host.to_ascii_lowercase(), *addr)).collect()),
+            gai: GaiResolver::new(),
+        }
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = vec::IntoIter<IpAddr>;
+    type Error = std::io::Error;
+    type Future = BoxFuture<'static, std::io::Result<vec::IntoIter<IpAddr>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.gai.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        if let Some(addr) = self.hosts.get(&name.as_str().to_ascii_lowercase()) {
+            let addrs = vec![*addr];
+            return Box::pin(async move { Ok(addrs.into_iter()) });
+        }
+        let resolving = self.gai.call(name);
+        Box::pin(async move { Ok(resolving.await?.collect::<Vec<_>>().into_iter()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut hosts = HashMap::new();
+        hosts.insert("Todo.Staging".to_owned(), "10.0.0.5".parse().unwrap());
+        let mut resolver = Resolver::new(&hosts);
+
+        let addrs = rt.block_on(resolver.call("todo.staging".parse().unwrap())).unwrap();
+        assert_eq!(addrs.collect::<Vec<_>>(), vec!["10.0.0.5".parse::<IpAddr>().unwrap()]);
+        let addrs = rt.block_on(async { resolver.call("localhost".parse().unwrap()).await }).unwrap();
+        assert!(addrs.into_iter().all(|addr| addr.is_loopback()));
+    }
+}
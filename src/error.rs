@@ -0,0 +1,260 @@
+use crate::breaker::CircuitOpen;
+use crate::config::ConfigError;
+use crate::i18n::{Message, Messages};
+use crate::negotiate::MediaType;
+use crate::problem::Problem;
+use crate::rate_limit::RateLimited;
+use hyper::header::{HeaderValue, ALLOW, CONTENT_LANGUAGE, RETRY_AFTER, UPGRADE, WWW_AUTHENTICATE};
+use hyper::{Body, Method, Response, StatusCode};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// For errors that are only ever logged, and for interfaces like hyper's
+/// connectors that need an untyped error.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Everything that can make a request fail. Upstream errors name the
+/// upstream so both the response and the logs can say which one failed.
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("upstream {0} timed out")]
+    UpstreamTimeout(&'static str),
+    #[error("upstream {upstream} unreachable: {source}")]
+    UpstreamUnreachable { upstream: &'static str, source: hyper::Error },
+    #[error(transparent)]
+    UpstreamUnavailable(#[from] CircuitOpen),
+    #[error("requests to upstream {0} are throttled")]
+    UpstreamThrottled(&'static str),
+    #[error("upstream {upstream} failed: {source}")]
+    UpstreamFailed { upstream: &'static str, source: hyper::Error },
+    #[error("upstream {upstream} answered {status}")]
+    UpstreamStatus { upstream: &'static str, status: StatusCode },
+    #[error("bad response body from upstream {upstream}: {source}")]
+    UpstreamBadBody { upstream: &'static str, source: BoxError },
+    /// The upstream redirected a GET more than it may, or somewhere it
+    /// mustn't be followed to.
+    #[error("upstream {upstream} redirected to {location}: {reason}")]
+    UpstreamRedirect { upstream: &'static str, location: String, reason: &'static str },
+    /// No OAuth2 token could be fetched for requests to the upstream.
+    #[error("fetching a token for upstream {upstream} failed: {source}")]
+    UpstreamToken { upstream: &'static str, source: BoxError },
+    /// The upstream's credential couldn't be read from Vault, and there's no
+    /// earlier one to fall back to.
+    #[error("reading the credential for upstream {upstream} from vault failed: {source}")]
+    UpstreamVault { upstream: &'static str, source: BoxError },
+    #[error("request timed out")]
+    Timeout,
+    /// The request is malformed, as the message explains.
+    #[error("{0}")]
+    BadRequest(Message),
+    #[error("not found")]
+    NotFound,
+    /// The route doesn't answer the request's method, only these.
+    #[error("method not allowed")]
+    MethodNotAllowed(Vec<Method>),
+    /// The client doesn't accept any of the media types the route answers
+    /// with, which are these.
+    #[error("not acceptable")]
+    NotAcceptable(Vec<&'static str>),
+    /// A request to a WebSocket endpoint that isn't a handshake.
+    #[error("not a WebSocket handshake")]
+    UpgradeRequired,
+    #[error("missing or invalid credentials")]
+    Unauthorized,
+    /// The key set tokens are verified with couldn't be fetched, and there's
+    /// no earlier one to fall back to.
+    #[error("fetching the JWT key set failed: {0}")]
+    KeysUnavailable(Arc<AppError>),
+    /// The client's address isn't allowed by the ip filter.
+    #[error("client address not allowed")]
+    Forbidden,
+    /// Like `Unauthorized`, for the admin endpoints' Basic auth.
+    #[error("missing or invalid admin credentials")]
+    AdminUnauthorized,
+    /// The request body was larger than the limit, in bytes.
+    #[error("request body larger than {0} bytes")]
+    PayloadTooLarge(u64),
+    #[error(transparent)]
+    RateLimited(#[from] RateLimited),
+    /// The error of a request that was coalesced with this one.
+    #[error(transparent)]
+    Coalesced(Arc<AppError>),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Internal(BoxError),
+}
+
+impl AppError {
+    pub fn upstream_bad_body(upstream: &'static str, err: impl Into<BoxError>) -> AppError {
+        AppError::UpstreamBadBody { upstream, source: err.into() }
+    }
+
+    /// Upstreams that can't be reached in time are a 504, ones that answer
+    /// with something unusable a 502.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::UpstreamTimeout(_) | AppError::UpstreamUnreachable { .. } | AppError::Timeout => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            AppError::UpstreamUnavailable(_) | AppError::UpstreamThrottled(_) | AppError::KeysUnavailable(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AppError::UpstreamFailed { .. }
+            | AppError::UpstreamStatus { .. }
+            | AppError::UpstreamBadBody { .. }
+            | AppError::UpstreamRedirect { .. }
+            | AppError::UpstreamToken { .. }
+            | AppError::UpstreamVault { .. } => StatusCode::BAD_GATEWAY,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            AppError::UpgradeRequired => StatusCode::UPGRADE_REQUIRED,
+            AppError::Unauthorized | AppError::AdminUnauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Coalesced(err) => err.status(),
+            AppError::Config(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What the client is told, in the language of `messages`; unlike
+    /// `Display` it doesn't leak the details of underlying errors.
+    pub fn detail(&self, messages: &Messages) -> Option<String> {
+        let upstream = |id, english, upstream: &str| Some(messages.get(id, english, &[("upstream", upstream)]));
+        match self {
+            AppError::UpstreamTimeout(name) => upstream("upstream_timed_out", "upstream {upstream} timed out", name),
+            AppError::UpstreamUnreachable { upstream: name, .. } => {
+                upstream("upstream_unreachable", "upstream {upstream} unreachable", name)
+            }
+            AppError::UpstreamUnavailable(CircuitOpen { upstream: name }) | AppError::UpstreamThrottled(name) => {
+                upstream("upstream_unavailable", "upstream {upstream} unavailable", name)
+            }
+            AppError::UpstreamFailed { upstream: name, .. }
+            | AppError::UpstreamStatus { upstream: name, .. }
+            | AppError::UpstreamBadBody { upstream: name, .. }
+            | AppError::UpstreamRedirect { upstream: name, .. } => {
+                upstream("bad_upstream_response", "bad response from upstream {upstream}", name)
+            }
+            AppError::UpstreamToken { upstream: name, .. } | AppError::UpstreamVault { upstream: name, .. } => {
+                upstream("upstream_unauthenticated", "could not authenticate to upstream {upstream}", name)
+            }
+            AppError::Timeout => Some(messages.get("request_timed_out", "request timed out", &[])),
+            AppError::BadRequest(message) => Some(message.localize(messages)),
+            AppError::NotFound | AppError::MethodNotAllowed(_) => None,
+            AppError::NotAcceptable(types) if types.is_empty() => None,
+            AppError::NotAcceptable(types) => Some(messages.get(
+                "media_types",
+                "available media types: {types}",
+                &[("types", &types.join(", "))],
+            )),
+            AppError::UpgradeRequired => Some(messages.get("websocket_only", "this is a WebSocket endpoint", &[])),
+            AppError::Unauthorized | AppError::AdminUnauthorized => {
+                Some(messages.get("invalid_credentials", "missing or invalid credentials", &[]))
+            }
+            AppError::KeysUnavailable(_) => {
+                Some(messages.get("keys_unavailable", "credentials can't be verified right now", &[]))
+            }
+            AppError::Forbidden => Some(messages.get("address_not_allowed", "client address not allowed", &[])),
+            AppError::PayloadTooLarge(max_bytes) => Some(messages.get(
+                "body_too_large",
+                "request body larger than {max_bytes} bytes",
+                &[("max_bytes", &max_bytes.to_string())],
+            )),
+            AppError::RateLimited(_) => Some(messages.get("rate_limited", "rate limit exceeded", &[])),
+            AppError::Coalesced(err) => err.detail(messages),
+            AppError::Config(_) | AppError::Internal(_) => Some(messages.get("internal_error", "internal error", &[])),
+        }
+    }
+
+    /// The title of the problem this is answered with, in the language of
+    /// `messages`.
+    pub fn title(&self, messages: &Messages) -> String {
+        let problem = Problem::new(self.status());
+        messages.get(&format!("status_{}", problem.status), &problem.title, &[])
+    }
+
+    /// The problem+json response for the request with `request_id` that
+    /// failed with this error, its title and detail in the language of
+    /// `messages`, encoded as `media_type`, JSON or MessagePack. A title's id
+    /// is `status_` and the status code, like `status_404`.
+    pub fn to_response(&self, request_id: &str, messages: &Messages, media_type: MediaType) -> Response<Body> {
+        let mut problem = Problem::new(self.status()).instance(format!("urn:request:{}", request_id));
+        problem.title = self.title(messages);
+        problem.detail = self.detail(messages);
+        let mut res = problem.into_response(media_type);
+        if let Some(language) = messages.language().and_then(|language| HeaderValue::from_str(language).ok()) {
+            res.headers_mut().insert(CONTENT_LANGUAGE, language);
+        }
+        match self {
+            AppError::RateLimited(RateLimited { retry_after }) => {
+                // In whole seconds, rounded up so that retrying then succeeds.
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(secs));
+            }
+            AppError::MethodNotAllowed(allowed) => {
+                let allowed = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+                if let Ok(allowed) = HeaderValue::from_str(&allowed) {
+                    res.headers_mut().insert(ALLOW, allowed);
+                }
+            }
+            AppError::UpgradeRequired => {
+                res.headers_mut().insert(UPGRADE, HeaderValue::from_static("websocket"));
+            }
+            AppError::Unauthorized => {
+                res.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            AppError::AdminUnauthorized => {
+                res.headers_mut().insert(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Basic realm="admin", charset="UTF-8""#),
+                );
+            }
+            _ => {}
+        }
+        res
+    }
+}
+
+impl From<BoxError> for AppError {
+    fn from(err: BoxError) -> AppError {
+        AppError::Internal(err)
+    }
+}
+
+macro_rules! internal_errors {
+    ($($err:ty),* $(,)?) => {
+        $(
+            impl From<$err> for AppError {
+                fn from(err: $err) -> AppError {
+                    AppError::Internal(err.into())
+                }
+            }
+        )*
+    };
+}
+
+// Upstream failures are mapped explicitly where the upstream is known, so
+// these only cover the service's own failures.
+internal_errors!(
+    std::io::Error,
+    hyper::http::Error,
+    hyper::http::uri::InvalidUri,
+    hyper::header::InvalidHeaderValue,
+    hyper::Error,
+    serde_json::Error,
+    quick_xml::SeError,
+    rmp_serde::encode::Error,
+    askama::Error,
+    jsonwebtoken::errors::Error,
+    prometheus::Error,
+    tokio::task::JoinError,
+    tonic::transport::Error,
+    tracing_subscriber::reload::Error,
+    tracing_subscriber::util::TryInitError,
+);
+
+#[cfg(feature = "otlp")]
+internal_errors!(opentelemetry_otlp::ExporterBuildError);
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// How many of the latest facts are kept for clients to catch up on.
+pub const FACT_LOG_CAPACITY: usize = 64;
+
+/// The latest cat facts fetched, each with a cursor that's greater than
+/// those of the facts before it, for `/facts/next` to answer with the ones a
+/// client hasn't seen yet. Cursors start from the time the log was created,
+/// in milliseconds, so they also grow across restarts.
+pub struct FactLog {
+    entries: Mutex<Entries>,
+    /// The cursor of the latest fact, to wake up those waiting for one.
+    latest: (watch::Sender<u64>, watch::Receiver<u64>),
+}
+
+struct Entries {
+    next_cursor: u64,
+    facts: VecDeque<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub cursor: u64,
+    pub fact: String,
+    pub fetched_at: Instant,
+}
+
+impl FactLog {
+    pub fn new() -> FactLog {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+        FactLog {
+            entries: Mutex::new(Entries { next_cursor: start, facts: VecDeque::new() }),
+            latest: watch::channel(start),
+        }
+    }
+
+    /// Adds `fact`, dropping the oldest one if the log is full.
+    pub fn push(&self, fact: String) {
+        let cursor = {
+            let mut entries = self.entries.lock().unwrap();
+            let cursor = entries.next_cursor;
+            entries.next_cursor += 1;
+            if entries.facts.len() == FACT_LOG_CAPACITY {
+                entries.facts.pop_front();
+            }
+            entries.facts.push_back(Entry { cursor, fact, fetched_at: Instant::now() });
+            cursor
+        };
+        let _ = self.latest.0.broadcast(cursor);
+    }
+
+    /// The oldest fact after `since`, or the latest one without it.
+    pub fn after(&self, since: Option<u64>) -> Option<Entry> {
+        let entries = self.entries.lock().unwrap();
+        match since {
+            Some(since) => entries.facts.iter().find(|entry| entry.cursor > since).cloned(),
+            None => entries.facts.back().cloned(),
+        }
+    }
+
+    /// When the latest fact was fetched, if there is one.
+    pub fn latest_fetched_at(&self) -> Option<Instant> {
+        self.entries.lock().unwrap().facts.back().map(|entry| entry.fetched_at)
+    }
+
+    /// A receiver that's woken up for every fact pushed from now on.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.latest.1.clone()
+    }
+}
+
+impl Default for FactLog {
+    fn default() -> FactLog {
+        FactLog::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_after() {
+        let log = FactLog::new();
+        assert_eq!(log.after(None), None);
+        assert_eq!(log.after(Some(0)), None);
+        log.push("one".to_owned());
+        log.push("two".to_owned());
+        let one = log.after(Some(0)).unwrap();
+        assert_eq!(one.fact, "one");
+        let two = log.after(Some(one.cursor)).unwrap();
+        assert_eq!(two.fact, "two");
+        assert_eq!(log.after(None), Some(two.clone()));
+        assert_eq!(log.after(Some(two.cursor)), None);
+
+        for i in 0..FACT_LOG_CAPACITY {
+            log.push(i.to_string());
+        }
+        // The oldest ones are gone, so a client that fell behind skips them.
+        assert_eq!(log.after(Some(0)).unwrap().fact, "0");
+    }
+}
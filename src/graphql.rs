@@ -0,0 +1,197 @@
+//! `POST /graphql`, which resolves `todo(id)`, `catFact` and `combined` with
+//! the same upstream clients, caches and fallbacks as the routes they mirror,
+//! so clients can fetch the fields they need in one round trip.
+
+use crate::body;
+use crate::config::{GraphQl, ServerCfg};
+use crate::error::AppError;
+use crate::handlers::{cat_fact, combined, invalid_todo_id, todo_title};
+use crate::i18n::{Message, Messages};
+use crate::problem::Problem;
+use crate::state::AppState;
+use crate::Result;
+use async_graphql::extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery};
+use async_graphql::parser::types::{ExecutableDocument, Selection, SelectionSet};
+use async_graphql::Name;
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, ErrorExtensions, Object, ServerError, ServerResult, SimpleObject, Variables,
+};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub type Schema = async_graphql::Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema(cfg: &GraphQl) -> Schema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(cfg.max_depth)
+        .limit_complexity(cfg.max_complexity)
+        .extension(RootFields(cfg.max_root_fields))
+        .finish()
+}
+
+/// Rejects queries selecting more than this many root fields, counting
+/// aliases and those of fragments, before they're run.
+struct RootFields(usize);
+
+impl ExtensionFactory for RootFields {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(RootFields(self.0))
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for RootFields {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        for (_, operation) in document.operations.iter() {
+            if root_fields(&document, &operation.node.selection_set.node, &mut HashMap::new()) > self.0 {
+                return Err(ServerError::new(format!("the query selects more than {} root fields", self.0), None));
+            }
+        }
+        Ok(document)
+    }
+}
+
+/// The fields `selection` selects, counting those of its fragments, which
+/// are only counted once each and kept in `counted`.
+fn root_fields<'a>(document: &'a ExecutableDocument, selection: &'a SelectionSet, counted: &mut HashMap<&'a Name, usize>) -> usize {
+    selection.items.iter().map(|item| match &item.node {
+        Selection::Field(_) => 1,
+        Selection::InlineFragment(fragment) => root_fields(document, &fragment.node.selection_set.node, counted),
+        Selection::FragmentSpread(spread) => {
+            let name = &spread.node.fragment_name.node;
+            if let Some(count) = counted.get(name) {
+                return *count;
+            }
+            // Zero while it's being counted, so that cycles, which are
+            // invalid anyway, end.
+            counted.insert(name, 0);
+            let count = document.fragments.get(name)
+                .map_or(0, |fragment| root_fields(document, &fragment.node.selection_set.node, counted));
+            counted.insert(name, count);
+            count
+        }
+    }).fold(0, usize::saturating_add)
+}
+
+pub struct Query;
+
+#[derive(SimpleObject)]
+struct Todo {
+    id: u64,
+    title: String,
+}
+
+#[derive(SimpleObject)]
+struct CatFact {
+    fact: String,
+    /// Whether it's the last one fetched successfully rather than a new one.
+    stale: bool,
+}
+
+#[derive(SimpleObject)]
+struct Combined {
+    todo_title: String,
+    cat_fact: String,
+    /// Whether either is the last one fetched successfully.
+    stale: bool,
+    /// The upstreams that failed and were replaced by their placeholder.
+    degraded: Vec<String>,
+}
+
+#[Object]
+impl Query {
+    /// The todo with `id`, like `/todos/{id}`; null if the upstream doesn't
+    /// know it.
+    async fn todo(&self, ctx: &Context<'_>, id: u64) -> async_graphql::Result<Option<Todo>> {
+        if id == 0 {
+            return Err(error(&invalid_todo_id()));
+        }
+        let (state, cfg) = data(ctx);
+        match todo_title(state, cfg, id).await {
+            Ok(title) => Ok(Some(Todo { id, title })),
+            Err(AppError::NotFound) => Ok(None),
+            Err(err) => Err(error(&err)),
+        }
+    }
+
+    /// A cat fact, like `/double` fetches it.
+    async fn cat_fact(&self, ctx: &Context<'_>) -> async_graphql::Result<CatFact> {
+        let (state, cfg) = data(ctx);
+        let (fact, stale) = cat_fact(state, cfg).await.map_err(|err| error(&err))?;
+        Ok(CatFact { fact, stale })
+    }
+
+    /// The first todo's title and a cat fact, like `/double`.
+    async fn combined(&self, ctx: &Context<'_>) -> async_graphql::Result<Combined> {
+        let (state, cfg) = data(ctx);
+        let (composed, stale, degraded) = combined(state, cfg).await.map_err(|err| error(&err))?;
+        Ok(Combined {
+            todo_title: composed.todo,
+            cat_fact: composed.cat_fact.unwrap_or_default(),
+            stale,
+            degraded: degraded.into_iter().map(str::to_owned).collect(),
+        })
+    }
+}
+
+fn data<'a>(ctx: &Context<'a>) -> (&'a AppState, &'a ServerCfg) {
+    (ctx.data_unchecked::<Arc<AppState>>(), ctx.data_unchecked::<Arc<ServerCfg>>())
+}
+
+/// `err` with its detail as the message, and the HTTP status it'd answer
+/// with as the `status` extension.
+fn error(err: &AppError) -> async_graphql::Error {
+    let status = err.status();
+    let message = err.detail(&Messages::ENGLISH)
+        .unwrap_or_else(|| status.canonical_reason().unwrap_or_default().to_owned());
+    async_graphql::Error::new(message).extend_with(|_, extensions| extensions.set("status", status.as_u16()))
+}
+
+/// Runs the GraphQL request in the JSON body. Errors resolving fields are in
+/// the `errors` of the answer, with whatever could be resolved in `data`.
+#[utoipa::path(post, path = "/graphql", tag = "composed", request_body(content = Object, description = "The `query`, and its `variables` and `operationName` if any"), responses(
+        (status = 200, description = "The `data` and any `errors`", body = Object),
+        (status = 400, description = "The body isn't a GraphQL request", body = Problem, content_type = "application/problem+json"),
+))]
+pub async fn graphql(req: Request<Body>, state: Arc<AppState>, cfg: Arc<ServerCfg>) -> Result<Response<Body>> {
+    let body = body::read(req.into_body(), cfg.max_body_bytes).await?;
+    let request = serde_json::from_slice::<async_graphql::Request>(&body).map_err(|err| {
+        AppError::BadRequest(Message::new("invalid_graphql_request", "invalid GraphQL request: {error}").arg("error", err))
+    })?;
+    let res = state.graphql.execute(request.data(state.clone()).data(cfg)).await;
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&res)?.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let schema = schema(&GraphQl { max_depth: 2, max_complexity: 4, max_root_fields: 2 });
+        let errors = |query: &str| {
+            let res = futures::executor::block_on(schema.execute(query));
+            res.errors.into_iter().map(|err| err.message).collect::<Vec<_>>()
+        };
+        assert!(errors("{ a: __typename b: __typename }").is_empty());
+        let too_many = vec!["the query selects more than 2 root fields".to_owned()];
+        assert_eq!(errors("{ a: __typename b: __typename c: __typename }"), too_many);
+        assert_eq!(errors("{ a: __typename ...F } fragment F on Query { b: __typename c: __typename }"), too_many);
+        assert_eq!(errors("{ ... on Query { a: __typename ... on Query { b: __typename c: __typename } } }"), too_many);
+        // Cycles end, and are rejected as invalid.
+        assert!(!errors("{ ...F } fragment F on Query { a: __typename ...F }").is_empty());
+        assert_eq!(errors("{ __schema { types { name } } }"), vec!["Query is nested too deep.".to_owned()]);
+        assert_eq!(errors("{ combined { todoTitle catFact stale degraded } }"), vec!["Query is too complex.".to_owned()]);
+    }
+}
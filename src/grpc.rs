@@ -0,0 +1,199 @@
+//! The gRPC service in `proto/example.proto`, served on `server.grpc_port`
+//! alongside the HTTP API. It goes through the same upstream clients, caches
+//! and fallbacks, and takes the config reloaded at the time of each call.
+//! Each call gets the ip filter, rate limit and authentication of the HTTP
+//! route it answers like, and is logged and counted like HTTP requests.
+
+use crate::access_log::{self, Entry};
+use crate::auth;
+use crate::config::ServerCfg;
+use crate::error::AppError;
+use crate::handlers::{cat_fact, combined, invalid_todo_id, todo_title};
+use crate::i18n::Messages;
+use crate::rate_limit;
+use crate::state::AppState;
+use crate::Result;
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use hyper::{HeaderMap, Method, StatusCode, Version};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::timeout;
+use tonic::{Code, Request, Response, Status};
+use tracing::instrument;
+
+pub mod proto {
+    tonic::include_proto!("mockito_example.v1");
+}
+
+use proto::example_server::{Example, ExampleServer};
+use proto::{CatFact, Combined, GetCatFactRequest, GetCombinedRequest, GetTodoRequest, Todo};
+
+/// The HTTP routes whose settings the calls get: `GetTodo` answers like
+/// `/todos/{id}`, the others like `/double`.
+const TODO_ROUTE: &str = "/todos/{id}";
+const DOUBLE_ROUTE: &str = "/double";
+
+/// The paths of the calls, which label them in the access log and the
+/// request metrics like routes do HTTP requests.
+const GET_TODO: &str = "/mockito_example.v1.Example/GetTodo";
+const GET_CAT_FACT: &str = "/mockito_example.v1.Example/GetCatFact";
+const GET_COMBINED: &str = "/mockito_example.v1.Example/GetCombined";
+
+struct Service {
+    state: Arc<AppState>,
+    cfg: Arc<ArcSwap<ServerCfg>>,
+}
+
+impl Service {
+    /// Answers `req`, a call to `path` answering like `route`, with what
+    /// `answer` makes of its message once it's admitted, within the request
+    /// timeout. Like an HTTP request, the call is logged in the access log,
+    /// with the status of the HTTP response it answers like, and counted in
+    /// the request metrics.
+    async fn call<T, R, F>(
+        &self,
+        req: Request<T>,
+        path: &'static str,
+        route: &'static str,
+        answer: impl FnOnce(T, Arc<ServerCfg>) -> F,
+    ) -> std::result::Result<Response<R>, Status>
+    where
+        F: Future<Output = Result<R>>,
+    {
+        let received = Utc::now();
+        let start = Instant::now();
+        let cfg = self.cfg.load_full();
+        let headers = req.metadata().clone().into_headers();
+        let peer = req.remote_addr().map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
+        let client_ip = rate_limit::client_ip(peer, &headers, &cfg.rate_limit.trusted_proxies);
+        let res = async {
+            self.admit(&headers, client_ip, &cfg, route).await?;
+            match timeout(cfg.request_timeout, answer(req.into_inner(), cfg.clone())).await {
+                Ok(res) => res,
+                Err(_) => Err(AppError::Timeout),
+            }
+        }.await;
+        let latency = start.elapsed();
+
+        let code = res.as_ref().err().map_or(StatusCode::OK, AppError::status);
+        access_log::log(cfg.access_log, &Entry {
+            time: received,
+            client_ip,
+            method: &Method::POST,
+            path,
+            version: Version::HTTP_2,
+            status: Some(code),
+            bytes: None,
+            duration: latency,
+        });
+        let metrics = &self.state.metrics;
+        metrics.requests.with_label_values(&[path, Method::POST.as_str(), code.as_str()]).inc();
+        let met_slo = !code.is_server_error() && latency <= cfg.slo.latency(path);
+        metrics.observe_request(path, latency, met_slo);
+        res.map(Response::new).map_err(|err| status(&err))
+    }
+
+    /// Checks a call like an HTTP request to `route` is checked before it's
+    /// handled: against the ip filter, the rate limit, and the API key or
+    /// JWT the route needs, which go in the metadata like in the headers.
+    async fn admit(&self, headers: &HeaderMap, client_ip: IpAddr, cfg: &ServerCfg, route: &'static str) -> Result<()> {
+        if !cfg.ip_filter.allows(client_ip) {
+            self.state.metrics.ip_filtered.with_label_values(&[route]).inc();
+            return Err(AppError::Forbidden);
+        }
+        if let Some(limit) = cfg.rate_limit.limit(route) {
+            self.state.rate_limiter.check(client_ip, route, limit)?;
+        }
+        auth::authenticate(headers, route, cfg, &self.state).await?;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Example for Service {
+    #[instrument(skip_all)]
+    async fn get_todo(&self, req: Request<GetTodoRequest>) -> std::result::Result<Response<Todo>, Status> {
+        self.call(req, GET_TODO, TODO_ROUTE, |req, cfg| async move {
+            if req.id == 0 {
+                return Err(invalid_todo_id());
+            }
+            let title = todo_title(&self.state, &cfg, req.id).await?;
+            Ok(Todo { id: req.id, title })
+        }).await
+    }
+
+    #[instrument(skip_all)]
+    async fn get_cat_fact(&self, req: Request<GetCatFactRequest>) -> std::result::Result<Response<CatFact>, Status> {
+        self.call(req, GET_CAT_FACT, DOUBLE_ROUTE, |_, cfg| async move {
+            let (fact, stale) = cat_fact(&self.state, &cfg).await?;
+            Ok(CatFact { fact, stale })
+        }).await
+    }
+
+    #[instrument(skip_all)]
+    async fn get_combined(&self, req: Request<GetCombinedRequest>) -> std::result::Result<Response<Combined>, Status> {
+        self.call(req, GET_COMBINED, DOUBLE_ROUTE, |_, cfg| async move {
+            let (composed, stale, degraded) = combined(&self.state, &cfg).await?;
+            Ok(Combined {
+                todo_title: composed.todo,
+                cat_fact: composed.cat_fact.unwrap_or_default(),
+                stale,
+                degraded: degraded.into_iter().map(str::to_owned).collect(),
+            })
+        }).await
+    }
+}
+
+/// The gRPC status of the HTTP status `err` would answer with, and the same
+/// detail.
+fn status(err: &AppError) -> Status {
+    let code = match err.status() {
+        StatusCode::BAD_REQUEST => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::METHOD_NOT_ALLOWED => Code::Unimplemented,
+        StatusCode::NOT_ACCEPTABLE => Code::InvalidArgument,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    };
+    let message = err.detail(&Messages::ENGLISH)
+        .unwrap_or_else(|| err.status().canonical_reason().unwrap_or_default().to_owned());
+    Status::new(code, message)
+}
+
+/// Serves the gRPC service on `listener` until `shutdown` resolves.
+pub async fn serve(
+    listener: std::net::TcpListener,
+    state: Arc<AppState>,
+    cfg: Arc<ArcSwap<ServerCfg>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let mut listener = tokio::net::TcpListener::from_std(listener)?;
+    tonic::transport::Server::builder()
+        .add_service(ExampleServer::new(Service { state, cfg }))
+        .serve_with_incoming_shutdown(listener.incoming(), shutdown)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let timed_out = status(&AppError::UpstreamTimeout("cats"));
+        assert_eq!((timed_out.code(), timed_out.message()), (Code::DeadlineExceeded, "upstream cats timed out"));
+        let not_found = status(&AppError::NotFound);
+        assert_eq!((not_found.code(), not_found.message()), (Code::NotFound, "Not Found"));
+        assert_eq!(status(&AppError::MethodNotAllowed(vec![Method::GET])).code(), Code::Unimplemented);
+        assert_eq!(status(&AppError::NotAcceptable(vec![])).code(), Code::InvalidArgument);
+        assert_eq!(status(&AppError::PayloadTooLarge(1024)).code(), Code::ResourceExhausted);
+    }
+}
@@ -0,0 +1,198 @@
+//! Message catalogs, loaded from `i18n.catalogs` at startup, that localize
+//! the static parts of responses and error messages for the language the
+//! `Accept-Language` header asks for.
+//!
+//! Every `<language>.toml` in the directory is a catalog of messages by id,
+//! like `todo = "Aufgabe"` in `de.toml`. Messages can have placeholders like
+//! `{upstream}`. Ids a catalog lacks, and languages without a catalog, get
+//! the built-in English messages.
+
+use crate::error::BoxError;
+use crate::negotiate::parse_quality;
+use hyper::header::{HeaderMap, ACCEPT_LANGUAGE};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Catalogs by lowercase language tag, like `de` or `pt-br`.
+#[derive(Debug, Default)]
+pub struct Catalogs(HashMap<String, HashMap<String, String>>);
+
+/// The messages of a request's language.
+#[derive(Clone, Copy, Debug)]
+pub struct Messages<'a> {
+    language: Option<&'a str>,
+    catalog: Option<&'a HashMap<String, String>>,
+}
+
+impl Catalogs {
+    pub fn load(dir: &Path) -> Result<Catalogs, BoxError> {
+        let mut catalogs = HashMap::new();
+        let entries = fs::read_dir(dir).map_err(|err| format!("reading catalogs {}: {}", dir.display(), err))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("toml")) {
+                continue;
+            }
+            let language = match path.file_stem().and_then(OsStr::to_str) {
+                Some(language) => language.to_ascii_lowercase(),
+                None => continue,
+            };
+            let catalog = fs::read_to_string(&path).map_err(BoxError::from).and_then(|catalog| {
+                toml::from_str::<HashMap<String, String>>(&catalog).map_err(BoxError::from)
+            });
+            let catalog = catalog.map_err(|err| format!("loading catalog {}: {}", path.display(), err))?;
+            catalogs.insert(language, catalog);
+        }
+        Ok(Catalogs(catalogs))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The messages for the language `headers` prefer most of those there
+    /// are catalogs for, as per RFC 4647's lookup: `de-CH` falls back to
+    /// `de`, and `*` or nothing to English.
+    pub fn messages(&self, headers: &HeaderMap) -> Messages<'_> {
+        let mut ranges = headers.get_all(ACCEPT_LANGUAGE).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(parse_range)
+            .filter(|(_, quality)| *quality > 0)
+            .collect::<Vec<_>>();
+        // Stable, so ranges of the same quality keep their order.
+        ranges.sort_by_key(|(_, quality)| std::cmp::Reverse(*quality));
+        for (range, _) in ranges {
+            if range == "*" {
+                break;
+            }
+            let mut tag = range.as_str();
+            loop {
+                if let Some((language, catalog)) = self.0.get_key_value(tag) {
+                    return Messages { language: Some(language), catalog: Some(catalog) };
+                }
+                match tag.rfind('-') {
+                    Some(end) => tag = &tag[..end],
+                    None => break,
+                }
+            }
+        }
+        Messages::ENGLISH
+    }
+}
+
+impl Messages<'_> {
+    pub const ENGLISH: Messages<'static> = Messages { language: None, catalog: None };
+
+    /// The language of the catalog, `None` for the built-in messages.
+    pub fn language(&self) -> Option<&str> {
+        self.language
+    }
+
+    /// The message with `id`, `english` if the catalog lacks it, with the
+    /// placeholders filled in from `args`.
+    pub fn get(&self, id: &str, english: &str, args: &[(&str, &str)]) -> String {
+        let mut message = self.catalog.and_then(|catalog| catalog.get(id)).map_or(english, String::as_str).to_owned();
+        for (name, value) in args {
+            message = message.replace(&format!("{{{}}}", name), value);
+        }
+        message
+    }
+}
+
+/// A message that's localized only once it's known who it's for: its id,
+/// the built-in English message, and its placeholders' values. `Display`
+/// shows it in English.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    id: &'static str,
+    english: &'static str,
+    args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(id: &'static str, english: &'static str) -> Message {
+        Message { id, english, args: Vec::new() }
+    }
+
+    /// Fills the placeholder `{name}` with `value`.
+    pub fn arg(mut self, name: &'static str, value: impl fmt::Display) -> Message {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    pub fn localize(&self, messages: &Messages) -> String {
+        let args = self.args.iter().map(|(name, value)| (*name, value.as_str())).collect::<Vec<_>>();
+        messages.get(self.id, self.english, &args)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.localize(&Messages::ENGLISH))
+    }
+}
+
+/// A language range and its quality; `None` for invalid ones, which are
+/// ignored.
+fn parse_range(range: &str) -> Option<(String, u16)> {
+    let mut params = range.split(';');
+    let tag = params.next()?.trim();
+    if tag.is_empty() || !tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'*') {
+        return None;
+    }
+    let mut quality = 1000;
+    for param in params {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("q") {
+                quality = parse_quality(value.trim())?;
+            }
+        }
+    }
+    Some((tag.to_ascii_lowercase(), quality))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalogs() -> Catalogs {
+        let de = [("todo", "Aufgabe"), ("timed_out", "{upstream} antwortete nicht")];
+        let pt_br = [("todo", "Tarefa")];
+        let to_catalog = |messages: &[(&str, &str)]| {
+            messages.iter().map(|(id, message)| (id.to_string(), message.to_string())).collect()
+        };
+        Catalogs([("de".to_owned(), to_catalog(&de)), ("pt-br".to_owned(), to_catalog(&pt_br))].iter().cloned().collect())
+    }
+
+    fn language(accept_language: &str) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_LANGUAGE, accept_language.parse().unwrap());
+        catalogs().messages(&headers).language().map(str::to_owned)
+    }
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(catalogs().messages(&HeaderMap::new()).language(), None);
+        assert_eq!(language("de").as_deref(), Some("de"));
+        assert_eq!(language("de-CH-1996").as_deref(), Some("de"));
+        assert_eq!(language("pt").as_deref(), None);
+        assert_eq!(language("PT-BR").as_deref(), Some("pt-br"));
+        assert_eq!(language("fr, de;q=0.5").as_deref(), Some("de"));
+        assert_eq!(language("de;q=0.5, pt-BR").as_deref(), Some("pt-br"));
+        assert_eq!(language("en, *;q=0.5, de;q=0.1").as_deref(), None);
+        assert_eq!(language("de;q=0, de-AT;q=0").as_deref(), None);
+
+        let catalogs = catalogs();
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_LANGUAGE, "de".parse().unwrap());
+        let messages = catalogs.messages(&headers);
+        assert_eq!(messages.get("todo", "Todo", &[]), "Aufgabe");
+        assert_eq!(messages.get("timed_out", "upstream {upstream} timed out", &[("upstream", "cats")]), "cats antwortete nicht");
+        assert_eq!(messages.get("cat_fact", "Cat Fact", &[]), "Cat Fact");
+        assert_eq!(Messages::ENGLISH.get("timed_out", "upstream {upstream} timed out", &[("upstream", "cats")]), "upstream cats timed out");
+    }
+}
@@ -0,0 +1,327 @@
+use crate::client::HttpClient;
+use crate::config::JwtCfg;
+use crate::error::AppError;
+use crate::singleflight::Singleflight;
+use crate::Result;
+use hyper::body::to_bytes;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+/// Tokens signed with a key that isn't in the cached key set only make it be
+/// fetched again if it's at least this old, so they can't be used to flood
+/// the issuer. Nor is it fetched again this soon after a fetch failed.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A key set and when it was fetched.
+type Fetched = (Instant, Arc<JwkSet>);
+
+/// A failed fetch of the key set and when it failed.
+type Failed = (Instant, Arc<AppError>);
+
+/// The claims of a verified token, available to handlers as an extension of
+/// the request.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Claims {
+    /// Who the token was issued to.
+    pub sub: Option<String>,
+    /// Everything else, including the registered claims that were verified
+    /// like `iss` and `exp`.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// Verifies JWTs signed with either the configured HS256 secret or, for
+/// RS256, one of the keys published at the configured JWKS url. The key set
+/// is cached across config reloads.
+#[derive(Default)]
+pub struct JwtVerifier {
+    jwks: Mutex<Option<Fetched>>,
+    failed: Mutex<Option<Failed>>,
+    /// Fetches of the key set, which concurrent requests share.
+    refreshes: Singleflight<String, std::result::Result<Fetched, Arc<AppError>>>,
+}
+
+impl JwtVerifier {
+    pub fn new() -> JwtVerifier {
+        JwtVerifier::default()
+    }
+
+    pub async fn verify(&self, token: &str, cfg: &JwtCfg, client: &HttpClient) -> Result<Claims> {
+        let header = decode_header(token).map_err(rejected)?;
+        // Only the algorithms configured, so that a public key can't be
+        // passed off as an HS256 secret.
+        let key = match (header.alg, &cfg.hs256_secret, &cfg.jwks_url) {
+            (Algorithm::HS256, Some(secret), _) => DecodingKey::from_secret(secret.expose().as_bytes()),
+            (Algorithm::RS256, _, Some(url)) => {
+                let kid = header.kid.ok_or_else(|| rejected("token has no key id"))?;
+                self.key(url, &kid, cfg.jwks_cache, client).await?
+            }
+            (alg, _, _) => return Err(rejected(format!("{:?} isn't accepted", alg))),
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = cfg.leeway.as_secs();
+        if let Some(issuer) = &cfg.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &cfg.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let data = decode::<Claims>(token, &key, &validation).map_err(rejected)?;
+        Ok(data.claims)
+    }
+
+    /// The key `kid` from the key set at `url`, fetched again once it's older
+    /// than `cache_for` or doesn't have the key. If fetching it fails the
+    /// last one fetched is used, and it isn't fetched again for
+    /// `JWKS_MIN_REFRESH`.
+    async fn key(&self, url: &str, kid: &str, cache_for: Duration, client: &HttpClient) -> Result<DecodingKey> {
+        let cached = self.jwks.lock().unwrap().clone();
+        let (fetched_at, jwks) = match cached {
+            Some((fetched_at, jwks)) if fetched_at.elapsed() < cache_for => (fetched_at, jwks),
+            _ => self.refresh(url, client).await?,
+        };
+        let jwks = match jwks.find(kid) {
+            Some(_) => jwks,
+            None if fetched_at.elapsed() >= JWKS_MIN_REFRESH => self.refresh(url, client).await?.1,
+            None => jwks,
+        };
+        let jwk = jwks.find(kid).ok_or_else(|| rejected(format!("unknown key id {:?}", kid)))?;
+        Ok(DecodingKey::from_jwk(jwk)?)
+    }
+
+    async fn refresh(&self, url: &str, client: &HttpClient) -> Result<Fetched> {
+        let failed = self.failed.lock().unwrap().clone();
+        let fetched = match failed {
+            Some((failed_at, err)) if failed_at.elapsed() < JWKS_MIN_REFRESH => Err(err),
+            _ => self.refreshes.run(url.to_owned(), self.fetch(url, client)).await.0,
+        };
+        match fetched {
+            Ok(fetched) => Ok(fetched),
+            Err(err) => match self.jwks.lock().unwrap().clone() {
+                Some(stale) => {
+                    warn!(url, %err, "fetching key set failed, using the last one fetched");
+                    Ok(stale)
+                }
+                None => Err(AppError::KeysUnavailable(err)),
+            },
+        }
+    }
+
+    async fn fetch(&self, url: &str, client: &HttpClient) -> std::result::Result<Fetched, Arc<AppError>> {
+        let fetch = async {
+            let res = client.get(url.parse()?).await?;
+            if !res.status().is_success() {
+                return Err(AppError::Internal(format!("fetching {} failed with {}", url, res.status()).into()));
+            }
+            let body = to_bytes(res.into_body()).await?;
+            Ok(serde_json::from_slice::<JwkSet>(&body)?)
+        };
+        let jwks = timeout(JWKS_TIMEOUT, fetch).await
+            .map_err(|_| AppError::Internal(format!("fetching {} timed out", url).into()))
+            .and_then(|fetched| fetched)
+            .map_err(Arc::new);
+        let jwks = match jwks {
+            Ok(jwks) => jwks,
+            Err(err) => {
+                *self.failed.lock().unwrap() = Some((Instant::now(), err.clone()));
+                return Err(err);
+            }
+        };
+        info!(url, keys = jwks.keys.len(), "fetched key set");
+        let fetched = (Instant::now(), Arc::new(jwks));
+        *self.jwks.lock().unwrap() = Some(fetched.clone());
+        *self.failed.lock().unwrap() = None;
+        Ok(fetched)
+    }
+}
+
+/// Logs why a token was rejected; the client is only told it was.
+fn rejected(reason: impl fmt::Display) -> AppError {
+    debug!(%reason, "rejected token");
+    AppError::Unauthorized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::init_client;
+    use crate::config::ClientCfg;
+    use crate::metrics::Metrics;
+    use crate::secret::Secret;
+    use httptest::{mappers::*, responders::*, Expectation};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::runtime::Runtime;
+
+    const RSA_KEY: &str = include_str!("../testdata/jwt_rsa.pem");
+
+    const JWKS: &str = include_str!("../testdata/jwks.json");
+
+    fn cfg() -> JwtCfg {
+        JwtCfg {
+            issuer: Some("https://issuer.example".to_owned()),
+            audience: Some("rust-mockito-example".to_owned()),
+            hs256_secret: Some(Secret::from("s3cret")),
+            jwks_url: None,
+            jwks_cache: Duration::from_secs(300),
+            leeway: Duration::from_secs(0),
+            routes: Vec::new(),
+        }
+    }
+
+    fn token_claims(exp_in: i64) -> serde_json::Value {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        json!({
+            "sub": "alice",
+            "iss": "https://issuer.example",
+            "aud": "rust-mockito-example",
+            "exp": now + exp_in,
+        })
+    }
+
+    fn hs256(claims: &serde_json::Value, secret: &str) -> String {
+        encode(&Header::new(Algorithm::HS256), claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_hs256() {
+        let mut rt = Runtime::new().unwrap();
+        let client = init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        let verifier = JwtVerifier::new();
+        let cfg = cfg();
+
+        let claims = rt.block_on(verifier.verify(&hs256(&token_claims(60), "s3cret"), &cfg, &client)).unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.other["iss"], "https://issuer.example");
+
+        for token in [hs256(&token_claims(-60), "s3cret"), hs256(&token_claims(60), "wrong"), "garbage".to_owned()] {
+            let res = rt.block_on(verifier.verify(&token, &cfg, &client));
+            assert!(matches!(res, Err(AppError::Unauthorized)));
+        }
+        let mut wrong_issuer = token_claims(60);
+        wrong_issuer["iss"] = json!("https://other.example");
+        let res = rt.block_on(verifier.verify(&hs256(&wrong_issuer, "s3cret"), &cfg, &client));
+        assert!(matches!(res, Err(AppError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_rs256_jwks() {
+        let server = httptest::Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/.well-known/jwks.json"))
+            .times(1)
+            .respond_with(status_code(200).body(JWKS)));
+
+        let mut rt = Runtime::new().unwrap();
+        let client = init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        let verifier = JwtVerifier::new();
+        let cfg = JwtCfg {
+            hs256_secret: None,
+            jwks_url: Some(server.url_str("/.well-known/jwks.json")),
+            ..cfg()
+        };
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test".to_owned());
+        let key = EncodingKey::from_rsa_pem(RSA_KEY.as_bytes()).unwrap();
+
+        // The key set is fetched once and then cached.
+        for _ in 0..2 {
+            let token = encode(&header, &token_claims(60), &key).unwrap();
+            let claims = rt.block_on(verifier.verify(&token, &cfg, &client)).unwrap();
+            assert_eq!(claims.sub.as_deref(), Some("alice"));
+        }
+
+        // HS256 isn't configured, so a token signed with it is rejected.
+        let res = rt.block_on(verifier.verify(&hs256(&token_claims(60), "s3cret"), &cfg, &client));
+        assert!(matches!(res, Err(AppError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_jwks_refresh() {
+        let mut server = httptest::Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/.well-known/jwks.json"))
+            .times(1)
+            .respond_with(from_fn(|_| {
+                // Slow enough for the requests to overlap.
+                std::thread::sleep(Duration::from_millis(50));
+                status_code(200).body(JWKS)
+            })));
+
+        let mut rt = Runtime::new().unwrap();
+        let client = init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        let verifier = JwtVerifier::new();
+        let cfg = JwtCfg {
+            hs256_secret: None,
+            jwks_url: Some(server.url_str("/.well-known/jwks.json")),
+            jwks_cache: Duration::from_secs(0),
+            ..cfg()
+        };
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test".to_owned());
+        let key = EncodingKey::from_rsa_pem(RSA_KEY.as_bytes()).unwrap();
+        let token = encode(&header, &token_claims(60), &key).unwrap();
+
+        // Concurrent requests share one fetch.
+        let verifies = (0..5).map(|_| verifier.verify(&token, &cfg, &client));
+        for claims in rt.block_on(futures::future::join_all(verifies)) {
+            assert_eq!(claims.unwrap().sub.as_deref(), Some("alice"));
+        }
+        server.verify_and_clear();
+
+        // The key set has expired, but the one fetched before is used while
+        // the issuer fails.
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/.well-known/jwks.json"))
+            .times(2)
+            .respond_with(status_code(500)));
+        let claims = rt.block_on(verifier.verify(&token, &cfg, &client)).unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+
+        // Without one, tokens can't be verified at all.
+        let res = rt.block_on(JwtVerifier::new().verify(&token, &cfg, &client));
+        let err = res.unwrap_err();
+        assert!(matches!(err, AppError::KeysUnavailable(_)), "{}", err);
+        assert_eq!(err.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_jwks_failure_backoff() {
+        let server = httptest::Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/.well-known/jwks.json"))
+            .times(1)
+            .respond_with(status_code(500)));
+
+        let mut rt = Runtime::new().unwrap();
+        let client = init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        let verifier = JwtVerifier::new();
+        let cfg = JwtCfg {
+            hs256_secret: None,
+            jwks_url: Some(server.url_str("/.well-known/jwks.json")),
+            ..cfg()
+        };
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test".to_owned());
+        let key = EncodingKey::from_rsa_pem(RSA_KEY.as_bytes()).unwrap();
+        let token = encode(&header, &token_claims(60), &key).unwrap();
+
+        // Only the first request fetches the key set; the next one fails
+        // without asking the issuer again.
+        for _ in 0..2 {
+            let res = rt.block_on(verifier.verify(&token, &cfg, &client));
+            assert!(matches!(res, Err(AppError::KeysUnavailable(_))));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+pub mod access_log;
+pub mod auth;
+pub mod body;
+pub mod breaker;
+pub mod cache;
+pub mod client;
+pub mod compression;
+pub mod config;
+pub mod cors;
+pub mod deadline;
+pub mod dns;
+pub mod egress;
+pub mod error;
+pub mod fact_log;
+pub mod graphql;
+pub mod grpc;
+pub mod handlers;
+pub mod i18n;
+pub mod jwt;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod negotiate;
+pub mod oauth;
+pub mod openapi;
+pub mod plugin;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod problem;
+pub mod propagation;
+pub mod proxy;
+pub mod rate_limit;
+#[cfg(feature = "redis-cache")]
+pub mod redis_cache;
+pub mod router;
+pub mod runtime;
+pub mod secret;
+pub mod server;
+pub mod singleflight;
+pub mod state;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tls;
+pub mod transform;
+pub mod vault;
+pub mod webhooks;
+pub mod ws;
+
+pub use config::{Config, ServerCfg};
+pub use error::AppError;
+pub use server::{run_server, spawn_server, start_server, ServerHandle};
+
+pub type Result<T> = std::result::Result<T, AppError>;
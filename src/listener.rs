@@ -0,0 +1,441 @@
+use crate::config::ServerCfg;
+#[cfg(unix)]
+use crate::config::UnixSocketCfg;
+use crate::error::AppError;
+use crate::metrics::Metrics;
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use hyper::server::accept::Accept;
+use prometheus::{IntCounter, IntGauge};
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, delay_for, Delay};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+/// How long to stop accepting after an error, which is usually running out
+/// of file descriptors, rather than spinning.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Clients that don't finish the TLS handshake within this long are
+/// disconnected, so that they can't hold on to a connection slot.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections over a Unix socket come from a proxy on the same host, so
+/// they're seen as coming from the loopback address, e.g. by
+/// `trusted_proxies`.
+const UNIX_REMOTE_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Accepts connections up to `max_connections` at a time. Connections beyond
+/// that wait up to `connection_queue_timeout` for one to close, and are
+/// closed if none does or `max_queued_connections` are waiting already.
+/// With TLS, connections are only handed out once their handshake is done.
+pub struct Listener {
+    listener: Incoming,
+    /// `None` doesn't limit connections.
+    limit: Option<Arc<Semaphore>>,
+    max_queued: usize,
+    queue_timeout: Duration,
+    /// The queued connections, which resolve to `None` once they time out.
+    queued: FuturesUnordered<BoxFuture<'static, Option<(Stream, SocketAddr, OwnedSemaphorePermit)>>>,
+    tls: Option<TlsAcceptor>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    /// The connections in their TLS handshake, which resolve to `None` if it
+    /// fails.
+    handshakes: FuturesUnordered<BoxFuture<'static, Option<Conn>>>,
+    backoff: Option<Pin<Box<Delay>>>,
+    open: IntGauge,
+    queued_gauge: IntGauge,
+    rejected: IntCounter,
+    handshake_failures: IntCounter,
+}
+
+impl Listener {
+    /// Must be called from within a tokio runtime.
+    pub fn new(listener: Bound, cfg: &ServerCfg, metrics: &Metrics) -> io::Result<Listener> {
+        Ok(Listener {
+            listener: listener.incoming()?,
+            limit: cfg.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            max_queued: cfg.max_queued_connections,
+            queue_timeout: cfg.connection_queue_timeout,
+            queued: FuturesUnordered::new(),
+            tls: None,
+            nodelay: cfg.tcp_nodelay,
+            keepalive: cfg.tcp_keepalive,
+            handshakes: FuturesUnordered::new(),
+            backoff: None,
+            open: metrics.connections_open.clone(),
+            queued_gauge: metrics.connections_queued.clone(),
+            rejected: metrics.connections_rejected.clone(),
+            handshake_failures: metrics.tls_handshake_failures.clone(),
+        })
+    }
+
+    /// A listener serving TLS on `listener` that shares this one's connection
+    /// limit. Must be called from within a tokio runtime.
+    pub fn with_tls(&self, listener: std::net::TcpListener, acceptor: TlsAcceptor) -> io::Result<Listener> {
+        Ok(self.sibling(Bound::Tcp(listener).incoming()?, Some(acceptor)))
+    }
+
+    /// A listener serving on `listener` too, sharing this one's connection
+    /// limit. Must be called from within a tokio runtime.
+    pub fn alongside(&self, listener: Bound) -> io::Result<Listener> {
+        Ok(self.sibling(listener.incoming()?, None))
+    }
+
+    fn sibling(&self, listener: Incoming, tls: Option<TlsAcceptor>) -> Listener {
+        Listener {
+            listener,
+            limit: self.limit.clone(),
+            max_queued: self.max_queued,
+            queue_timeout: self.queue_timeout,
+            queued: FuturesUnordered::new(),
+            tls,
+            nodelay: self.nodelay,
+            keepalive: self.keepalive,
+            handshakes: FuturesUnordered::new(),
+            backoff: None,
+            open: self.open.clone(),
+            queued_gauge: self.queued_gauge.clone(),
+            rejected: self.rejected.clone(),
+            handshake_failures: self.handshake_failures.clone(),
+        }
+    }
+
+    /// Returns the connection if it may be served right away, or else queues
+    /// or closes it.
+    fn admit(&mut self, stream: Stream, remote_addr: SocketAddr) -> Option<Conn> {
+        if let Stream::Plain(stream) = &stream {
+            if let Err(err) = self.configure(stream) {
+                debug!(%remote_addr, %err, "failed to set socket options");
+            }
+        }
+        let limit = match &self.limit {
+            Some(limit) => limit.clone(),
+            None => return self.start(stream, remote_addr, None),
+        };
+        if let Ok(permit) = limit.clone().try_acquire_owned() {
+            return self.start(stream, remote_addr, Some(permit));
+        }
+        if self.queued.len() >= self.max_queued {
+            debug!(%remote_addr, "rejected connection");
+            self.rejected.inc();
+            return None;
+        }
+        self.queued_gauge.inc();
+        let timeout = self.queue_timeout;
+        self.queued.push(Box::pin(async move {
+            let permit = time::timeout(timeout, limit.acquire_owned()).await.ok()?;
+            Some((stream, remote_addr, permit))
+        }));
+        None
+    }
+
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        stream.set_keepalive(self.keepalive)
+    }
+
+    /// Returns the admitted connection if it may be served right away, or
+    /// else starts its TLS handshake.
+    fn start(&mut self, stream: Stream, remote_addr: SocketAddr, permit: Option<OwnedSemaphorePermit>) -> Option<Conn> {
+        let (acceptor, stream) = match (&self.tls, stream) {
+            (Some(acceptor), Stream::Plain(stream)) => (acceptor.clone(), stream),
+            (_, stream) => return Some(Conn::new(stream, remote_addr, permit, &self.open)),
+        };
+        let open = self.open.clone();
+        let failures = self.handshake_failures.clone();
+        self.handshakes.push(Box::pin(async move {
+            match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => Some(Conn::new(Stream::Tls(Box::new(stream)), remote_addr, permit, &open)),
+                Ok(Err(err)) => {
+                    debug!(%remote_addr, %err, "TLS handshake failed");
+                    failures.inc();
+                    None
+                }
+                Err(_) => {
+                    debug!(%remote_addr, "TLS handshake timed out");
+                    failures.inc();
+                    None
+                }
+            }
+        }));
+        None
+    }
+}
+
+impl Accept for Listener {
+    type Conn = Conn;
+    type Error = AppError;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Conn, AppError>>> {
+        let this = self.get_mut();
+        'accept: loop {
+            if let Some(backoff) = &mut this.backoff {
+                futures::ready!(backoff.as_mut().poll(cx));
+                this.backoff = None;
+            }
+            while let Poll::Ready(Some(handshaken)) = this.handshakes.poll_next_unpin(cx) {
+                if let Some(conn) = handshaken {
+                    return Poll::Ready(Some(Ok(conn)));
+                }
+            }
+            while let Poll::Ready(Some(admitted)) = this.queued.poll_next_unpin(cx) {
+                this.queued_gauge.dec();
+                match admitted {
+                    Some((stream, remote_addr, permit)) => match this.start(stream, remote_addr, Some(permit)) {
+                        Some(conn) => return Poll::Ready(Some(Ok(conn))),
+                        // Its handshake needs polling to make progress.
+                        None => continue 'accept,
+                    },
+                    None => {
+                        debug!("rejected connection after queueing");
+                        this.rejected.inc();
+                    }
+                }
+            }
+            let (stream, remote_addr) = match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok(accepted)) => accepted,
+                Poll::Ready(Err(err)) => {
+                    warn!(%err, "failed to accept connection");
+                    this.backoff = Some(Box::pin(delay_for(ACCEPT_BACKOFF)));
+                    continue;
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(conn) = this.admit(stream, remote_addr) {
+                return Poll::Ready(Some(Ok(conn)));
+            }
+        }
+    }
+}
+
+/// A socket bound before it's served on, by the server or by systemd.
+pub enum Bound {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Bound {
+    /// Must be called from within a tokio runtime.
+    fn incoming(self) -> io::Result<Incoming> {
+        match self {
+            Bound::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(Incoming::Tcp(TcpListener::from_std(listener)?))
+            }
+            #[cfg(unix)]
+            Bound::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(Incoming::Unix(UnixListener::from_std(listener)?))
+            }
+        }
+    }
+}
+
+/// Where connections are accepted from.
+enum Incoming {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Incoming {
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<(Stream, SocketAddr)>> {
+        match self {
+            Incoming::Tcp(listener) => listener.poll_accept(cx).map_ok(|(stream, addr)| (Stream::Plain(stream), addr)),
+            #[cfg(unix)]
+            Incoming::Unix(listener) => listener.poll_accept(cx).map_ok(|(stream, _)| (Stream::Unix(stream), UNIX_REMOTE_ADDR)),
+        }
+    }
+}
+
+/// Binds `cfg.acceptors` sockets to `addr` with a backlog of `cfg.backlog`,
+/// with `SO_REUSEPORT` if there's more than one, so that the kernel spreads
+/// connections among them and they're accepted in parallel. With port 0 they
+/// all get the port the first does.
+pub fn bind_tcp(mut addr: SocketAddr, cfg: &ServerCfg) -> io::Result<Vec<std::net::TcpListener>> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let mut listeners = Vec::with_capacity(cfg.acceptors);
+    for _ in 0..cfg.acceptors {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        // Like std, so that restarting doesn't wait for TIME_WAIT to pass.
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        if cfg.acceptors > 1 {
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(cfg.backlog as i32)?;
+        let listener = std::net::TcpListener::from(socket);
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Binds the Unix socket at `cfg.path`, replacing a socket left behind by a
+/// previous run, but not any other kind of file.
+#[cfg(unix)]
+pub fn bind_unix(cfg: &UnixSocketCfg) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(&cfg.path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&cfg.path)?,
+        _ => {}
+    }
+    let listener = std::os::unix::net::UnixListener::bind(&cfg.path)
+        .map_err(|err| io::Error::new(err.kind(), format!("binding {}: {}", cfg.path.display(), err)))?;
+    if let Some(mode) = cfg.mode {
+        std::fs::set_permissions(&cfg.path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// An accepted connection, which frees its slot when it's closed.
+pub struct Conn {
+    stream: Stream,
+    remote_addr: SocketAddr,
+    _permit: Option<OwnedSemaphorePermit>,
+    open: IntGauge,
+}
+
+impl Conn {
+    fn new(stream: Stream, remote_addr: SocketAddr, permit: Option<OwnedSemaphorePermit>, open: &IntGauge) -> Conn {
+        open.inc();
+        Conn { stream, remote_addr, _permit: permit, open: open.clone() }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.stream, Stream::Tls(_))
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.open.dec();
+    }
+}
+
+impl AsyncRead for Conn {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        match &self.stream {
+            Stream::Plain(stream) => stream.prepare_uninitialized_buffer(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.prepare_uninitialized_buffer(buf),
+            Stream::Tls(stream) => stream.prepare_uninitialized_buffer(buf),
+        }
+    }
+
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    #[cfg(unix)]
+    use socket2::SockRef;
+    #[cfg(unix)]
+    use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_options() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut cfg = Config::default().validate().unwrap();
+        cfg.port = 0;
+        cfg.backlog = 16;
+        cfg.tcp_nodelay = true;
+        cfg.tcp_keepalive = Some(Duration::from_secs(30));
+        let bound = bind_tcp(cfg.addr(), &cfg).unwrap().remove(0);
+        let addr = bound.local_addr().unwrap();
+        #[cfg(target_os = "linux")]
+        {
+            // For a listening socket, ss reports the backlog as its Send-Q.
+            let filter = format!("sport = :{}", addr.port());
+            let output = std::process::Command::new("ss").args(["-Hltn", &filter]).output().unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert_eq!(stdout.split_whitespace().nth(2), Some("16"), "{}", stdout);
+        }
+        rt.block_on(async {
+            let mut listener = Listener::new(Bound::Tcp(bound), &cfg, &Metrics::new()).unwrap();
+            let accept = futures::future::poll_fn(|cx| Pin::new(&mut listener).poll_accept(cx));
+            let (_client, accepted) = futures::join!(TcpStream::connect(addr), accept);
+            let conn = accepted.unwrap().unwrap();
+            let stream = match &conn.stream {
+                Stream::Plain(stream) => stream,
+                _ => panic!("expected a plain TCP connection"),
+            };
+            assert!(stream.nodelay().unwrap());
+            // Safe as `stream` outlives the borrow.
+            let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+            let socket = SockRef::from(&fd);
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+        });
+    }
+}
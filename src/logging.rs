@@ -0,0 +1,115 @@
+use crate::access_log;
+use crate::config::{LogFormat, LogLevel};
+use crate::error::AppError;
+use crate::Result;
+use std::sync::OnceLock;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Lets the filter of the global subscriber be changed at runtime.
+static FILTER: OnceLock<FilterHandle> = OnceLock::new();
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Level {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// Keeps trace export running; dropping it flushes any spans that haven't
+/// been exported yet.
+pub struct Guard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("failed to flush traces: {}", err);
+            }
+        }
+    }
+}
+
+/// Writes access log lines as they are, without a timestamp, level or spans
+/// of their own.
+struct AccessLine;
+
+impl<S, N> FormatEvent<S, N> for AccessLine
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Installs the global tracing subscriber. Should be called once, before the
+/// server is started.
+///
+/// Access log lines go to the same writer as the logs, but aren't subject to
+/// their filter, which only `access_log` turns off.
+pub fn init(level: LogLevel, format: LogFormat) -> Result<Guard> {
+    let filter = EnvFilter::default().add_directive(Level::from(level).into());
+    let (filter, handle) = reload::Layer::new(filter);
+    let fmt = match format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+    let fmt = fmt.with_filter(Targets::new().with_default(LevelFilter::TRACE).with_target(access_log::TARGET, LevelFilter::OFF));
+    let access = fmt::layer().event_format(AccessLine);
+    // Printed, so that the test harness captures it.
+    #[cfg(test)]
+    let access = access.with_test_writer();
+    let access = access.with_filter(Targets::new().with_target(access_log::TARGET, LevelFilter::INFO));
+    let _ = FILTER.set(handle);
+
+    #[cfg(feature = "otlp")]
+    {
+        let (otlp, provider) = match crate::otlp::layer()? {
+            Some((layer, provider)) => (Some(layer), Some(provider)),
+            None => (None, None),
+        };
+        Registry::default().with(fmt.and_then(otlp).with_filter(filter)).with(access).try_init()?;
+        Ok(Guard { provider })
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        Registry::default().with(fmt.with_filter(filter)).with(access).try_init()?;
+        Ok(Guard {})
+    }
+}
+
+fn handle() -> Result<&'static FilterHandle> {
+    FILTER.get().ok_or_else(|| AppError::Internal("logging hasn't been initialized".into()))
+}
+
+/// The filter currently applied to logs, in `EnvFilter` syntax.
+pub fn filter() -> Result<String> {
+    Ok(handle()?.with_current(|filter| filter.to_string())?)
+}
+
+/// Replaces the filter applied to logs.
+pub fn set_filter(filter: EnvFilter) -> Result<()> {
+    handle()?.reload(filter)?;
+    Ok(())
+}
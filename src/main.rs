@@ -1,213 +1,98 @@
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{
-    body::to_bytes, client::HttpConnector, Body, Client, Method, Request, Response, Server,
-    StatusCode,
-};
-use hyper_tls::HttpsConnector;
-use serde_derive::{Deserialize, Serialize};
-use serde_json::from_slice;
-use std::sync::Arc;
-
-const CATS_URL: &str = "https://cat-fact.herokuapp.com";
-
-const TODO_URL: &str = "https://jsonplaceholder.typicode.com";
-
-struct ServerCfg{
-    cats_url: String,
-    todo_url: String,
-}
-
-type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
-type Result<T> = std::result::Result<T, Error>;
-type HttpClient = Client<HttpsConnector<HttpConnector>>;
-
-#[derive(Serialize, Deserialize)]
-struct CatFact {
-    text: String,
-}
-
-#[derive(Serialize, Deserialize)]
-struct TODO {
-    title: String,
-}
-
-fn get_cats_url(base_url: &str) -> String {
-    format!("{}facts/random", base_url)
-}
-
-fn get_todo_url(base_url: &str) -> String {
-    format!("{}todos/1", base_url)
-}
-
-async fn basic(_req: Request<Body>, client: &HttpClient, todo_url: &str) -> Result<Body> {
-    let res = do_get_req(&get_todo_url(todo_url), &client).await?;
-    let body = to_bytes(res.into_body()).await?;
-    let todo: TODO = from_slice(&body)?;
-    Ok(todo.title.into())
-}
-
-async fn double(_req: Request<Body>, client: &HttpClient, cats_url: &str, todo_url: &str) -> Result<Body> {
-    let res_todo = do_get_req(&get_todo_url(todo_url), &client).await?;
-    let body_todo = to_bytes(res_todo.into_body()).await?;
-    let todo: TODO = from_slice(&body_todo)?;
-
-    let res_cats = do_get_req(&get_cats_url(cats_url), &client).await?;
-    let body_cats = to_bytes(res_cats.into_body()).await?;
-    let fact: CatFact = from_slice(&body_cats)?;
-    Ok(format!("Todo: {}, Cat Fact: {}", todo.title, fact.text).into())
+use clap::Parser;
+use rust_mockito_example::config::{LogFormat, LogLevel};
+use rust_mockito_example::{logging, run_server, runtime, Config, Result, ServerCfg};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// Flags take precedence over the environment, which takes precedence over the
+/// config file.
+#[derive(Clone, Parser)]
+#[command(version, about)]
+struct Args {
+    /// TOML file to read the configuration from.
+    #[arg(long, env = "CONFIG")]
+    config: Option<PathBuf>,
+    /// Port to listen on [default: 3000].
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+    /// Address to bind the listener to [default: 127.0.0.1].
+    #[arg(long, env = "BIND_ADDR")]
+    bind: Option<IpAddr>,
+    /// Base url of the cat facts api [default: https://cat-fact.herokuapp.com/].
+    #[arg(long, env = "CATS_URL")]
+    cats_url: Option<String>,
+    /// Base url of the todo api [default: https://jsonplaceholder.typicode.com/].
+    #[arg(long, env = "TODO_URL")]
+    todo_url: Option<String>,
+    /// Most verbose level that gets logged [default: info].
+    #[arg(long, env = "LOG_LEVEL", value_enum)]
+    log_level: Option<LogLevel>,
+    /// How log lines are formatted [default: text].
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+    /// Threads running the server's tasks, 0 is one per core [default: 0].
+    #[arg(long, env = "WORKER_THREADS")]
+    worker_threads: Option<usize>,
 }
 
-async fn do_get_req(uri: &str, client: &HttpClient) -> Result<Response<Body>> {
-    let request = Request::builder()
-        .method(Method::GET)
-        .uri(uri)
-        .body(Body::empty())?;
-    let res = client.request(request).await?;
-    Ok(res)
-}
-
-async fn route(req: Request<Body>, client: HttpClient, cfg: Arc<ServerCfg>) -> Result<Response<Body>> {
-    let mut response = Response::new(Body::empty());
-
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/basic") => {
-            *response.body_mut() = basic(req, &client, &cfg.todo_url).await?;
+impl Args {
+    fn into_cfg(self) -> Result<ServerCfg> {
+        let mut cfg = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(port) = self.port {
+            cfg.server.port = port;
         }
-        (&Method::GET, "/double") => {
-            *response.body_mut() = double(req, &client, &cfg.cats_url, &cfg.todo_url).await?;
+        if let Some(bind) = self.bind {
+            cfg.server.bind = bind;
         }
-        _ => {
-            *response.status_mut() = StatusCode::NOT_FOUND;
+        if let Some(url) = self.cats_url {
+            cfg.upstreams.cats.url = Some(url);
         }
-    };
-    Ok(response)
-}
-
-fn init_client() -> HttpClient {
-    let https = HttpsConnector::new();
-    Client::builder().build::<_, Body>(https)
-}
-
-async fn run_server() -> Result<()> {
-    _run_server(ServerCfg{
-        cats_url: CATS_URL.to_owned(),
-        todo_url: TODO_URL.to_owned(),
-    }).await
-}
-
-async fn _run_server(cfg: ServerCfg) -> Result<()> {
-    let client = init_client();
-    let cfg = Arc::new(cfg);
-
-    let new_service = make_service_fn(move |_| {
-        let client_clone = client.clone();
-        let cfg = cfg.clone();
-
-        async { Ok::<_, Error>(service_fn(
-            move |req| route(req, client_clone.clone(), cfg.clone())
-        ))}
-    });
-    let addr = "127.0.0.1:3000".parse().unwrap();
-    let server = Server::bind(&addr).serve(new_service);
-
-    println!("Listening on http://{}", addr);
-    let res = server.await?;
-    Ok(res)
+        if let Some(url) = self.todo_url {
+            cfg.upstreams.todo.url = Some(url);
+        }
+        if let Some(log_level) = self.log_level {
+            cfg.server.log_level = log_level;
+        }
+        if let Some(log_format) = self.log_format {
+            cfg.server.log_format = log_format;
+        }
+        if let Some(worker_threads) = self.worker_threads {
+            cfg.runtime.worker_threads = worker_threads;
+        }
+        Ok(cfg.validate()?)
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    run_server().await?;
-    Ok(())
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let cfg = args.clone().into_cfg()?;
+    let mut rt = runtime::build(&cfg.runtime)?;
+    rt.block_on(async move {
+        let _guard = logging::init(cfg.log_level, cfg.log_format)?;
+        run_server(cfg, move || args.clone().into_cfg()).await?;
+        Ok(())
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use httptest::{Expectation, mappers::*, responders::*};
-    use serde_json::json;
-    use tokio::runtime::Runtime;
 
     #[test]
-    fn test_basic() {
-        let server = httptest::Server::run();
-        server.expect(
-            Expectation::matching(request::method_path("GET", "/todos/1"))
-            .respond_with(json_encoded(json!({
-                "title": "get another cat"
-            }))));
-
-        let mut rt = Runtime::new().unwrap();
-        let client = init_client();
-
-        let cfg = ServerCfg{
-            cats_url: server.url_str("/"),
-            todo_url: server.url_str("/"),
-        };
-
-        // start server
-        rt.spawn(_run_server(cfg));
-
-        // wait for server to come up
-        std::thread::sleep(std::time::Duration::from_millis(50));
-
-        // make requests
-        let req_fut = client.request(
-            Request::builder()
-                .method(Method::GET)
-                .uri("http://localhost:3000/basic")
-                .body(Body::empty())
-                .unwrap(),
-        );
-        let res = rt.block_on(req_fut).unwrap();
-        let body = rt.block_on(to_bytes(res.into_body())).unwrap();
-
-        assert_eq!(std::str::from_utf8(&body).unwrap(), "get another cat");
-    }
-
-    #[test]
-    fn test_double() {
-        let mut rt = Runtime::new().unwrap();
-        let client = init_client();
-        let server = httptest::Server::run();
-        server.expect(
-            Expectation::matching(request::method_path("GET", "/facts/random"))
-            .respond_with(
-                json_encoded(json!({
-                    "text": "cats are the best living creatures in the universe"
-                }))));
-        server.expect(
-            Expectation::matching(request::method_path("GET", "/todos/1"))
-            .respond_with(
-                json_encoded(json!({
-                    "title": "get another cat"
-                }))));
-
-        let cfg = ServerCfg{
-            cats_url: server.url_str("/"),
-            todo_url: server.url_str("/"),
-        };
-
-        // start server
-        rt.spawn(_run_server(cfg));
-
-        // wait for server to come up
-        std::thread::sleep(std::time::Duration::from_millis(50));
-
-        // make requests
-        let req_fut = client.request(
-            Request::builder()
-                .method(Method::GET)
-                .uri("http://localhost:3000/double")
-                .body(Body::empty())
-                .unwrap(),
-        );
-        let res = rt.block_on(req_fut).unwrap();
-        let body = rt.block_on(to_bytes(res.into_body())).unwrap();
-
-        assert_eq!(
-            std::str::from_utf8(&body).unwrap(),
-            "Todo: get another cat, Cat Fact: cats are the best living creatures in the universe"
-        );
+    fn test_args() {
+        let cfg = Args::try_parse_from([
+            "rust-mockito-example",
+            "--port", "8080",
+            "--bind", "0.0.0.0",
+            "--todo-url", "http://todos.staging",
+            "--worker-threads", "2",
+        ]).unwrap().into_cfg().unwrap();
+
+        assert_eq!(cfg.addr(), "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(cfg.todo.url, "http://todos.staging/");
+        assert_eq!(cfg.runtime.worker_threads, 2);
     }
 }
@@ -0,0 +1,409 @@
+use crate::Result;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many of the most recent requests per route the latency quantiles and
+/// burn rate are computed over.
+const WINDOW: usize = 1024;
+
+const QUANTILES: [(f64, &str); 3] = [(0.5, "0.5"), (0.95, "0.95"), (0.99, "0.99")];
+
+/// The service's Prometheus metrics, exported at `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// Requests handled, by route, method and status code.
+    pub requests: IntCounterVec,
+    /// Time spent handling requests, by route.
+    pub request_duration: HistogramVec,
+    /// Connections being served.
+    pub connections_open: IntGauge,
+    /// Connections waiting for others to close before they're served.
+    pub connections_queued: IntGauge,
+    /// Connections closed without being served because too many were open.
+    pub connections_rejected: IntCounter,
+    /// Requests sent to the upstreams, by upstream and status class.
+    pub upstream_requests: IntCounterVec,
+    /// Upstream requests that were retried, by upstream.
+    pub upstream_retries: IntCounterVec,
+    /// Time until the upstreams' response headers arrived, by upstream.
+    upstream_request_duration: HistogramVec,
+    /// Requests sent to an upstream a second time because the first attempt
+    /// was slow, by upstream.
+    pub upstream_hedges: IntCounterVec,
+    /// Hedged requests that answered before the original, by upstream.
+    pub upstream_hedge_wins: IntCounterVec,
+    /// Upstream requests that weren't sent because an identical one was
+    /// in flight, by upstream.
+    pub upstream_coalesced: IntCounterVec,
+    /// Lookups of upstream responses in the cache, by upstream and result.
+    pub upstream_cache: IntCounterVec,
+    /// Time spent establishing connections to the upstreams, by host.
+    pub upstream_connect_duration: HistogramVec,
+    /// State of the upstreams' circuit breakers: 0 closed, 1 open, 2 half
+    /// open.
+    pub upstream_circuit_state: IntGaugeVec,
+    /// Upstream requests failed fast because the circuit was open, by
+    /// upstream.
+    pub upstream_circuit_rejections: IntCounterVec,
+    /// Upstream requests delayed or rejected to stay within the upstream's
+    /// rate limit, by upstream and result.
+    pub upstream_throttled: IntCounterVec,
+    /// OAuth2 tokens fetched for upstreams, by upstream and result.
+    pub upstream_token_fetches: IntCounterVec,
+    /// Upstream credentials read from Vault, by upstream and result.
+    pub upstream_vault_reads: IntCounterVec,
+    /// Webhooks subscribed to the cat facts.
+    pub webhook_subscriptions: IntGauge,
+    /// Deliveries to webhooks, by whether they were delivered, failed after
+    /// every attempt, or were dropped because the queue was full.
+    pub webhook_deliveries: IntCounterVec,
+    /// Attempts to deliver to webhooks, by status class.
+    pub webhook_delivery_attempts: IntCounterVec,
+    /// TLS handshakes that failed or timed out.
+    pub tls_handshake_failures: IntCounter,
+    /// Requests authenticated with an API key, by the name of the key.
+    pub api_key_requests: IntCounterVec,
+    /// Requests rejected because the client exceeded the rate limit, by
+    /// route.
+    pub rate_limited: IntCounterVec,
+    /// Requests rejected because of the client's address, by route.
+    pub ip_filtered: IntCounterVec,
+    /// Requests abandoned by their client before they were answered, by
+    /// route.
+    pub cancelled: IntCounterVec,
+    /// Requests that did or didn't meet their route's SLO, by route and
+    /// result.
+    pub slo_requests: IntCounterVec,
+    latency_quantiles: GaugeVec,
+    slo_burn_rate: GaugeVec,
+    /// The latency of and whether the SLO was met for the most recent
+    /// requests, by route.
+    windows: Mutex<HashMap<&'static str, VecDeque<(f64, bool)>>>,
+    /// The latency of the most recent requests, by upstream.
+    upstream_windows: Mutex<HashMap<&'static str, VecDeque<f64>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests handled."),
+            &["route", "method", "status"],
+        ).unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time spent handling requests."),
+            &["route"],
+        ).unwrap();
+        let connections_open = IntGauge::new("http_connections_open", "Connections being served.").unwrap();
+        let connections_queued = IntGauge::new(
+            "http_connections_queued",
+            "Connections waiting for others to close before they're served.",
+        ).unwrap();
+        let connections_rejected = IntCounter::new(
+            "http_connections_rejected_total",
+            "Connections closed without being served because too many were open.",
+        ).unwrap();
+        let upstream_requests = IntCounterVec::new(
+            Opts::new("upstream_requests_total", "Requests sent to the upstreams."),
+            &["upstream", "status_class"],
+        ).unwrap();
+        let upstream_retries = IntCounterVec::new(
+            Opts::new("upstream_retries_total", "Upstream requests that were retried."),
+            &["upstream"],
+        ).unwrap();
+        let upstream_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Time until the upstreams' response headers arrived.",
+            ),
+            &["upstream"],
+        ).unwrap();
+        let upstream_hedges = IntCounterVec::new(
+            Opts::new(
+                "upstream_hedged_requests_total",
+                "Requests sent to an upstream a second time because the first attempt was slow.",
+            ),
+            &["upstream"],
+        ).unwrap();
+        let upstream_hedge_wins = IntCounterVec::new(
+            Opts::new(
+                "upstream_hedge_wins_total",
+                "Hedged requests that answered before the original.",
+            ),
+            &["upstream"],
+        ).unwrap();
+        let upstream_coalesced = IntCounterVec::new(
+            Opts::new(
+                "upstream_coalesced_requests_total",
+                "Upstream requests that weren't sent because an identical one was in flight.",
+            ),
+            &["upstream"],
+        ).unwrap();
+        let upstream_cache = IntCounterVec::new(
+            Opts::new("upstream_cache_requests_total", "Lookups of upstream responses in the cache."),
+            &["upstream", "result"],
+        ).unwrap();
+        let upstream_connect_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_connect_duration_seconds",
+                "Time spent establishing connections to the upstreams.",
+            ),
+            &["host"],
+        ).unwrap();
+        let upstream_circuit_state = IntGaugeVec::new(
+            Opts::new(
+                "upstream_circuit_state",
+                "State of the upstreams' circuit breakers: 0 closed, 1 open, 2 half open.",
+            ),
+            &["upstream"],
+        ).unwrap();
+        let upstream_circuit_rejections = IntCounterVec::new(
+            Opts::new(
+                "upstream_circuit_rejections_total",
+                "Upstream requests failed fast because the circuit was open.",
+            ),
+            &["upstream"],
+        ).unwrap();
+        let upstream_throttled = IntCounterVec::new(
+            Opts::new(
+                "upstream_throttled_requests_total",
+                "Upstream requests delayed or rejected to stay within the upstream's rate limit.",
+            ),
+            &["upstream", "result"],
+        ).unwrap();
+        let upstream_token_fetches = IntCounterVec::new(
+            Opts::new("upstream_token_fetches_total", "OAuth2 tokens fetched for upstream requests."),
+            &["upstream", "result"],
+        ).unwrap();
+        let upstream_vault_reads = IntCounterVec::new(
+            Opts::new("upstream_vault_reads_total", "Upstream credentials read from Vault."),
+            &["upstream", "result"],
+        ).unwrap();
+        let webhook_subscriptions = IntGauge::new(
+            "webhook_subscriptions",
+            "Webhooks subscribed to the cat facts.",
+        ).unwrap();
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new(
+                "webhook_deliveries_total",
+                "Deliveries to webhooks, by whether they were delivered, failed or dropped.",
+            ),
+            &["result"],
+        ).unwrap();
+        let webhook_delivery_attempts = IntCounterVec::new(
+            Opts::new("webhook_delivery_attempts_total", "Attempts to deliver to webhooks."),
+            &["status_class"],
+        ).unwrap();
+        let tls_handshake_failures = IntCounter::new(
+            "http_tls_handshake_failures_total",
+            "TLS handshakes with clients that failed or timed out.",
+        ).unwrap();
+        let api_key_requests = IntCounterVec::new(
+            Opts::new("http_api_key_requests_total", "Requests authenticated with an API key."),
+            &["key"],
+        ).unwrap();
+        let ip_filtered = IntCounterVec::new(
+            Opts::new(
+                "http_ip_filtered_requests_total",
+                "Requests rejected because the client's address isn't allowed.",
+            ),
+            &["route"],
+        ).unwrap();
+        let cancelled = IntCounterVec::new(
+            Opts::new(
+                "http_requests_cancelled_total",
+                "Requests whose client disconnected before they were answered.",
+            ),
+            &["route"],
+        ).unwrap();
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "http_rate_limited_requests_total",
+                "Requests rejected because the client exceeded the rate limit.",
+            ),
+            &["route"],
+        ).unwrap();
+        let slo_requests = IntCounterVec::new(
+            Opts::new("slo_requests_total", "Requests that did or didn't meet their SLO."),
+            &["route", "result"],
+        ).unwrap();
+        let latency_quantiles = GaugeVec::new(
+            Opts::new(
+                "http_request_latency_seconds",
+                "Latency quantiles over the most recent requests.",
+            ),
+            &["route", "quantile"],
+        ).unwrap();
+        let slo_burn_rate = GaugeVec::new(
+            Opts::new(
+                "slo_error_budget_burn_rate",
+                "Rate the error budget is spent at over the most recent requests; 1 spends it exactly.",
+            ),
+            &["route"],
+        ).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(connections_open.clone())).unwrap();
+        registry.register(Box::new(connections_queued.clone())).unwrap();
+        registry.register(Box::new(connections_rejected.clone())).unwrap();
+        registry.register(Box::new(upstream_requests.clone())).unwrap();
+        registry.register(Box::new(upstream_retries.clone())).unwrap();
+        registry.register(Box::new(upstream_request_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_hedges.clone())).unwrap();
+        registry.register(Box::new(upstream_hedge_wins.clone())).unwrap();
+        registry.register(Box::new(upstream_coalesced.clone())).unwrap();
+        registry.register(Box::new(upstream_cache.clone())).unwrap();
+        registry.register(Box::new(upstream_connect_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_circuit_state.clone())).unwrap();
+        registry.register(Box::new(upstream_circuit_rejections.clone())).unwrap();
+        registry.register(Box::new(upstream_throttled.clone())).unwrap();
+        registry.register(Box::new(upstream_token_fetches.clone())).unwrap();
+        registry.register(Box::new(upstream_vault_reads.clone())).unwrap();
+        registry.register(Box::new(webhook_subscriptions.clone())).unwrap();
+        registry.register(Box::new(webhook_deliveries.clone())).unwrap();
+        registry.register(Box::new(webhook_delivery_attempts.clone())).unwrap();
+        registry.register(Box::new(tls_handshake_failures.clone())).unwrap();
+        registry.register(Box::new(api_key_requests.clone())).unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry.register(Box::new(ip_filtered.clone())).unwrap();
+        registry.register(Box::new(cancelled.clone())).unwrap();
+        registry.register(Box::new(slo_requests.clone())).unwrap();
+        registry.register(Box::new(latency_quantiles.clone())).unwrap();
+        registry.register(Box::new(slo_burn_rate.clone())).unwrap();
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            connections_open,
+            connections_queued,
+            connections_rejected,
+            upstream_requests,
+            upstream_retries,
+            upstream_request_duration,
+            upstream_hedges,
+            upstream_hedge_wins,
+            upstream_coalesced,
+            upstream_cache,
+            upstream_connect_duration,
+            upstream_circuit_state,
+            upstream_circuit_rejections,
+            upstream_throttled,
+            upstream_token_fetches,
+            upstream_vault_reads,
+            webhook_subscriptions,
+            webhook_deliveries,
+            webhook_delivery_attempts,
+            tls_handshake_failures,
+            api_key_requests,
+            rate_limited,
+            ip_filtered,
+            cancelled,
+            slo_requests,
+            latency_quantiles,
+            slo_burn_rate,
+            windows: Mutex::new(HashMap::new()),
+            upstream_windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records how long a request to `route` took and whether it met the
+    /// route's SLO.
+    pub fn observe_request(&self, route: &'static str, latency: Duration, met_slo: bool) {
+        let latency = latency.as_secs_f64();
+        self.request_duration.with_label_values(&[route]).observe(latency);
+        let result = if met_slo { "good" } else { "bad" };
+        self.slo_requests.with_label_values(&[route, result]).inc();
+
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(route).or_default();
+        if window.len() == WINDOW {
+            window.pop_front();
+        }
+        window.push_back((latency, met_slo));
+    }
+
+    /// Records how long a request to `upstream` took to answer.
+    pub fn observe_upstream(&self, upstream: &'static str, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        self.upstream_request_duration.with_label_values(&[upstream]).observe(latency);
+
+        let mut windows = self.upstream_windows.lock().unwrap();
+        let window = windows.entry(upstream).or_default();
+        if window.len() == WINDOW {
+            window.pop_front();
+        }
+        window.push_back(latency);
+    }
+
+    /// The `quantile` of the latency of the most recent requests to
+    /// `upstream`, if any were made.
+    pub fn upstream_latency(&self, upstream: &'static str, quantile: f64) -> Option<Duration> {
+        let windows = self.upstream_windows.lock().unwrap();
+        let mut latencies: Vec<f64> = windows.get(upstream)?.iter().copied().collect();
+        latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(Duration::from_secs_f64(nearest_rank(&latencies, quantile)))
+    }
+
+    /// Renders all metrics in the Prometheus text format, with burn rates
+    /// relative to `slo_target`, the fraction of requests that should meet
+    /// their SLO.
+    pub fn encode(&self, slo_target: f64) -> Result<Vec<u8>> {
+        for (route, window) in self.windows.lock().unwrap().iter() {
+            let mut latencies: Vec<f64> = window.iter().map(|(latency, _)| *latency).collect();
+            latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for (quantile, label) in QUANTILES.iter() {
+                self.latency_quantiles.with_label_values(&[route, label])
+                    .set(nearest_rank(&latencies, *quantile));
+            }
+
+            let bad = window.iter().filter(|(_, met_slo)| !met_slo).count();
+            let bad_ratio = bad as f64 / window.len() as f64;
+            self.slo_burn_rate.with_label_values(&[route]).set(bad_ratio / (1.0 - slo_target));
+        }
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+fn nearest_rank(sorted: &[f64], quantile: f64) -> f64 {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantiles_and_burn_rate() {
+        let metrics = Metrics::new();
+        for ms in 1..=100 {
+            metrics.observe_request("/basic", Duration::from_millis(ms), ms <= 98);
+        }
+        let body = String::from_utf8(metrics.encode(0.99).unwrap()).unwrap();
+
+        assert!(body.contains(r#"http_request_latency_seconds{quantile="0.5",route="/basic"} 0.05"#));
+        assert!(body.contains(r#"http_request_latency_seconds{quantile="0.99",route="/basic"} 0.099"#));
+        assert!(body.contains(r#"slo_requests_total{result="bad",route="/basic"} 2"#));
+        // 2% bad against a 1% budget
+        let burn_rate: f64 = body.lines()
+            .find_map(|line| line.strip_prefix(r#"slo_error_budget_burn_rate{route="/basic"} "#))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((burn_rate - 2.0).abs() < 1e-9);
+    }
+}
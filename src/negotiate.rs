@@ -0,0 +1,165 @@
+use crate::error::AppError;
+use crate::Result;
+use hyper::header::{HeaderMap, ACCEPT};
+
+/// The media types routes can answer with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaType {
+    Json,
+    Text,
+    Xml,
+    MsgPack,
+    Html,
+}
+
+impl MediaType {
+    pub fn essence(self) -> &'static str {
+        match self {
+            MediaType::Json => "application/json",
+            MediaType::Text => "text/plain",
+            MediaType::Xml => "application/xml",
+            MediaType::MsgPack => "application/msgpack",
+            MediaType::Html => "text/html",
+        }
+    }
+
+    /// The `Content-Type` of responses of this type.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            MediaType::Json => "application/json",
+            MediaType::Text => "text/plain; charset=utf-8",
+            MediaType::Xml => "application/xml; charset=utf-8",
+            MediaType::MsgPack => "application/msgpack",
+            MediaType::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+/// Which of the `offered` types to answer a request with `headers` with, as
+/// per RFC 7231: the one the `Accept` header gives the highest quality,
+/// judged by the most specific range that matches it, or the first of those
+/// with the same. Without an `Accept` header that's the first one offered,
+/// and if the client accepts none of them the request fails with a 406.
+pub fn negotiate(headers: &HeaderMap, offered: &[MediaType]) -> Result<MediaType> {
+    let ranges = headers.get_all(ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(parse_range)
+        .collect::<Vec<_>>();
+    if ranges.is_empty() {
+        return offered.first().copied().ok_or_else(|| AppError::NotAcceptable(Vec::new()));
+    }
+    let mut best: Option<(MediaType, u16)> = None;
+    for &media_type in offered {
+        let quality = ranges.iter()
+            .filter(|range| range.matches(media_type.essence()))
+            .max_by_key(|range| range.specificity())
+            .map_or(0, |range| range.quality);
+        if quality > 0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((media_type, quality));
+        }
+    }
+    best.map(|(media_type, _)| media_type)
+        .ok_or_else(|| AppError::NotAcceptable(offered.iter().map(|media_type| media_type.essence()).collect()))
+}
+
+/// Like `negotiate`, but falls back to the first type offered instead of
+/// failing, for routes that answered regardless of `Accept` before it was
+/// honoured.
+pub fn prefer(headers: &HeaderMap, offered: &[MediaType]) -> MediaType {
+    negotiate(headers, offered).unwrap_or(offered[0])
+}
+
+/// A media range of an `Accept` header, like `text/*;q=0.5`.
+struct Range {
+    kind: String,
+    subtype: String,
+    /// In thousandths, so it can be compared exactly.
+    quality: u16,
+}
+
+impl Range {
+    fn matches(&self, essence: &str) -> bool {
+        let (kind, subtype) = essence.split_once('/').unwrap_or((essence, ""));
+        (self.kind == "*" || self.kind == kind) && (self.subtype == "*" || self.subtype == subtype)
+    }
+
+    fn specificity(&self) -> u8 {
+        u8::from(self.kind != "*") + u8::from(self.subtype != "*")
+    }
+}
+
+/// `None` for ranges that aren't valid, which are ignored.
+fn parse_range(range: &str) -> Option<Range> {
+    let mut params = range.split(';');
+    let (kind, subtype) = params.next()?.trim().split_once('/')?;
+    if kind.is_empty() || subtype.is_empty() || (kind == "*" && subtype != "*") {
+        return None;
+    }
+    let mut quality = 1000;
+    for param in params {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("q") {
+                quality = parse_quality(value.trim())?;
+            }
+        }
+    }
+    Some(Range { kind: kind.to_ascii_lowercase(), subtype: subtype.to_ascii_lowercase(), quality })
+}
+
+/// A qvalue, `0` to `1` with up to three decimals, in thousandths.
+pub(crate) fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offered = [MediaType::Json, MediaType::Text];
+        assert_eq!(negotiate(&HeaderMap::new(), &offered).unwrap(), MediaType::Json);
+        assert_eq!(negotiate(&accept("*/*"), &offered).unwrap(), MediaType::Json);
+        assert_eq!(negotiate(&accept("text/plain"), &offered).unwrap(), MediaType::Text);
+        assert_eq!(negotiate(&accept("TEXT/*"), &offered).unwrap(), MediaType::Text);
+        assert_eq!(negotiate(&accept("application/json;q=0.5, text/plain"), &offered).unwrap(), MediaType::Text);
+        assert_eq!(negotiate(&accept("*/*;q=0.1, application/json;q=0.2"), &offered).unwrap(), MediaType::Json);
+        // The most specific range decides, however low its quality.
+        assert_eq!(negotiate(&accept("*/*, application/json;q=0"), &offered).unwrap(), MediaType::Text);
+        // Invalid ranges are ignored.
+        assert_eq!(negotiate(&accept("text/plain;q=2, application/json"), &offered).unwrap(), MediaType::Json);
+
+        match negotiate(&accept("application/xml, text/*;q=0"), &offered) {
+            Err(AppError::NotAcceptable(types)) => assert_eq!(types, vec!["application/json", "text/plain"]),
+            _ => panic!("neither type is acceptable"),
+        }
+        assert_eq!(prefer(&accept("application/xml"), &offered), MediaType::Json);
+    }
+
+    #[test]
+    fn test_parse_quality() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0"), Some(0));
+        for invalid in &["1.5", "0.1234", "-1", "0.x", "", "2"] {
+            assert_eq!(parse_quality(invalid), None, "{}", invalid);
+        }
+    }
+}
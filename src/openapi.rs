@@ -0,0 +1,219 @@
+//! The OpenAPI spec of the HTTP API, generated from the `#[utoipa::path]`
+//! annotations on the handlers and served at `/openapi.json` for generating
+//! clients, and the Swagger UI at `/docs` for exploring it.
+
+use crate::breaker::{Circuit, CircuitState};
+use crate::cache::CacheEntry;
+use crate::config::ServerCfg;
+use crate::error::AppError;
+use crate::handlers::{Check, Checks, Composed, Health, NextFact, Readiness, SourceResult, TodoPage, Version};
+use crate::problem::Problem;
+use crate::handlers::param;
+use crate::webhooks::{Listed, NewSubscription, Subscription};
+use crate::Result;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response};
+use std::collections::BTreeMap;
+use std::io;
+use tracing::warn;
+use utoipa::openapi::path::{Operation, PathItem};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, Ref, RefOr, ResponseBuilder};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        crate::handlers::basic,
+        crate::handlers::double,
+        crate::handlers::aggregate_sources,
+        crate::handlers::source,
+        crate::graphql::graphql,
+        crate::handlers::facts,
+        crate::handlers::next_fact,
+        crate::handlers::facts_stream,
+        crate::ws::websocket,
+        crate::handlers::todos,
+        crate::handlers::create_todo,
+        crate::handlers::todo,
+        crate::handlers::modify_todo,
+        crate::webhooks::subscriptions,
+        crate::webhooks::subscribe,
+        crate::webhooks::unsubscribe,
+        crate::handlers::healthz,
+        crate::handlers::readyz,
+        crate::handlers::version,
+        crate::handlers::metrics,
+        openapi,
+        docs,
+        docs_asset,
+        crate::handlers::log_level,
+        crate::handlers::config,
+        crate::handlers::circuits,
+        crate::handlers::cache_entries,
+        crate::handlers::purge_cache,
+        crate::proxy::proxy,
+    ),
+    components(schemas(
+        Composed, TodoPage, SourceResult, NextFact, Health, Readiness, Checks, Check, Version, Circuit, CircuitState,
+        CacheEntry, Subscription, NewSubscription, Listed, Problem,
+    )),
+    // Routes only need an API key or a JWT if they're configured to.
+    security((), ("api_key" = []), ("bearer" = [])),
+    modifiers(&Amend),
+)]
+pub struct ApiDoc;
+
+/// What the annotations can't say: the security schemes, the problem every
+/// operation can fail with, and the operations of handlers answering at more
+/// than one path.
+struct Amend;
+
+impl Modify for Amend {
+    fn modify(&self, spec: &mut utoipa::openapi::OpenApi) {
+        let components = spec.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))));
+        let bearer = HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build();
+        components.add_security_scheme("bearer", SecurityScheme::Http(bearer));
+        components.add_security_scheme("admin", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Basic).build()));
+        let problem = ResponseBuilder::new()
+            .description("An RFC 7807 problem")
+            .content("application/problem+json", ContentBuilder::new().schema(Some(Ref::from_schema_name("Problem"))).build())
+            .build();
+        components.responses.insert("Problem".to_owned(), problem.into());
+
+        let paths = &mut spec.paths.paths;
+        if let Some(mut html) = paths.get("/double").cloned() {
+            if let Some(op) = html.get.as_mut() {
+                op.operation_id = Some("double_html".to_owned());
+                if let Some(RefOr::T(ok)) = op.responses.responses.get_mut("200") {
+                    ok.content.retain(|media_type, _| media_type == "text/html");
+                }
+                op.responses.responses.remove("406");
+            }
+            paths.insert("/double.html".to_owned(), html);
+        }
+        let purge_all = paths.get("/admin/cache/{key}").and_then(|item| item.delete.clone()).map(|mut op| {
+            op.operation_id = Some("purge_cache_all".to_owned());
+            op.parameters = None;
+            op.responses.responses.retain(|status, _| status.starts_with('2'));
+            op
+        });
+        if let Some(item) = paths.get_mut("/admin/cache") {
+            item.delete = purge_all;
+        }
+
+        let mut ids = BTreeMap::new();
+        for item in paths.values() {
+            for (_, op) in operations(item) {
+                *ids.entry(op.operation_id.clone()).or_insert(0) += 1;
+            }
+        }
+        for item in paths.values_mut() {
+            for (method, op) in operations_mut(item) {
+                // The handlers answering several methods get an id for each.
+                if ids[&op.operation_id] > 1 {
+                    op.operation_id = op.operation_id.take().map(|id| format!("{}_{}", id, method));
+                }
+                // Bodies are only read for the methods that take one.
+                if method == "get" || method == "delete" {
+                    op.request_body = None;
+                }
+                op.responses.responses.insert("default".to_owned(), Ref::from_response_name("Problem").into());
+            }
+        }
+    }
+}
+
+fn operations(item: &PathItem) -> impl Iterator<Item = (&'static str, &Operation)> {
+    let PathItem { get, put, post, delete, patch, .. } = item;
+    vec![("get", get), ("put", put), ("post", post), ("delete", delete), ("patch", patch)].into_iter()
+        .filter_map(|(method, op)| op.as_ref().map(|op| (method, op)))
+}
+
+fn operations_mut(item: &mut PathItem) -> impl Iterator<Item = (&'static str, &mut Operation)> {
+    let PathItem { get, put, post, delete, patch, .. } = item;
+    vec![("get", get), ("put", put), ("post", post), ("delete", delete), ("patch", patch)].into_iter()
+        .filter_map(|(method, op)| op.as_mut().map(|op| (method, op)))
+}
+
+/// This spec.
+#[utoipa::path(get, path = "/openapi.json", tag = "operations", responses(
+        (status = 200, description = "The OpenAPI spec", body = Object),
+))]
+pub fn openapi() -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(ApiDoc::openapi().to_json()?.into())?)
+}
+
+/// Swagger UI for trying out the routes in this spec, if `docs.enabled` is
+/// on. Its assets are served from `docs.assets` by `docs_asset`.
+#[utoipa::path(get, path = "/docs", tag = "operations", responses(
+        (status = 200, description = "The explorer", body = String, content_type = "text/html"),
+        (status = 404, description = "The explorer is disabled", body = Problem, content_type = "application/problem+json"),
+))]
+pub fn docs(cfg: &ServerCfg) -> Result<Response<Body>> {
+    if cfg.docs.is_none() {
+        return Err(AppError::NotFound);
+    }
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(include_str!("../templates/docs.html").into())?)
+}
+
+/// One of the Swagger UI assets `/docs` loads, from `docs.assets`, so that
+/// the explorer doesn't run scripts from elsewhere.
+#[utoipa::path(get, path = "/docs/{asset}", tag = "operations", params(
+        ("asset" = String, Path, description = "`swagger-ui.css` or `swagger-ui-bundle.js`"),
+), responses(
+        (status = 200, description = "The asset", body = String, content_type = "text/css"),
+        (status = 404, description = "The explorer is disabled, or there's no such asset", body = Problem, content_type = "application/problem+json"),
+))]
+pub async fn docs_asset(req: Request<Body>, cfg: &ServerCfg) -> Result<Response<Body>> {
+    let dir = cfg.docs.as_ref().ok_or(AppError::NotFound)?;
+    let (name, content_type) = match param(&req, "asset") {
+        Some(name @ "swagger-ui.css") => (name, "text/css; charset=utf-8"),
+        Some(name @ "swagger-ui-bundle.js") => (name, "text/javascript; charset=utf-8"),
+        _ => return Err(AppError::NotFound),
+    };
+    let asset = match tokio::fs::read(dir.join(name)).await {
+        Ok(asset) => asset,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            warn!(dir = %dir.display(), name, "Swagger UI asset missing");
+            return Err(AppError::NotFound);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    Ok(Response::builder().header(CONTENT_TYPE, content_type).body(asset.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::routes;
+    use serde_json::Value;
+
+    #[test]
+    fn test_routes_described() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for route in routes().routes() {
+            let path = route.pattern().replace("{*", "{");
+            let item = &spec["paths"][&path];
+            assert!(item.is_object(), "{} isn't described", path);
+            for method in route.methods() {
+                let op = &item[method.as_str().to_lowercase()];
+                assert!(op["operationId"].is_string(), "{} {} isn't described", method, path);
+                assert!(op["responses"]["default"].is_object(), "{} {} has no default response", method, path);
+            }
+        }
+        let mut ids = spec["paths"].as_object().unwrap().values()
+            .flat_map(|item| item.as_object().unwrap().values())
+            .map(|op| op["operationId"].clone())
+            .collect::<Vec<Value>>();
+        let described = ids.len();
+        ids.sort_by_key(Value::to_string);
+        ids.dedup();
+        assert_eq!(ids.len(), described, "operation ids aren't unique");
+    }
+}
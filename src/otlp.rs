@@ -0,0 +1,37 @@
+use crate::Result;
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::env;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Builds a layer exporting spans to the collector at
+/// `OTEL_EXPORTER_OTLP_ENDPOINT`, or nothing if it isn't set. The rest of the
+/// exporter is configured through the standard `OTEL_*` variables.
+///
+/// The provider has to be shut down before exiting to flush pending spans.
+pub fn layer<S>() -> Result<Option<(impl Layer<S>, SdkTracerProvider)>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let mut resource = Resource::builder();
+    if env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some((tracing_opentelemetry::layer().with_tracer(tracer), provider)))
+}
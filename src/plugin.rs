@@ -0,0 +1,278 @@
+//! A WASM plugin, loaded from `plugin.path` at startup, that can rewrite the
+//! bodies upstreams answer with and the bodies `/basic` and `/double` answer
+//! with.
+//!
+//! The plugin is a core WASM module without imports. It exports its `memory`,
+//! `alloc(len: i32) -> i32`, which returns where `len` bytes can be written,
+//! and either or both hooks:
+//!
+//! - `rewrite_response(upstream_ptr, upstream_len, body_ptr, body_len) -> i64`
+//!   gets the name of an upstream and the body it answered with, before the
+//!   upstream's transform is applied.
+//! - `enrich_body(route_ptr, route_len, input_ptr, input_len) -> i64` gets the
+//!   route and a JSON object with the body it's about to answer with and the
+//!   values it's made of, like
+//!   `{"body":"Todo: ..., Cat Fact: ...","values":{"todos":"...","cats":"..."}}`
+//!   with the text response format. MessagePack bodies aren't passed to it.
+//!
+//! Both return the bytes to use instead, as their address in the upper 32
+//! bits and their length in the lower ones. Every call gets an instance of
+//! its own, runs on a blocking thread, and fails once it has used up
+//! `plugin.fuel` or tries to grow its memory past `plugin.max_memory_bytes`.
+//!
+//! Without the `wasm-plugins` feature no plugin can be configured and the
+//! bodies are left as they are.
+
+use crate::state::AppState;
+use crate::Result;
+use std::borrow::Cow;
+
+#[cfg(feature = "wasm-plugins")]
+pub use wasm::Plugin;
+
+/// `body`, which `upstream` answered with, as the plugin rewrites it.
+#[cfg(feature = "wasm-plugins")]
+pub async fn rewrite_response<'a>(state: &AppState, upstream: &str, body: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    let plugin = match &state.plugin {
+        Some(plugin) => plugin.clone(),
+        None => return Ok(Cow::Borrowed(body)),
+    };
+    let (upstream, owned) = (upstream.to_owned(), body.to_vec());
+    let rewritten = blocking(move || plugin.rewrite_response(&upstream, &owned)).await?;
+    Ok(rewritten.map_or(Cow::Borrowed(body), Cow::Owned))
+}
+
+/// What `route` answers with instead of `body`, which is made of `values`.
+#[cfg(feature = "wasm-plugins")]
+pub async fn enrich_body(state: &AppState, route: &str, body: String, values: &[(&str, &str)]) -> Result<String> {
+    use crate::error::AppError;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    let plugin = match &state.plugin {
+        Some(plugin) => plugin.clone(),
+        None => return Ok(body),
+    };
+    let values = values.iter().copied().collect::<BTreeMap<_, _>>();
+    let input = json!({ "body": body, "values": values }).to_string();
+    let route = route.to_owned();
+    let enriched = blocking(move || plugin.enrich_body(&route, input.as_bytes())).await?;
+    match enriched {
+        Some(enriched) => String::from_utf8(enriched).map_err(|err| AppError::Internal(err.into())),
+        None => Ok(body),
+    }
+}
+
+/// Runs a plugin call on a blocking thread, since it can take as long as its
+/// fuel lasts.
+#[cfg(feature = "wasm-plugins")]
+async fn blocking<T: Send + 'static>(
+    call: impl FnOnce() -> std::result::Result<T, crate::error::BoxError> + Send + 'static,
+) -> Result<T> {
+    use crate::error::AppError;
+
+    tokio::task::spawn_blocking(call).await
+        .map_err(|err| AppError::Internal(err.into()))?
+        .map_err(AppError::Internal)
+}
+
+/// `body`, which `upstream` answered with, as the plugin rewrites it.
+#[cfg(not(feature = "wasm-plugins"))]
+pub async fn rewrite_response<'a>(_state: &AppState, _upstream: &str, body: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    Ok(Cow::Borrowed(body))
+}
+
+/// What `route` answers with instead of `body`, which is made of `values`.
+#[cfg(not(feature = "wasm-plugins"))]
+pub async fn enrich_body(_state: &AppState, _route: &str, body: String, _values: &[(&str, &str)]) -> Result<String> {
+    Ok(body)
+}
+
+#[cfg(feature = "wasm-plugins")]
+mod wasm {
+    use crate::config::PluginCfg;
+    use crate::error::BoxError;
+    use std::convert::TryFrom;
+    use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+    const REWRITE_RESPONSE: &str = "rewrite_response";
+
+    const ENRICH_BODY: &str = "enrich_body";
+
+    /// The compiled module; it's instantiated anew for every call, so calls
+    /// don't share state and can run concurrently. Cloning it is cheap.
+    #[derive(Clone)]
+    pub struct Plugin {
+        engine: Engine,
+        module: Module,
+        fuel: u64,
+        max_memory_bytes: usize,
+    }
+
+    impl Plugin {
+        pub fn load(cfg: &PluginCfg) -> Result<Plugin, BoxError> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)?;
+            let module = Module::from_file(&engine, &cfg.path)
+                .map_err(|err| format!("loading plugin {}: {:#}", cfg.path.display(), err))?;
+            if let Some(import) = module.imports().next() {
+                return Err(format!(
+                    "plugin {} can't import anything, but imports {}::{}",
+                    cfg.path.display(), import.module(), import.name()
+                ).into());
+            }
+            for export in &["memory", "alloc"] {
+                if module.get_export(export).is_none() {
+                    return Err(format!("plugin {} doesn't export {}", cfg.path.display(), export).into());
+                }
+            }
+            Ok(Plugin { engine, module, fuel: cfg.fuel, max_memory_bytes: cfg.max_memory_bytes })
+        }
+
+        /// `None` if the plugin has no `rewrite_response` hook.
+        pub fn rewrite_response(&self, upstream: &str, body: &[u8]) -> Result<Option<Vec<u8>>, BoxError> {
+            self.call(REWRITE_RESPONSE, upstream.as_bytes(), body)
+        }
+
+        /// `None` if the plugin has no `enrich_body` hook.
+        pub fn enrich_body(&self, route: &str, input: &[u8]) -> Result<Option<Vec<u8>>, BoxError> {
+            self.call(ENRICH_BODY, route.as_bytes(), input)
+        }
+
+        fn call(&self, hook: &str, first: &[u8], second: &[u8]) -> Result<Option<Vec<u8>>, BoxError> {
+            if self.module.get_export(hook).is_none() {
+                return Ok(None);
+            }
+            let limits = StoreLimitsBuilder::new()
+                .memory_size(self.max_memory_bytes)
+                .trap_on_grow_failure(true)
+                .build();
+            let mut store = Store::new(&self.engine, limits);
+            store.limiter(|limits: &mut StoreLimits| limits);
+            store.set_fuel(self.fuel)?;
+            let instance = Instance::new(&mut store, &self.module, &[])?;
+            let memory = instance.get_memory(&mut store, "memory").ok_or("the plugin's memory isn't a memory")?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let hook = instance.get_typed_func::<(i32, i32, i32, i32), i64>(&mut store, hook)?;
+            let mut args = Vec::new();
+            for bytes in &[first, second] {
+                let len = i32::try_from(bytes.len())?;
+                let ptr = alloc.call(&mut store, len)?;
+                memory.write(&mut store, ptr as u32 as usize, bytes)?;
+                args.push((ptr, len));
+            }
+            let packed = hook.call(&mut store, (args[0].0, args[0].1, args[1].0, args[1].1))? as u64;
+            let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+            let output = memory.data(&store).get(ptr..ptr + len).ok_or("the plugin returned bytes out of its memory")?;
+            Ok(Some(output.to_vec()))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasm-plugins"))]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::PluginCfg;
+    use crate::error::BoxError;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Replaces what the todo upstream answers with, leaves the others' be,
+    /// and wraps the final bodies in brackets.
+    pub const PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"title\":\"written by the plugin\"}")
+          (global $next (mut i32) (i32.const 1024))
+          (func $alloc (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func $pack (param $ptr i32) (param $len i32) (result i64)
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+              (i64.extend_i32_u (local.get $len))))
+          (func (export "rewrite_response")
+            (param $name i32) (param $name_len i32) (param $body i32) (param $len i32) (result i64)
+            (if (result i64) (i32.eq (i32.load8_u (local.get $name)) (i32.const 116))
+              (then (call $pack (i32.const 0) (i32.const 33)))
+              (else (call $pack (local.get $body) (local.get $len)))))
+          (func (export "enrich_body")
+            (param $route i32) (param $route_len i32) (param $input i32) (param $len i32) (result i64)
+            (local $out i32)
+            (local.set $out (call $alloc (i32.add (local.get $len) (i32.const 2))))
+            (i32.store8 (local.get $out) (i32.const 91))
+            (memory.copy (i32.add (local.get $out) (i32.const 1)) (local.get $input) (local.get $len))
+            (i32.store8 (i32.add (i32.add (local.get $out) (i32.const 1)) (local.get $len)) (i32.const 93))
+            (call $pack (local.get $out) (i32.add (local.get $len) (i32.const 2)))))
+    "#;
+
+    /// A plugin's file, removed once it's dropped.
+    pub struct PluginFile(pub PathBuf);
+
+    impl Drop for PluginFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Writes `wat` to a file of its own, named after `name`.
+    pub fn write(name: &str, wat: &str) -> PluginFile {
+        let path = env::temp_dir().join(format!("plugin-test-{}-{}.wat", std::process::id(), name));
+        fs::write(&path, wat).unwrap();
+        PluginFile(path)
+    }
+
+    fn load(name: &str, wat: &str) -> std::result::Result<Plugin, BoxError> {
+        let file = write(name, wat);
+        Plugin::load(&PluginCfg { path: file.0.clone(), fuel: 100_000, max_memory_bytes: 1024 * 1024 })
+    }
+
+    #[test]
+    fn test_hooks() {
+        let plugin = load("hooks", PLUGIN).unwrap();
+        let body = plugin.rewrite_response("todo", br#"{"title":"cat"}"#).unwrap();
+        assert_eq!(body.unwrap(), br#"{"title":"written by the plugin"}"#);
+        let body = plugin.rewrite_response("cats", br#"{"text":"cat"}"#).unwrap();
+        assert_eq!(body.unwrap(), br#"{"text":"cat"}"#);
+        assert_eq!(plugin.enrich_body("/basic", b"body").unwrap().unwrap(), b"[body]");
+
+        // Every call starts over, whatever the one before allocated.
+        for _ in 0..3 {
+            assert_eq!(plugin.enrich_body("/basic", b"").unwrap().unwrap(), b"[]");
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let looping = r#"(module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "enrich_body") (param i32 i32 i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))"#;
+        let plugin = load("looping", looping).unwrap();
+        assert!(plugin.enrich_body("/basic", b"body").is_err());
+        assert!(plugin.rewrite_response("todo", b"body").unwrap().is_none());
+
+        // 64 KiB pages, so 32 of them are 2 MiB.
+        let growing = r#"(module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "enrich_body") (param i32 i32 i32 i32) (result i64)
+            (drop (memory.grow (i32.const 32)))
+            (i64.const 0)))"#;
+        let plugin = load("growing", growing).unwrap();
+        assert!(plugin.enrich_body("/basic", b"body").is_err());
+        let huge = r#"(module (memory (export "memory") 32) (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "enrich_body") (param i32 i32 i32 i32) (result i64) (i64.const 0)))"#;
+        assert!(load("huge", huge).unwrap().enrich_body("/basic", b"body").is_err());
+
+        let importing = r#"(module (import "env" "log" (func)) (memory (export "memory") 1))"#;
+        assert!(load("importing", importing).is_err());
+        assert!(load("empty", "(module)").is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::negotiate::MediaType;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+pub const CONTENT_TYPE_PROBLEM: &str = "application/problem+json";
+
+/// The same problem details, for clients that asked for MessagePack.
+pub const CONTENT_TYPE_PROBLEM_MSGPACK: &str = "application/problem+msgpack";
+
+/// An RFC 7807 problem details object, the body of every error response the
+/// service generates itself. The `type` is always `about:blank`, so the
+/// `title` is the reason phrase of the status, unless it's localized.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// `urn:request:` and the id of the request that failed, which its
+    /// `X-Request-Id` has too.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode) -> Problem {
+        Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_owned(),
+            status: status.as_u16(),
+            detail: None,
+            instance: None,
+        }
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Problem {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn instance(mut self, instance: impl Into<String>) -> Problem {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    /// As JSON, or as MessagePack if that's `media_type`.
+    pub fn into_response(self, media_type: MediaType) -> Response<Body> {
+        let (body, content_type) = match media_type {
+            MediaType::MsgPack => (rmp_serde::to_vec_named(&self).unwrap(), CONTENT_TYPE_PROBLEM_MSGPACK),
+            _ => (serde_json::to_vec(&self).unwrap(), CONTENT_TYPE_PROBLEM),
+        };
+        let mut res = Response::new(body.into());
+        *res.status_mut() = StatusCode::from_u16(self.status).unwrap();
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res
+    }
+}
@@ -0,0 +1,117 @@
+//! W3C trace context (`traceparent`/`tracestate`) propagation from incoming
+//! requests to the upstream requests made while handling them.
+//!
+//! With the `otlp` feature the incoming context becomes the parent of the
+//! request span and upstream requests carry the context of their own span.
+//! Without it the service doesn't record spans of its own, so the incoming
+//! headers are passed through unchanged.
+
+use hyper::header::HeaderMap;
+use std::future::Future;
+use tracing::Span;
+
+pub const TRACEPARENT: &str = "traceparent";
+
+pub const TRACESTATE: &str = "tracestate";
+
+/// Runs `fut`, the handling of a request with `headers`, as part of the trace
+/// the request belongs to.
+#[cfg(feature = "otlp")]
+pub async fn continue_trace<F: Future>(headers: &HeaderMap, span: &Span, fut: F) -> F::Output {
+    use opentelemetry::global;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let cx = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&otel::HeaderExtractor(headers))
+    });
+    span.set_parent(cx);
+    fut.await
+}
+
+/// Adds the headers continuing the current trace to an upstream request.
+#[cfg(feature = "otlp")]
+pub fn inject(headers: &mut HeaderMap) {
+    use opentelemetry::global;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let cx = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut otel::HeaderInjector(headers))
+    });
+}
+
+#[cfg(feature = "otlp")]
+mod otel {
+    use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+    use opentelemetry::propagation::{Extractor, Injector};
+
+    pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(HeaderName::as_str).collect()
+        }
+    }
+
+    pub struct HeaderInjector<'a>(pub &'a mut HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(name), Ok(value)) = (key.parse::<HeaderName>(), HeaderValue::from_str(&value)) {
+                self.0.insert(name, value);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+tokio::task_local! {
+    static TRACE_HEADERS: HeaderMap;
+}
+
+/// Runs `fut`, the handling of a request with `headers`, as part of the trace
+/// the request belongs to.
+#[cfg(not(feature = "otlp"))]
+pub async fn continue_trace<F: Future>(headers: &HeaderMap, _span: &Span, fut: F) -> F::Output {
+    let mut trace_headers = HeaderMap::new();
+    if let Some(traceparent) = headers.get(TRACEPARENT) {
+        if traceparent.to_str().map(is_valid_traceparent).unwrap_or(false) {
+            trace_headers.insert(TRACEPARENT, traceparent.clone());
+            if let Some(tracestate) = headers.get(TRACESTATE) {
+                trace_headers.insert(TRACESTATE, tracestate.clone());
+            }
+        }
+    }
+    TRACE_HEADERS.scope(trace_headers, fut).await
+}
+
+/// Adds the headers continuing the current trace to an upstream request.
+#[cfg(not(feature = "otlp"))]
+pub fn inject(headers: &mut HeaderMap) {
+    let _ = TRACE_HEADERS.try_with(|trace_headers| {
+        for (name, value) in trace_headers {
+            headers.insert(name, value.clone());
+        }
+    });
+}
+
+/// Checks for `{version}-{trace-id}-{parent-id}-{flags}` with an all-zero
+/// trace or parent id being invalid, as per the spec.
+#[cfg(not(feature = "otlp"))]
+fn is_valid_traceparent(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    let is_hex = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    let is_zero = |s: &str| s.bytes().all(|b| b == b'0');
+    parts.len() >= 4
+        && is_hex(parts[0], 2)
+        && parts[0] != "ff"
+        && is_hex(parts[1], 32)
+        && !is_zero(parts[1])
+        && is_hex(parts[2], 16)
+        && !is_zero(parts[2])
+        && is_hex(parts[3], 2)
+}
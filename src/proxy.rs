@@ -0,0 +1,110 @@
+use crate::client;
+use crate::auth::X_API_KEY;
+use crate::compression::Relayed;
+use crate::config::ServerCfg;
+use crate::error::AppError;
+use crate::problem::Problem;
+use crate::router::Params;
+use crate::state::AppState;
+use crate::Result;
+use hyper::header::{HeaderMap, HeaderName, AUTHORIZATION, CONNECTION, COOKIE, HOST};
+use hyper::{Body, Request, Response};
+use tracing::instrument;
+
+/// Headers that only apply to a single connection, as per RFC 7230, so they
+/// aren't passed on in either direction.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Forwards the request to `path` on the upstream named `upstream`, with its
+/// method, query, headers and body, which is streamed. `Host` becomes the
+/// upstream's, and `Authorization`, `X-API-Key`, `Cookie` and
+/// `Proxy-Authorization` aren't passed on, since they hold the client's
+/// credentials for this service, not the upstream. The upstream's response is
+/// streamed back as it is, server errors included, since the point is seeing
+/// what the upstream does. So are `Range` requests, and the `206 Partial
+/// Content` answering them.
+#[utoipa::path(method(get, post, put, patch, delete), path = "/proxy/{upstream}/{path}", tag = "admin", security(("admin" = [])), params(
+        ("upstream" = String, Path, description = "`cats` or `todo`"),
+        ("path" = String, Path, description = "The path on the upstream"),
+        ("range" = Option<String>, Header, description = "Passed on, like the other headers"),
+), request_body(content = Object, content_type = "*/*", description = "Passed on as it is"), responses(
+        (status = 200, description = "The upstream's answer, as it is", content_type = "*/*"),
+        (status = 206, description = "The part of the upstream's answer that `Range` asks for", content_type = "*/*"),
+        (status = 404, description = "The upstream is unknown or proxying is off", body = Problem, content_type = "application/problem+json"),
+))]
+#[instrument(skip_all)]
+pub async fn proxy(req: Request<Body>, state: &AppState, cfg: &ServerCfg) -> Result<Response<Body>> {
+    if !cfg.proxy {
+        return Err(AppError::NotFound);
+    }
+    let params = req.extensions().get::<Params>().cloned().unwrap_or_default();
+    let upstream = match params.get("upstream") {
+        Some("cats") => &cfg.cats,
+        Some("todo") => &cfg.todo,
+        _ => return Err(AppError::NotFound),
+    };
+    let mut uri = format!("{}{}", upstream.url, params.get("path").unwrap_or_default());
+    if let Some(query) = req.uri().query() {
+        uri.push('?');
+        uri.push_str(query);
+    }
+    let (parts, body) = req.into_parts();
+    let mut request = Request::builder().method(parts.method).uri(uri).body(body)?;
+    let headers = request.headers_mut();
+    *headers = parts.headers;
+    strip_hop_by_hop(headers);
+    // The client sets it from the uri.
+    headers.remove(HOST);
+    headers.remove(AUTHORIZATION);
+    headers.remove(X_API_KEY);
+    headers.remove(COOKIE);
+
+    // The request has the client's headers already, and forwarding them
+    // would add back those removed above.
+    let sent = client::with_inbound_headers(HeaderMap::new(), client::send(state, upstream, request));
+    let (mut parts, body) = sent.await?.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    parts.extensions.insert(Relayed);
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Removes the hop-by-hop headers, along with any others `Connection` lists.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers.get_all(CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse::<HeaderName>().ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, "keep-alive, X-Hop".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("x-hop", "1".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert("x-end-to-end", "1".parse().unwrap());
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.keys().map(HeaderName::as_str).collect::<Vec<_>>(), vec!["x-end-to-end"]);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::config::{RateLimit, ThrottlePolicy};
+use crate::error::AppError;
+use crate::metrics::Metrics;
+use hyper::header::HeaderMap;
+use ipnet::IpNet;
+use prometheus::IntCounterVec;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+use tracing::{debug, warn};
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Returned instead of handling a request from a client that exceeded its
+/// route's rate limit.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rate limit exceeded, retry after {:?}", self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+struct Bucket {
+    /// Negative while tokens that have yet to be refilled are reserved.
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket will have refilled completely.
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant, limit: RateLimit) -> Bucket {
+        Bucket { tokens: f64::from(limit.burst), updated: now, full_at: now }
+    }
+
+    /// Takes a token, reserving the next one to be refilled if there is none
+    /// and it comes within `max_wait`. Returns how long to wait for the
+    /// token, or for the next one if it doesn't come soon enough.
+    fn take(&mut self, now: Instant, limit: RateLimit, max_wait: Duration) -> Result<Duration, Duration> {
+        let burst = f64::from(limit.burst);
+        let refilled = now.duration_since(self.updated).as_secs_f64() * limit.rate;
+        self.tokens = (self.tokens + refilled).min(burst);
+        self.updated = now;
+        let wait = Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / limit.rate);
+        if wait > max_wait {
+            return Err(wait);
+        }
+        self.tokens -= 1.0;
+        self.full_at = now + Duration::from_secs_f64((burst - self.tokens) / limit.rate);
+        Ok(wait)
+    }
+
+    /// Returns a token taken but not used, like one reserved by a request
+    /// that was cancelled while waiting for it.
+    fn give_back(&mut self, limit: RateLimit) {
+        let burst = f64::from(limit.burst);
+        self.tokens = (self.tokens + 1.0).min(burst);
+        self.full_at = self.updated + Duration::from_secs_f64((burst - self.tokens) / limit.rate);
+    }
+}
+
+type ClientRoute = (IpAddr, &'static str);
+
+/// The buckets of the clients that used some of their tokens, dropped once
+/// they've refilled completely, since they're no different from new ones
+/// then. Each is scheduled to be looked at when it was last due to be full,
+/// so that dropping them takes a few heap operations per request rather than
+/// scans of all of them.
+#[derive(Default)]
+struct ClientBuckets {
+    buckets: HashMap<ClientRoute, Bucket>,
+    /// One entry per bucket, at or before its `full_at`.
+    due: BinaryHeap<Reverse<(Instant, ClientRoute)>>,
+}
+
+impl ClientBuckets {
+    fn take(&mut self, now: Instant, key: ClientRoute, limit: RateLimit) -> Result<Duration, Duration> {
+        self.expire(now);
+        let (bucket, new) = match self.buckets.entry(key) {
+            Entry::Occupied(entry) => (entry.into_mut(), false),
+            Entry::Vacant(entry) => (entry.insert(Bucket::new(now, limit)), true),
+        };
+        let taken = bucket.take(now, limit, Duration::from_secs(0));
+        if new {
+            self.due.push(Reverse((bucket.full_at, key)));
+        }
+        taken
+    }
+
+    /// Drops the buckets that have refilled by `now`, and reschedules the
+    /// ones that were taken from since they were scheduled.
+    fn expire(&mut self, now: Instant) {
+        while let Some(Reverse((due, key))) = self.due.peek().copied() {
+            if due > now {
+                break;
+            }
+            self.due.pop();
+            match self.buckets.get(&key) {
+                Some(bucket) if bucket.full_at > now => self.due.push(Reverse((bucket.full_at, key))),
+                _ => {
+                    self.buckets.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Token buckets by client and route. Limits come with each call so that they
+/// follow config reloads while the buckets themselves don't reset.
+pub struct RateLimiter {
+    buckets: Mutex<ClientBuckets>,
+    rejections: IntCounterVec,
+}
+
+impl RateLimiter {
+    pub fn new(metrics: &Metrics) -> RateLimiter {
+        RateLimiter {
+            buckets: Mutex::new(ClientBuckets::default()),
+            rejections: metrics.rate_limited.clone(),
+        }
+    }
+
+    /// Takes a token from the bucket of `client` for `route`, or says how
+    /// long until there is one.
+    pub fn check(&self, client: IpAddr, route: &'static str, limit: RateLimit) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.take(now, (client, route), limit) {
+            Ok(_) => Ok(()),
+            Err(retry_after) => {
+                self.rejections.with_label_values(&[route]).inc();
+                Err(RateLimited { retry_after })
+            }
+        }
+    }
+}
+
+/// Token buckets by upstream, limiting the requests sent to them, and the
+/// cool-downs upstreams asked for with a 429. Like the breakers, policies
+/// come with each call.
+pub struct Throttles {
+    buckets: Mutex<HashMap<&'static str, Bucket>>,
+    /// Until when no requests are sent to each upstream.
+    cool_downs: Mutex<HashMap<&'static str, Instant>>,
+    throttled: IntCounterVec,
+}
+
+impl Throttles {
+    pub fn new(metrics: &Metrics) -> Throttles {
+        Throttles {
+            buckets: Mutex::new(HashMap::new()),
+            cool_downs: Mutex::new(HashMap::new()),
+            throttled: metrics.upstream_throttled.clone(),
+        }
+    }
+
+    /// Waits until a request may be sent to `upstream`, or fails right away
+    /// if it's cooling down or waiting would take longer than the policy's
+    /// `max_wait`.
+    pub async fn acquire(&self, upstream: &'static str, policy: Option<&ThrottlePolicy>) -> Result<(), AppError> {
+        if self.cooling_down(upstream) {
+            self.throttled.with_label_values(&[upstream, "cooling_down"]).inc();
+            return Err(AppError::UpstreamThrottled(upstream));
+        }
+        let policy = match policy {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let taken = self.take(upstream, policy, policy.max_wait);
+        match taken {
+            Ok(wait) if wait > Duration::from_secs(0) => {
+                debug!(?wait, "throttling upstream request");
+                self.throttled.with_label_values(&[upstream, "delayed"]).inc();
+                let reservation = Reservation { throttles: self, upstream, limit: policy.limit };
+                delay_for(wait).await;
+                std::mem::forget(reservation);
+                Ok(())
+            }
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.throttled.with_label_values(&[upstream, "rejected"]).inc();
+                Err(AppError::UpstreamThrottled(upstream))
+            }
+        }
+    }
+
+    /// Whether a request may be sent to `upstream` right away, for optional
+    /// ones like hedges that aren't worth waiting for.
+    pub fn try_acquire(&self, upstream: &'static str, policy: Option<&ThrottlePolicy>) -> bool {
+        !self.cooling_down(upstream)
+            && policy.is_none_or(|policy| self.take(upstream, policy, Duration::from_secs(0)).is_ok())
+    }
+
+    /// Stops requests to `upstream` for `duration`, or longer if it was
+    /// already asked to.
+    pub fn cool_down(&self, upstream: &'static str, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut cool_downs = self.cool_downs.lock().unwrap();
+        let cool_down = cool_downs.entry(upstream).or_insert(until);
+        *cool_down = until.max(*cool_down);
+        warn!(upstream, ?duration, "upstream asked to cool down");
+    }
+
+    fn cooling_down(&self, upstream: &'static str) -> bool {
+        let cool_downs = self.cool_downs.lock().unwrap();
+        cool_downs.get(upstream).is_some_and(|until| Instant::now() < *until)
+    }
+
+    fn take(&self, upstream: &'static str, policy: &ThrottlePolicy, max_wait: Duration) -> Result<Duration, Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(upstream).or_insert_with(|| Bucket::new(now, policy.limit));
+        bucket.take(now, policy.limit, max_wait)
+    }
+}
+
+/// A token reserved by a request waiting for it, which is given back if the
+/// request is dropped before it's done waiting, e.g. because its client went
+/// away, rather than being lost to the requests behind it.
+struct Reservation<'a> {
+    throttles: &'a Throttles,
+    upstream: &'static str,
+    limit: RateLimit,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut buckets = self.throttles.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get_mut(self.upstream) {
+            bucket.give_back(self.limit);
+        }
+    }
+}
+
+/// The address of the client that made a request that came from `peer`. Each
+/// trusted proxy appends the address it got the request from to
+/// `X-Forwarded-For`, so the client is the last one not sent by a trusted
+/// proxy.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let mut client = peer;
+    let forwarded: Vec<&str> = headers.get_all(X_FORWARDED_FOR).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in forwarded.into_iter().rev() {
+        if !trusted(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let limiter = RateLimiter::new(&Metrics::new());
+        let client = "192.0.2.1".parse().unwrap();
+        let limit = RateLimit { rate: 0.5, burst: 2 };
+
+        assert!(limiter.check(client, "/basic", limit).is_ok());
+        assert!(limiter.check(client, "/basic", limit).is_ok());
+        let err = limiter.check(client, "/basic", limit).unwrap_err();
+        assert!(err.retry_after > Duration::from_secs(1) && err.retry_after <= Duration::from_secs(2));
+
+        // Other routes and clients have buckets of their own.
+        assert!(limiter.check(client, "/double", limit).is_ok());
+        assert!(limiter.check("192.0.2.2".parse().unwrap(), "/basic", limit).is_ok());
+    }
+
+    #[test]
+    fn test_expire() {
+        let limiter = RateLimiter::new(&Metrics::new());
+        let limit = RateLimit { rate: 100.0, burst: 1 };
+        for i in 0..100 {
+            let client = IpAddr::from([192, 0, 2, i]);
+            assert!(limiter.check(client, "/basic", limit).is_ok());
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), 100);
+
+        // They've all refilled within 10ms, so the next request drops them.
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.check("192.0.2.200".parse().unwrap(), "/basic", limit).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), 1);
+        assert_eq!(buckets.due.len(), 1);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let throttles = Throttles::new(&Metrics::new());
+        let policy = ThrottlePolicy {
+            limit: RateLimit { rate: 20.0, burst: 1 },
+            max_wait: Duration::from_millis(60),
+        };
+
+        let start = Instant::now();
+        rt.block_on(throttles.acquire("cats", Some(&policy))).unwrap();
+        assert!(!throttles.try_acquire("cats", Some(&policy)));
+        // The next token is refilled after 50ms.
+        let impatient = ThrottlePolicy { max_wait: Duration::from_millis(10), ..policy };
+        assert!(matches!(
+            rt.block_on(throttles.acquire("cats", Some(&impatient))),
+            Err(AppError::UpstreamThrottled("cats"))
+        ));
+        rt.block_on(throttles.acquire("cats", Some(&policy))).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(throttles.try_acquire("todo", Some(&policy)));
+    }
+
+    #[test]
+    fn test_throttle_cancelled() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let throttles = Throttles::new(&Metrics::new());
+        let policy = ThrottlePolicy {
+            limit: RateLimit { rate: 10.0, burst: 1 },
+            max_wait: Duration::from_millis(150),
+        };
+
+        rt.block_on(throttles.acquire("cats", Some(&policy))).unwrap();
+        // Reserves the token due in 100ms, but gives up on it after 10ms.
+        let cancelled = rt.block_on(async {
+            tokio::time::timeout(Duration::from_millis(10), throttles.acquire("cats", Some(&policy))).await
+        });
+        assert!(cancelled.is_err());
+        // So the next request gets that token rather than the one after it.
+        let start = Instant::now();
+        rt.block_on(throttles.acquire("cats", Some(&policy))).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(140), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_cool_down() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let throttles = Throttles::new(&Metrics::new());
+
+        throttles.cool_down("cats", Duration::from_millis(50));
+        assert!(rt.block_on(throttles.acquire("cats", None)).is_err());
+        assert!(!throttles.try_acquire("cats", None));
+        assert!(throttles.try_acquire("todo", None));
+        // A shorter cool-down doesn't cut the current one short.
+        throttles.cool_down("cats", Duration::from_millis(0));
+        assert!(!throttles.try_acquire("cats", None));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(rt.block_on(throttles.acquire("cats", None)).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, "198.51.100.7, 192.0.2.1, 10.0.0.2".parse().unwrap());
+
+        let proxy = "10.0.0.1".parse().unwrap();
+        assert_eq!(client_ip(proxy, &headers, &trusted), "192.0.2.1".parse::<IpAddr>().unwrap());
+        // Anyone else could have made the header up.
+        let other = "203.0.113.9".parse().unwrap();
+        assert_eq!(client_ip(other, &headers, &trusted), other);
+        assert_eq!(client_ip(proxy, &HeaderMap::new(), &trusted), proxy);
+    }
+}
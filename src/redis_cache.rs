@@ -0,0 +1,223 @@
+use crate::cache::{CacheEntry, CacheStore, Freshness, Validators};
+use crate::client::Fetched;
+use crate::config::CacheCfg;
+use crate::error::{AppError, BoxError};
+use crate::Result;
+use futures::future::BoxFuture;
+use hyper::StatusCode;
+use r2d2::Pool;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::spawn_blocking;
+use tracing::warn;
+
+/// Keeps the keys of different services sharing a Redis apart, and those of
+/// older versions of the encoding.
+const KEY_PREFIX: &str = "rust-mockito-example:v2:";
+
+/// Keeps responses in Redis so that all instances share them. Redis being
+/// unavailable only costs the cache: lookups miss and inserts are dropped.
+pub struct RedisStore {
+    pool: Pool<redis::Client>,
+}
+
+impl RedisStore {
+    pub fn new(cfg: &CacheCfg) -> Result<RedisStore> {
+        let client = redis::Client::open(cfg.redis_url.expose())
+            .map_err(|err| AppError::Internal(err.into()))?;
+        // Unchecked so that the service starts while Redis is down.
+        let pool = Pool::builder()
+            .max_size(cfg.redis_pool_size)
+            .connection_timeout(Duration::from_millis(cfg.redis_timeout_ms))
+            .build_unchecked(client);
+        Ok(RedisStore { pool })
+    }
+
+    /// Runs `f` on a pooled connection on the blocking thread pool, since the
+    /// client is synchronous.
+    async fn with_conn<T, F>(&self, f: F) -> std::result::Result<T, BoxError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut redis::Connection) -> redis::RedisResult<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            Ok(f(&mut conn)?)
+        }).await?
+    }
+}
+
+impl CacheStore for RedisStore {
+    fn get<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, Option<Fetched>> {
+        let key = format!("{}{}", KEY_PREFIX, uri);
+        Box::pin(async move {
+            let value = self.with_conn(move |conn| {
+                redis::cmd("GET").arg(key).query::<Option<Vec<u8>>>(conn)
+            }).await;
+            match value {
+                Ok(value) => decode(&value?).map(|(_, fetched)| fetched),
+                Err(err) => {
+                    warn!(%err, "cache lookup in redis failed");
+                    None
+                }
+            }
+        })
+    }
+
+    fn insert<'a>(&'a self, uri: &'a str, fetched: Fetched, ttl: Duration) -> BoxFuture<'a, ()> {
+        let key = format!("{}{}", KEY_PREFIX, uri);
+        Box::pin(async move {
+            let res = self.with_conn(move |conn| {
+                redis::cmd("SET").arg(key).arg(encode(&fetched, unix_millis()))
+                    .arg("PX").arg(ttl.as_millis() as u64)
+                    .query::<()>(conn)
+            }).await;
+            if let Err(err) = res {
+                warn!(%err, "cache insert into redis failed");
+            }
+        })
+    }
+
+    fn entries(&self) -> BoxFuture<'_, Vec<CacheEntry>> {
+        Box::pin(async move {
+            let entries = self.with_conn(|conn| {
+                let keys = scan_keys(conn)?;
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    pipe.cmd("GET").arg(key).cmd("PTTL").arg(key);
+                }
+                let values: Vec<(Option<Vec<u8>>, i64)> = pipe.query(conn)?;
+                Ok(keys.into_iter().zip(values).collect::<Vec<_>>())
+            }).await;
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!(%err, "listing the cache in redis failed");
+                    return Vec::new();
+                }
+            };
+            let now = unix_millis();
+            entries.into_iter()
+                .filter_map(|(key, (value, pttl))| {
+                    // Keys that expired since the scan have no value.
+                    let (inserted_at, _) = decode(&value?)?;
+                    Some(CacheEntry {
+                        key: key[KEY_PREFIX.len()..].to_owned(),
+                        age_ms: now.saturating_sub(inserted_at),
+                        expires_in_ms: pttl.max(0) as u64,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn remove<'a>(&'a self, uri: &'a str) -> BoxFuture<'a, bool> {
+        let key = format!("{}{}", KEY_PREFIX, uri);
+        Box::pin(async move {
+            let removed = self.with_conn(move |conn| redis::cmd("DEL").arg(key).query::<u64>(conn)).await;
+            match removed {
+                Ok(removed) => removed > 0,
+                Err(err) => {
+                    warn!(%err, "cache removal from redis failed");
+                    false
+                }
+            }
+        })
+    }
+
+    fn clear(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            // Only this service's keys, rather than FLUSHDB.
+            let res = self.with_conn(|conn| {
+                let keys = scan_keys(conn)?;
+                if !keys.is_empty() {
+                    redis::cmd("DEL").arg(keys).query::<()>(conn)?;
+                }
+                Ok(())
+            }).await;
+            if let Err(err) = res {
+                warn!(%err, "clearing the cache in redis failed");
+            }
+        })
+    }
+}
+
+fn scan_keys(conn: &mut redis::Connection) -> redis::RedisResult<Vec<String>> {
+    let pattern = format!("{}*", KEY_PREFIX);
+    let keys = redis::cmd("SCAN").cursor_arg(0).arg("MATCH").arg(pattern).clone().iter(conn)?.collect();
+    Ok(keys)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// The status code in two bytes and the time of insertion in milliseconds
+/// since the epoch in eight, both big endian, followed by the body.
+fn encode(fetched: &Fetched, inserted_at: u64) -> Vec<u8> {
+    let mut value = fetched.status.as_u16().to_be_bytes().to_vec();
+    value.extend_from_slice(&inserted_at.to_be_bytes());
+    value.extend_from_slice(&fetched.body);
+    value
+}
+
+fn decode(value: &[u8]) -> Option<(u64, Fetched)> {
+    if value.len() < 10 {
+        return None;
+    }
+    let status = StatusCode::from_u16(u16::from_be_bytes([value[0], value[1]])).ok()?;
+    let mut inserted_at = [0; 8];
+    inserted_at.copy_from_slice(&value[2..10]);
+    let fetched = Fetched {
+        status,
+        body: value[10..].to_vec().into(),
+        freshness: Freshness::Unspecified,
+        validators: Validators::default(),
+    };
+    Some((u64::from_be_bytes(inserted_at), fetched))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::Secret;
+    use std::time::Instant;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_encoding() {
+        let fetched = Fetched {
+            status: StatusCode::OK,
+            body: r#"{"title":"x"}"#.into(),
+            freshness: Freshness::Unspecified,
+            validators: Validators::default(),
+        };
+        let (inserted_at, decoded) = decode(&encode(&fetched, 1_600_000_000_000)).unwrap();
+        assert_eq!(inserted_at, 1_600_000_000_000);
+        assert_eq!(decoded.status, StatusCode::OK);
+        assert_eq!(decoded.body, fetched.body);
+    }
+
+    #[test]
+    fn test_unavailable() {
+        let mut rt = Runtime::new().unwrap();
+        let cfg = CacheCfg {
+            redis_url: Secret::from("redis://127.0.0.1:1/"),
+            ..CacheCfg::default()
+        };
+        let store = RedisStore::new(&cfg).unwrap();
+        let fetched = Fetched {
+            status: StatusCode::OK,
+            body: "{}".into(),
+            freshness: Freshness::Unspecified,
+            validators: Validators::default(),
+        };
+
+        let start = Instant::now();
+        rt.block_on(store.insert("http://todo/todos/1", fetched, Duration::from_secs(60)));
+        assert!(rt.block_on(store.get("http://todo/todos/1")).is_none());
+        assert!(rt.block_on(store.entries()).is_empty());
+        assert!(!rt.block_on(store.remove("http://todo/todos/1")));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
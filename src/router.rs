@@ -0,0 +1,330 @@
+use crate::config::ServerCfg;
+use crate::error::AppError;
+use crate::state::AppState;
+use crate::Result;
+use futures::future::BoxFuture;
+use hyper::{Body, Method, Request, Response};
+use std::sync::Arc;
+
+pub type Handler = fn(Request<Body>, Arc<AppState>, Arc<ServerCfg>) -> BoxFuture<'static, Result<Response<Body>>>;
+
+/// Finds the route for a path. Routes are matched in the order they were
+/// added, so more specific ones go first.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+/// A path pattern and the handlers for the methods it answers. `{name}`
+/// matches a single non-empty segment, and a final `{*name}` the non-empty
+/// rest of the path.
+pub struct Route {
+    pattern: &'static str,
+    segments: Vec<Segment>,
+    handlers: Vec<(Method, Handler)>,
+    /// Handles the methods there's no handler for.
+    any: Option<Handler>,
+    admin: bool,
+}
+
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+    Rest(&'static str),
+}
+
+/// The values of a route's parameters in the request path, added to the
+/// request's extensions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(param, _)| *param == name).map(|(_, value)| value.as_str())
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route(mut self, route: Route) -> Router {
+        self.routes.push(route);
+        self
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// The first route matching `path` and its parameters.
+    pub fn at(&self, path: &str) -> Option<(&Route, Params)> {
+        self.routes.iter().find_map(|route| route.matches(path).map(|params| (route, params)))
+    }
+
+    /// The normalized form of `path`, if it isn't normalized and a route
+    /// matches it. Paths matching a route with a `{*name}` as they are are
+    /// left alone, since the rest may be passed on verbatim.
+    pub fn normalized(&self, path: &str) -> Option<String> {
+        if self.at(path).is_some_and(|(route, _)| route.has_rest()) {
+            return None;
+        }
+        normalize(path).filter(|normalized| self.at(normalized).is_some())
+    }
+}
+
+/// `path` without empty segments, from duplicate or trailing slashes, and
+/// with dot segments resolved and percent-encoded unreserved characters
+/// decoded, or `None` if that's what it is already. Other percent-encoded
+/// bytes, like `%2F`, stay encoded, in upper case, so decoding never moves
+/// where a segment ends.
+pub fn normalize(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = decode_unreserved(segment);
+        match segment.as_str() {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let normalized = format!("/{}", segments.join("/"));
+    if normalized == path {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+fn decode_unreserved(segment: &str) -> String {
+    let mut decoded = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while let Some(percent) = rest.find('%') {
+        decoded.push_str(&rest[..percent]);
+        let escaped = rest.get(percent + 1..percent + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => decoded.push(byte as char),
+            Some(byte) => decoded.push_str(&format!("%{:02X}", byte)),
+            None => {
+                decoded.push('%');
+                rest = &rest[percent + 1..];
+                continue;
+            }
+        }
+        rest = &rest[percent + 3..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+impl Route {
+    pub fn new(pattern: &'static str) -> Route {
+        let segments = pattern.trim_start_matches('/').split('/').map(|segment| {
+            match segment.strip_prefix('{').and_then(|param| param.strip_suffix('}')) {
+                Some(param) => match param.strip_prefix('*') {
+                    Some(rest) => Segment::Rest(rest),
+                    None => Segment::Param(param),
+                },
+                None => Segment::Literal(segment),
+            }
+        }).collect::<Vec<_>>();
+        let rest = segments.iter().position(|segment| matches!(segment, Segment::Rest(_)));
+        assert!(rest.is_none_or(|rest| rest == segments.len() - 1), "{}: {{*...}} must come last", pattern);
+        Route { pattern, segments, handlers: Vec::new(), any: None, admin: false }
+    }
+
+    pub fn get(self, handler: Handler) -> Route {
+        self.method(Method::GET, handler)
+    }
+
+    pub fn post(self, handler: Handler) -> Route {
+        self.method(Method::POST, handler)
+    }
+
+    pub fn put(self, handler: Handler) -> Route {
+        self.method(Method::PUT, handler)
+    }
+
+    pub fn patch(self, handler: Handler) -> Route {
+        self.method(Method::PATCH, handler)
+    }
+
+    pub fn delete(self, handler: Handler) -> Route {
+        self.method(Method::DELETE, handler)
+    }
+
+    pub fn method(mut self, method: Method, handler: Handler) -> Route {
+        self.handlers.push((method, handler));
+        self
+    }
+
+    /// Handles every method not handled otherwise.
+    pub fn any(mut self, handler: Handler) -> Route {
+        self.any = Some(handler);
+        self
+    }
+
+    /// Requests to the route need the admin credentials.
+    pub fn admin(mut self) -> Route {
+        self.admin = true;
+        self
+    }
+
+    /// Identifies the route in metrics and in the config, e.g.
+    /// `/todos/{id}`.
+    pub fn pattern(&self) -> &'static str {
+        self.pattern
+    }
+
+    /// The methods there are handlers for, apart from `any`.
+    pub fn methods(&self) -> impl Iterator<Item = &Method> {
+        self.handlers.iter().map(|(method, _)| method)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    /// Whether the pattern ends with a `{*name}`.
+    pub fn has_rest(&self) -> bool {
+        matches!(self.segments.last(), Some(Segment::Rest(_)))
+    }
+
+    /// The handler for `method`, or a 405 listing the methods there are
+    /// handlers for if the route doesn't handle any method. `HEAD` goes to
+    /// the `GET` handler unless there's one for it.
+    pub fn handler(&self, method: &Method) -> Result<Handler> {
+        let find = |method: &Method| self.handlers.iter().find(|(handled, _)| handled == method).map(|(_, handler)| *handler);
+        let handler = find(method).or_else(|| if method == Method::HEAD { find(&Method::GET) } else { None });
+        match handler.or(self.any) {
+            Some(handler) => Ok(handler),
+            None => Err(AppError::MethodNotAllowed(self.allowed())),
+        }
+    }
+
+    /// The methods the route answers: those there are handlers for, with
+    /// `HEAD` after `GET`, and `OPTIONS`, which the server answers with
+    /// these unless there's a handler for it.
+    pub fn allowed(&self) -> Vec<Method> {
+        let mut allowed = Vec::new();
+        for method in self.methods() {
+            allowed.push(method.clone());
+            if method == Method::GET && !self.handles(&Method::HEAD) {
+                allowed.push(Method::HEAD);
+            }
+        }
+        if !self.handles(&Method::OPTIONS) {
+            allowed.push(Method::OPTIONS);
+        }
+        allowed
+    }
+
+    fn handles(&self, method: &Method) -> bool {
+        self.handlers.iter().any(|(handled, _)| handled == method)
+    }
+
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Vec::new();
+        // `None` once the path has no segments left.
+        let mut rest = Some(path.strip_prefix('/')?);
+        for segment in &self.segments {
+            let path = rest?;
+            let (current, remaining) = match path.find('/') {
+                Some(end) => (&path[..end], Some(&path[end + 1..])),
+                None => (path, None),
+            };
+            match segment {
+                Segment::Literal(literal) if *literal == current => {}
+                Segment::Param(name) if !current.is_empty() => params.push((*name, current.to_owned())),
+                Segment::Rest(name) if !path.is_empty() => {
+                    params.push((*name, path.to_owned()));
+                    return Some(Params(params));
+                }
+                _ => return None,
+            }
+            rest = remaining;
+        }
+        match rest {
+            Some(_) => None,
+            None => Some(Params(params)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::FutureExt;
+
+    fn ok(_: Request<Body>, _: Arc<AppState>, _: Arc<ServerCfg>) -> BoxFuture<'static, Result<Response<Body>>> {
+        async { Ok(Response::new(Body::empty())) }.boxed()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .route(Route::new("/basic").get(ok))
+            .route(Route::new("/todos/{id}").get(ok))
+            .route(Route::new("/admin/cache").get(ok).delete(ok).admin())
+            .route(Route::new("/admin/cache/{*key}").delete(ok).admin())
+            .route(Route::new("/proxy/{upstream}/{*path}").any(ok))
+    }
+
+    fn at(path: &str) -> Option<(&'static str, Params)> {
+        router().at(path).map(|(route, params)| (route.pattern(), params))
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eq!(at("/basic"), Some(("/basic", Params::default())));
+        let (pattern, params) = at("/todos/5").unwrap();
+        assert_eq!(pattern, "/todos/{id}");
+        assert_eq!(params.get("id"), Some("5"));
+        let (pattern, params) = at("/admin/cache/a/b%2Fc").unwrap();
+        assert_eq!(pattern, "/admin/cache/{*key}");
+        assert_eq!(params.get("key"), Some("a/b%2Fc"));
+        assert_eq!(at("/admin/cache").unwrap().0, "/admin/cache");
+
+        for path in &["", "/", "/basic/", "/basicx", "/todos", "/todos/", "/todos/5/comments", "/admin/cache/"] {
+            assert!(at(path).is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/basic"), None);
+        assert_eq!(normalize("/"), None);
+        assert_eq!(normalize("/basic/").as_deref(), Some("/basic"));
+        assert_eq!(normalize("//todos///5").as_deref(), Some("/todos/5"));
+        assert_eq!(normalize("/todos/./6/../%35").as_deref(), Some("/todos/5"));
+        assert_eq!(normalize("/a/%7e%2f%2Fb%zz%").as_deref(), Some("/a/~%2F%2Fb%zz%"));
+        assert_eq!(normalize("/..").as_deref(), Some("/"));
+
+        let router = router();
+        assert_eq!(router.normalized("/basic/").as_deref(), Some("/basic"));
+        assert_eq!(router.normalized("/nope/"), None);
+        assert_eq!(router.normalized("/admin/cache/a//b/"), None);
+    }
+
+    #[test]
+    fn test_methods() {
+        let router = router();
+        let (route, _) = router.at("/admin/cache").unwrap();
+        assert!(route.is_admin());
+        assert!(route.handler(&Method::DELETE).is_ok());
+        match route.handler(&Method::POST) {
+            Err(AppError::MethodNotAllowed(allowed)) => {
+                assert_eq!(allowed, vec![Method::GET, Method::HEAD, Method::DELETE, Method::OPTIONS])
+            }
+            _ => panic!("POST should not be allowed"),
+        }
+        assert!(route.handler(&Method::HEAD).is_ok());
+        let (route, _) = router.at("/admin/cache/key").unwrap();
+        assert!(route.handler(&Method::HEAD).is_err());
+        assert!(!router.at("/basic").unwrap().0.is_admin());
+        let (route, _) = router.at("/proxy/todo/todos").unwrap();
+        assert!(route.handler(&Method::PATCH).is_ok());
+    }
+}
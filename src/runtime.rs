@@ -0,0 +1,41 @@
+//! The Tokio runtime the server runs on, sized by `[runtime]` rather than
+//! `#[tokio::main]`'s defaults, e.g. for small containers or benchmarks.
+
+use crate::config::RuntimeCfg;
+use std::io;
+use tokio::runtime::{Builder, Runtime};
+
+pub fn build(cfg: &RuntimeCfg) -> io::Result<Runtime> {
+    let workers = match cfg.worker_threads {
+        0 => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+        workers => workers,
+    };
+    Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .core_threads(workers)
+        // Counts the workers too.
+        .max_threads(workers + cfg.max_blocking_threads)
+        .thread_name(cfg.thread_name.as_str())
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let cfg = RuntimeCfg { worker_threads: 2, thread_name: "test-worker".to_owned(), ..RuntimeCfg::default() };
+        let mut rt = build(&cfg).unwrap();
+
+        let name = rt.block_on(async {
+            tokio::spawn(async { std::thread::current().name().map(str::to_owned) }).await.unwrap()
+        });
+        assert_eq!(name.as_deref(), Some("test-worker"));
+        let name = rt.block_on(async {
+            tokio::task::spawn_blocking(|| std::thread::current().name().map(str::to_owned)).await.unwrap()
+        });
+        assert_eq!(name.as_deref(), Some("test-worker"));
+    }
+}
@@ -0,0 +1,108 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// A credential from the config, like an API key or a client secret. It's
+/// never shown by `Debug`, so that logging a config doesn't leak it.
+///
+/// In the config it's either the value itself or where to read it from:
+///
+/// ```toml
+/// hs256_secret = { env = "JWT_SECRET" }
+/// oauth_client_secret = { file = "/run/secrets/todo_client_secret" }
+/// ```
+///
+/// Files are read as they are, apart from trailing newlines.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Secret {
+        Secret(value.into())
+    }
+
+    /// The value itself, for where it's sent or compared.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Secret {
+        Secret::new(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret([redacted])")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Source {
+    Value(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+        let source = Source::deserialize(deserializer).map_err(|_: D::Error| {
+            de::Error::custom("expected a string, { env = \"...\" } or { file = \"...\" }")
+        })?;
+        match source {
+            Source::Value(value) => Ok(Secret(value)),
+            Source::Env { env } => env::var(&env)
+                .map(Secret)
+                .map_err(|err| de::Error::custom(format!("environment variable {}: {}", env, err))),
+            Source::File { file } => fs::read_to_string(&file)
+                .map(|value| Secret(value.trim_end_matches(&['\r', '\n'][..]).to_owned()))
+                .map_err(|err| de::Error::custom(format!("reading {}: {}", file.display(), err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[derive(Debug, Deserialize)]
+    struct Section {
+        secret: Secret,
+    }
+
+    fn parse(toml: &str) -> Result<Secret, toml::de::Error> {
+        toml::from_str::<Section>(toml).map(|section| section.secret)
+    }
+
+    #[test]
+    fn test_sources() {
+        assert_eq!(parse(r#"secret = "s3cret""#).unwrap().expose(), "s3cret");
+
+        env::set_var("SECRET_TEST_SOURCES", "from-env");
+        assert_eq!(parse(r#"secret = { env = "SECRET_TEST_SOURCES" }"#).unwrap().expose(), "from-env");
+        assert!(parse(r#"secret = { env = "SECRET_TEST_UNSET" }"#).is_err());
+
+        let path = env::temp_dir().join(format!("secret-test-{}", std::process::id()));
+        fs::File::create(&path).unwrap().write_all(b"from-file\n").unwrap();
+        let res = parse(&format!("secret = {{ file = {:?} }}", path));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap().expose(), "from-file");
+        assert!(parse(r#"secret = { file = "testdata/missing" }"#).is_err());
+        assert!(parse(r#"secret = { vault = "todo" }"#).is_err());
+    }
+
+    #[test]
+    fn test_redacted() {
+        let section = parse(r#"secret = "s3cret""#).map(|secret| Section { secret }).unwrap();
+        let debug = format!("{:?}", section);
+        assert!(!debug.contains("s3cret"), "{}", debug);
+        assert!(debug.contains("[redacted]"), "{}", debug);
+    }
+}
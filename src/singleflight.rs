@@ -0,0 +1,104 @@
+use futures::channel::oneshot;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+/// Coalesces concurrent calls with the same key: the first one runs, the
+/// ones arriving while it's in flight wait for and share its result.
+pub struct Singleflight<K, V> {
+    calls: Mutex<HashMap<K, Vec<oneshot::Sender<V>>>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Singleflight<K, V> {
+    pub fn new() -> Singleflight<K, V> {
+        Singleflight { calls: Mutex::new(HashMap::new()) }
+    }
+
+    /// Runs `fut` unless a call for `key` is already in flight, in which
+    /// case its result is returned instead. Also returns whether the result
+    /// was shared. If the call in flight is dropped before it finishes, `fut`
+    /// is run after all.
+    pub async fn run<F: Future<Output = V>>(&self, key: K, fut: F) -> (V, bool) {
+        let waiting = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get_mut(&key) {
+                Some(waiters) => {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    Some(rx)
+                }
+                None => {
+                    calls.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(rx) = waiting {
+            return match rx.await {
+                Ok(value) => (value, true),
+                Err(oneshot::Canceled) => (fut.await, false),
+            };
+        }
+
+        let mut leader = Leader { calls: &self.calls, key: Some(key) };
+        let value = fut.await;
+        for waiter in leader.finish() {
+            let _ = waiter.send(value.clone());
+        }
+        (value, false)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Default for Singleflight<K, V> {
+    fn default() -> Singleflight<K, V> {
+        Singleflight::new()
+    }
+}
+
+/// Unregisters the call in flight, also when it's dropped before finishing
+/// so the waiters don't wait forever.
+struct Leader<'a, K: Eq + Hash, V> {
+    calls: &'a Mutex<HashMap<K, Vec<oneshot::Sender<V>>>>,
+    key: Option<K>,
+}
+
+impl<K: Eq + Hash, V> Leader<'_, K, V> {
+    fn finish(&mut self) -> Vec<oneshot::Sender<V>> {
+        let key = self.key.take().unwrap();
+        self.calls.lock().unwrap().remove(&key).unwrap_or_default()
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.calls.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+    use tokio::time::delay_for;
+
+    #[test]
+    fn test_coalesces() {
+        let mut rt = Runtime::new().unwrap();
+        let group = Singleflight::new();
+        let slow = async {
+            delay_for(Duration::from_millis(20)).await;
+            1
+        };
+        let (first, second) = rt.block_on(join(group.run("todo", slow), group.run("todo", async { 2 })));
+        assert_eq!(first, (1, false));
+        assert_eq!(second, (1, true));
+
+        // Nothing's in flight anymore.
+        assert_eq!(rt.block_on(group.run("todo", async { 3 })), (3, false));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::breaker::Breakers;
+use crate::cache::{new_cache, ResponseCache, Revalidations};
+use crate::client::{init_client, init_upstream_client, HttpClient, InFlight};
+use crate::config::{Http2, ServerCfg, UpstreamCfg};
+use crate::fact_log::FactLog;
+use crate::graphql::{self, Schema};
+use crate::handlers::Readiness;
+use crate::i18n::Catalogs;
+use crate::jwt::JwtVerifier;
+use crate::metrics::Metrics;
+use crate::oauth::TokenManager;
+#[cfg(feature = "wasm-plugins")]
+use crate::plugin::Plugin;
+use crate::rate_limit::{RateLimiter, Throttles};
+use crate::router::Router;
+use crate::server::routes;
+use crate::singleflight::Singleflight;
+use crate::vault::VaultCredentials;
+use crate::webhooks::Webhooks;
+use crate::ws;
+use crate::AppError;
+use crate::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{broadcast, watch};
+
+/// How many events a slow `/facts/stream` client can fall behind before it
+/// misses some.
+const FACT_EVENTS_CAPACITY: usize = 64;
+
+/// State shared by all requests that, unlike `ServerCfg`, survives a config
+/// reload.
+pub struct AppState {
+    pub client: HttpClient,
+    /// For the upstreams with TLS settings of their own or speaking HTTP/2,
+    /// which are only read at startup.
+    pub upstream_clients: HashMap<&'static str, HttpClient>,
+    pub started_at: Instant,
+    pub metrics: Metrics,
+    pub breakers: Breakers,
+    pub rate_limiter: RateLimiter,
+    pub throttles: Throttles,
+    pub in_flight: InFlight,
+    pub jwt: JwtVerifier,
+    /// OAuth2 tokens for the upstreams that need one.
+    pub tokens: TokenManager,
+    /// Credentials from Vault for the upstreams that need one.
+    pub vault: VaultCredentials,
+    pub cache: ResponseCache,
+    /// The expired responses that can be refreshed with a conditional
+    /// request.
+    pub revalidations: Revalidations,
+    /// Only loaded at startup, like the cache.
+    #[cfg(feature = "wasm-plugins")]
+    pub plugin: Option<Plugin>,
+    /// Only loaded at startup; empty without `i18n.catalogs`.
+    pub catalogs: Catalogs,
+    /// The last readiness check and when it was made.
+    pub readiness: Mutex<Option<(Instant, Readiness)>>,
+    pub last_good: LastGood,
+    pub router: Router,
+    /// The updates pushed to the clients of `/ws`, as JSON.
+    pub updates: broadcast::Sender<String>,
+    /// The events of `/facts/stream`, sent to all its clients.
+    pub fact_events: broadcast::Sender<String>,
+    /// The latest cat facts, for `/facts/next`.
+    pub fact_log: FactLog,
+    /// Coalesces the facts `/facts/next` fetches itself, so clients waiting
+    /// together fetch one between them.
+    pub fact_polls: Singleflight<(), std::result::Result<(), Arc<AppError>>>,
+    /// Only loaded at startup, like the catalogs.
+    pub webhooks: Webhooks,
+    pub graphql: Schema,
+    /// Set once the server shuts down, to end responses that would otherwise
+    /// go on forever, like `/facts/stream`.
+    draining: (watch::Sender<bool>, watch::Receiver<bool>),
+}
+
+/// The last values fetched successfully from the upstreams, served instead of
+/// an error when a live fetch fails.
+#[derive(Default)]
+pub struct LastGood {
+    pub todo_title: Mutex<Option<String>>,
+    pub cat_fact: Mutex<Option<String>>,
+}
+
+impl AppState {
+    pub fn new(cfg: &ServerCfg) -> Result<AppState> {
+        let metrics = Metrics::new();
+        let mut upstream_clients = HashMap::new();
+        for upstream in [&cfg.cats, &cfg.todo].iter().copied().chain(cfg.sources.iter().map(|source| &source.upstream)) {
+            if upstream.tls.is_some() || upstream.http2 != Http2::Off {
+                let client = init_upstream_client(&metrics, &cfg.client, upstream.tls.as_ref(), upstream.http2)?;
+                upstream_clients.insert(upstream.name, client);
+            }
+        }
+        Ok(AppState {
+            client: init_client(&metrics, &cfg.client)?,
+            upstream_clients,
+            started_at: Instant::now(),
+            breakers: Breakers::new(&metrics),
+            rate_limiter: RateLimiter::new(&metrics),
+            throttles: Throttles::new(&metrics),
+            in_flight: InFlight::new(),
+            jwt: JwtVerifier::new(),
+            tokens: TokenManager::new(&metrics),
+            vault: VaultCredentials::new(&metrics),
+            cache: new_cache(&cfg.cache)?,
+            revalidations: Revalidations::new(&cfg.cache),
+            #[cfg(feature = "wasm-plugins")]
+            plugin: cfg.plugin.as_ref().map(Plugin::load).transpose().map_err(AppError::Internal)?,
+            catalogs: match &cfg.catalogs {
+                Some(dir) => Catalogs::load(dir).map_err(AppError::Internal)?,
+                None => Catalogs::default(),
+            },
+            graphql: graphql::schema(&cfg.graphql),
+            webhooks: Webhooks::new(&cfg.webhooks, &metrics).map_err(AppError::Internal)?,
+            metrics,
+            readiness: Mutex::new(None),
+            last_good: LastGood::default(),
+            router: routes(),
+            updates: ws::channel(),
+            fact_events: broadcast::channel(FACT_EVENTS_CAPACITY).0,
+            fact_log: FactLog::new(),
+            fact_polls: Singleflight::new(),
+            draining: watch::channel(false),
+        })
+    }
+
+    /// Ends the responses waiting on `drained`.
+    pub fn drain(&self) {
+        let _ = self.draining.0.broadcast(true);
+    }
+
+    /// Resolves once the server starts shutting down.
+    pub fn drained(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut draining = self.draining.1.clone();
+        async move {
+            while let Some(false) = draining.recv().await {}
+        }
+    }
+
+    /// The client requests to `upstream` are sent with.
+    pub fn upstream_client(&self, upstream: &UpstreamCfg) -> &HttpClient {
+        self.upstream_clients.get(upstream.name).unwrap_or(&self.client)
+    }
+}
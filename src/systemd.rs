@@ -0,0 +1,145 @@
+//! Socket activation: systemd binds the sockets of a socket unit and passes
+//! them to the server when it starts it. They stay open while the server
+//! restarts, so connections made in the meantime wait in their backlog
+//! rather than being refused.
+
+use crate::listener::Bound;
+use socket2::{Domain, Socket, Type};
+use std::env;
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+/// The first file descriptor passed; the others follow it.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The stream sockets systemd passed to this process, in the order of the
+/// socket unit's `Listen` lines; none if it didn't start the server. Like
+/// `sd_listen_fds(1)`, unsets the variables saying so, so that the processes
+/// the server starts don't take them as their own, and keeps the sockets from
+/// being inherited by them.
+pub fn listeners() -> io::Result<Vec<Bound>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    let fds = passed(pid.as_deref(), fds.as_deref(), std::process::id())?;
+    // Safe as systemd hands them over for this process to own, which
+    // LISTEN_PID makes sure it is.
+    fds.map(|fd| unsafe { adopt(fd) }).collect()
+}
+
+/// The file descriptors passed, if they're meant for the process `pid`
+/// rather than a parent that inherited the variables to it.
+fn passed(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> io::Result<Range<RawFd>> {
+    let invalid = |name: &str, value: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {:?} is not a number", name, value));
+    let listen_pid = match listen_pid {
+        Some(value) => value.parse::<u32>().map_err(|_| invalid("LISTEN_PID", value))?,
+        None => return Ok(0..0),
+    };
+    if listen_pid != pid {
+        return Ok(0..0);
+    }
+    let listen_fds = match listen_fds {
+        Some(value) => value.parse::<RawFd>().map_err(|_| invalid("LISTEN_FDS", value))?,
+        None => return Ok(0..0),
+    };
+    Ok(LISTEN_FDS_START..LISTEN_FDS_START + listen_fds.max(0))
+}
+
+/// The listening socket `fd`, which is TCP if it has an internet address and
+/// a Unix socket otherwise. It's closed on exec from then on, and anything
+/// but a listening stream socket is refused, as a misconfigured socket unit
+/// may pass datagram or not yet listening ones.
+///
+/// # Safety
+///
+/// `fd` must be a socket nothing else owns.
+unsafe fn adopt(fd: RawFd) -> io::Result<Bound> {
+    let socket = Socket::from_raw_fd(fd);
+    socket.set_cloexec(true)?;
+    if socket.r#type()? != Type::STREAM || !socket.is_listener()? {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("file descriptor {} isn't a listening stream socket", fd)));
+    }
+    Ok(match socket.domain()? {
+        Domain::UNIX => Bound::Unix(UnixListener::from_raw_fd(socket.into_raw_fd())),
+        _ => Bound::Tcp(std::net::TcpListener::from_raw_fd(socket.into_raw_fd())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    /// Whether `fd` is closed on exec, as the kernel reports (in octal).
+    fn cloexec(fd: RawFd) -> bool {
+        let info = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd)).unwrap();
+        let flags = info.lines().find_map(|line| line.strip_prefix("flags:")).unwrap();
+        u32::from_str_radix(flags.trim(), 8).unwrap() & 0o2000000 != 0
+    }
+
+    #[test]
+    fn test_passed() {
+        assert_eq!(passed(None, None, 42).unwrap(), 0..0);
+        assert_eq!(passed(Some("42"), Some("2"), 42).unwrap(), 3..5);
+        // They're meant for another process.
+        assert_eq!(passed(Some("41"), Some("2"), 42).unwrap(), 0..0);
+        assert!(passed(Some("42"), Some("two"), 42).is_err());
+    }
+
+    #[test]
+    fn test_adopt() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        // As passed by systemd.
+        socket2::SockRef::from(&tcp).set_cloexec(false).unwrap();
+        match unsafe { adopt(tcp.into_raw_fd()) }.unwrap() {
+            Bound::Tcp(listener) => {
+                assert_eq!(listener.local_addr().unwrap(), addr);
+                assert!(cloexec(listener.as_raw_fd()));
+            }
+            Bound::Unix(_) => panic!("the TCP socket was adopted as a Unix one"),
+        }
+
+        let path = env::temp_dir().join(format!("systemd-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        match unsafe { adopt(unix.into_raw_fd()) }.unwrap() {
+            Bound::Unix(listener) => assert_eq!(listener.local_addr().unwrap().as_pathname(), Some(path.as_path())),
+            Bound::Tcp(_) => panic!("the Unix socket was adopted as a TCP one"),
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // Neither datagram sockets nor stream ones that aren't listening.
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(unsafe { adopt(udp.into_raw_fd()) }.is_err());
+        let unbound = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        assert!(unsafe { adopt(unbound.into_raw_fd()) }.is_err());
+    }
+
+    #[test]
+    fn test_listeners_unsets_variables() {
+        // Run again in a process of its own with the variables set, since the
+        // other tests share this one's environment.
+        if env::var_os("SYSTEMD_TEST_CHILD").is_none() {
+            let output = std::process::Command::new(env::current_exe().unwrap())
+                .args(["systemd::tests::test_listeners_unsets_variables", "--exact"])
+                .env("SYSTEMD_TEST_CHILD", "1")
+                .env("LISTEN_PID", "1")
+                .env("LISTEN_FDS", "1")
+                .env("LISTEN_FDNAMES", "http")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success() && stdout.contains("1 passed"), "{}", stdout);
+            return;
+        }
+        assert!(listeners().unwrap().is_empty());
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            assert!(env::var_os(name).is_none(), "{}", name);
+        }
+    }
+}
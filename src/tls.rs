@@ -0,0 +1,425 @@
+use crate::config::{Http2, TlsCfg, UpstreamTlsCfg};
+use crate::egress::{ProxyConnector, ProxyStream};
+use crate::error::BoxError;
+use futures::future::BoxFuture;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use native_tls::Identity;
+use openssl_probe::ProbeResult;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore, ServerConfig, Session};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+
+/// Loads the certificate chain and key the server presents. Both are only
+/// read at startup.
+pub fn acceptor(cfg: &TlsCfg) -> io::Result<TlsAcceptor> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs(&cfg.cert)?, private_key(&cfg.key)?)
+        .map_err(|err| invalid(&cfg.cert, err))?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The connector for an upstream with TLS settings of its own, or that
+/// speaks HTTP/2, over `http`. Only read at startup.
+pub fn upstream_connector(cfg: Option<&UpstreamTlsCfg>, http2: Http2, http: ProxyConnector) -> io::Result<UpstreamConnector> {
+    match http2 {
+        Http2::Off => native_connector(cfg, http),
+        Http2::Alpn => Ok(UpstreamConnector::Alpn(http, Arc::new(alpn_connector(cfg, &[b"h2", b"http/1.1"])?))),
+        Http2::PriorKnowledge => Ok(UpstreamConnector::Alpn(http, Arc::new(alpn_connector(cfg, &[b"h2"])?))),
+    }
+}
+
+fn native_connector(cfg: Option<&UpstreamTlsCfg>, http: ProxyConnector) -> io::Result<UpstreamConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some((cert, key)) = cfg.and_then(|cfg| cfg.identity.as_ref()) {
+        let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+            .map_err(|err| invalid(key, err))?;
+        builder.identity(identity);
+    }
+    if let Some(path) = cfg.and_then(|cfg| cfg.ca_bundle.as_ref()) {
+        builder.disable_built_in_roots(true);
+        for cert in certs(path)? {
+            let cert = native_tls::Certificate::from_der(&cert.0).map_err(|err| invalid(path, err))?;
+            builder.add_root_certificate(cert);
+        }
+    }
+    let tls = builder.build().map_err(io::Error::other)?;
+    Ok(UpstreamConnector::Native(HttpsConnector::from((http, tls.into()))))
+}
+
+/// A rustls connector offering `protocols`, which unlike native-tls tells
+/// which one the upstream picked. It trusts the CAs the system's OpenSSL
+/// does unless `cfg` has a bundle.
+fn alpn_connector(cfg: Option<&UpstreamTlsCfg>, protocols: &[&[u8]]) -> io::Result<AlpnConnector> {
+    let mut config = ClientConfig::new();
+    if let Some((cert, key)) = cfg.and_then(|cfg| cfg.identity.as_ref()) {
+        config.set_single_client_cert(certs(cert)?, private_key(key)?)
+            .map_err(|err| invalid(key, err))?;
+    }
+    config.set_protocols(&protocols.iter().map(|protocol| protocol.to_vec()).collect::<Vec<_>>());
+    let connector = OnceLock::new();
+    if let Some(path) = cfg.and_then(|cfg| cfg.ca_bundle.as_ref()) {
+        for cert in certs(path)? {
+            config.root_store.add(&cert).map_err(|err| invalid(path, err))?;
+        }
+        let _ = connector.set(Ok(TlsConnector::from(Arc::new(config.clone()))));
+    }
+    Ok(AlpnConnector { config, connector })
+}
+
+/// The system's CAs are only loaded for the first https connection, so that
+/// upstreams speaking HTTP/2 over plain http don't need any.
+pub struct AlpnConnector {
+    config: ClientConfig,
+    connector: OnceLock<Result<TlsConnector, String>>,
+}
+
+impl AlpnConnector {
+    fn connector(&self) -> Result<TlsConnector, BoxError> {
+        let connector = self.connector.get_or_init(|| {
+            let mut config = self.config.clone();
+            system_roots(&mut config.root_store, &openssl_probe::probe())?;
+            Ok(TlsConnector::from(Arc::new(config)))
+        });
+        connector.clone().map_err(Into::into)
+    }
+}
+
+/// Adds the CAs in the system's OpenSSL bundle or, without one, those in its
+/// certificates directories.
+fn system_roots(roots: &mut RootCertStore, probe: &ProbeResult) -> Result<(), String> {
+    if let Some(path) = &probe.cert_file {
+        let file = open(path).map_err(|err| err.to_string())?;
+        roots.add_pem_file(&mut BufReader::new(file))
+            .map_err(|_| invalid(path, "invalid certificates").to_string())?;
+        return Ok(());
+    }
+    for dir in &probe.cert_dir {
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            // Besides certificates there may be CRLs or other files, which
+            // are skipped.
+            if let Ok(file) = File::open(entry.path()) {
+                let _ = roots.add_pem_file(&mut BufReader::new(file));
+            }
+        }
+    }
+    match roots.is_empty() {
+        true => Err("no system CA certificates found".to_owned()),
+        false => Ok(()),
+    }
+}
+
+/// Connects to upstreams over TCP, wrapped in TLS for https uris. hyper only
+/// speaks HTTP/2 over TLS if the connection says it was negotiated, which
+/// hyper-tls never does, so those offering it connect with rustls instead.
+#[derive(Clone)]
+pub enum UpstreamConnector {
+    Native(HttpsConnector<ProxyConnector>),
+    Alpn(ProxyConnector, Arc<AlpnConnector>),
+}
+
+impl UpstreamConnector {
+    pub fn new(http: ProxyConnector) -> UpstreamConnector {
+        UpstreamConnector::Native(HttpsConnector::new_with_connector(http))
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<UpstreamStream, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), BoxError>> {
+        match self {
+            UpstreamConnector::Native(https) => https.poll_ready(cx),
+            UpstreamConnector::Alpn(http, _) => http.poll_ready(cx).map_err(Into::into),
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match self {
+            UpstreamConnector::Native(https) => {
+                let connecting = https.call(uri);
+                Box::pin(async move { Ok(UpstreamStream::Native(connecting.await?)) })
+            }
+            UpstreamConnector::Alpn(http, tls) => {
+                let https = uri.scheme_str() == Some("https");
+                let host = uri.host().unwrap_or_default().to_owned();
+                let connecting = http.call(uri);
+                let tls = tls.clone();
+                Box::pin(async move {
+                    let tcp = connecting.await?;
+                    if !https {
+                        return Ok(UpstreamStream::Tcp(tcp));
+                    }
+                    let tls = tls.connector()?;
+                    // webpki only verifies certificates for names.
+                    let name = DNSNameRef::try_from_ascii_str(&host)
+                        .map_err(|_| format!("{} isn't a DNS name, which upstreams speaking HTTP/2 over TLS must have", host))?;
+                    Ok(UpstreamStream::Tls(Box::new(tls.connect(name, tcp).await?)))
+                })
+            }
+        }
+    }
+}
+
+pub enum UpstreamStream {
+    Native(MaybeHttpsStream<ProxyStream>),
+    Tcp(ProxyStream),
+    Tls(Box<client::TlsStream<ProxyStream>>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Native(stream) => stream.connected(),
+            UpstreamStream::Tcp(stream) => stream.connected(),
+            UpstreamStream::Tls(stream) => {
+                let (tcp, session) = stream.get_ref();
+                match session.get_alpn_protocol() {
+                    Some(b"h2") => tcp.connected().negotiated_h2(),
+                    _ => tcp.connected(),
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Native(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Native(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Native(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Native(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The PEM certificates in `path`, of which there must be at least one.
+pub fn certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(invalid(path, "no certificates found")),
+    }
+}
+
+/// The first PKCS#8 or RSA private key in the PEM file at `path`.
+pub fn private_key(path: &Path) -> io::Result<PrivateKey> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut BufReader::new(open(path)?));
+    let rsa = pemfile::rsa_private_keys(&mut BufReader::new(open(path)?));
+    pkcs8.into_iter().chain(rsa).flatten().next()
+        .ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|err| io::Error::new(err.kind(), format!("opening {}: {}", path.display(), err)))
+}
+
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|err| io::Error::new(err.kind(), format!("reading {}: {}", path.display(), err)))
+}
+
+fn invalid(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_system_roots() {
+        let dir = std::env::temp_dir().join(format!("system-roots-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("testdata/tls_ca.pem", dir.join("ca.pem")).unwrap();
+        fs::write(dir.join("notes.txt"), "not a certificate").unwrap();
+
+        let mut roots = RootCertStore::empty();
+        let probe = ProbeResult { cert_file: None, cert_dir: vec![dir.clone()] };
+        assert_eq!(system_roots(&mut roots, &probe), Ok(()));
+        assert_eq!(roots.len(), 1);
+
+        let mut roots = RootCertStore::empty();
+        let probe = ProbeResult { cert_file: Some("testdata/tls_ca.pem".into()), cert_dir: vec![] };
+        assert_eq!(system_roots(&mut roots, &probe), Ok(()));
+        assert_eq!(roots.len(), 1);
+
+        let mut roots = RootCertStore::empty();
+        let probe = ProbeResult { cert_file: None, cert_dir: vec![dir.join("missing")] };
+        assert!(system_roots(&mut roots, &probe).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_acceptor() {
+        let cfg = TlsCfg {
+            port: 0,
+            cert: PathBuf::from("testdata/tls_cert.pem"),
+            key: PathBuf::from("testdata/tls_key.pem"),
+        };
+        assert!(acceptor(&cfg).is_ok());
+
+        // The files the other way around.
+        let swapped = TlsCfg { cert: cfg.key.clone(), key: cfg.cert.clone(), ..cfg };
+        let err = acceptor(&swapped).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let missing = TlsCfg { cert: PathBuf::from("testdata/missing.pem"), ..swapped };
+        assert_eq!(acceptor(&missing).err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Serves a fixed response over TLS to clients presenting a certificate
+    /// signed by the test CA, and returns its address.
+    async fn mtls_server() -> std::net::SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, RootCertStore};
+
+        let mut roots = RootCertStore::empty();
+        for cert in certs(Path::new("testdata/tls_ca.pem")).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+        let key = private_key(Path::new("testdata/tls_key.pem")).unwrap();
+        config.set_single_cert(certs(Path::new("testdata/tls_cert.pem")).unwrap(), key).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok").await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_upstream_mtls() {
+        use crate::client::init_upstream_client;
+        use crate::config::ClientCfg;
+        use crate::metrics::Metrics;
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let addr = rt.block_on(mtls_server());
+        let uri: hyper::Uri = format!("https://localhost:{}/", addr.port()).parse().unwrap();
+        let mut cfg = UpstreamTlsCfg {
+            identity: Some((PathBuf::from("testdata/tls_cert.pem"), PathBuf::from("testdata/tls_key.pem"))),
+            ca_bundle: Some(PathBuf::from("testdata/tls_ca.pem")),
+        };
+
+        for &http2 in &[Http2::Off, Http2::Alpn] {
+            let client = init_upstream_client(&Metrics::new(), &ClientCfg::default(), Some(&cfg), http2).unwrap();
+            let res = rt.block_on(client.get(uri.clone())).unwrap();
+            assert_eq!(res.status(), hyper::StatusCode::OK);
+        }
+
+        // The server doesn't let clients without a certificate in.
+        cfg.identity = None;
+        for &http2 in &[Http2::Off, Http2::Alpn] {
+            let client = init_upstream_client(&Metrics::new(), &ClientCfg::default(), Some(&cfg), http2).unwrap();
+            assert!(rt.block_on(client.get(uri.clone())).is_err());
+        }
+
+        // Nor is the server trusted without the CA bundle.
+        let client = crate::client::init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        assert!(rt.block_on(client.get(uri)).is_err());
+    }
+
+    #[test]
+    fn test_upstream_alpn() {
+        use crate::client::init_upstream_client;
+        use crate::config::ClientCfg;
+        use crate::metrics::Metrics;
+        use hyper::service::service_fn;
+        use hyper::{Body, Response, Version};
+
+        // Answers with the version the request was made with.
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let addr = rt.block_on(async {
+            let cfg = TlsCfg {
+                port: 0,
+                cert: PathBuf::from("testdata/tls_cert.pem"),
+                key: PathBuf::from("testdata/tls_key.pem"),
+            };
+            let acceptor = acceptor(&cfg).unwrap();
+            let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let acceptor = acceptor.clone();
+                    tokio::spawn(async move {
+                        let stream = acceptor.accept(stream).await?;
+                        let service = service_fn(|req: hyper::Request<Body>| async move {
+                            Ok::<_, hyper::Error>(Response::new(Body::from(format!("{:?}", req.version()))))
+                        });
+                        hyper::server::conn::Http::new().serve_connection(stream, service).await
+                            .map_err(io::Error::other)
+                    });
+                }
+            });
+            addr
+        });
+        let uri: hyper::Uri = format!("https://localhost:{}/", addr.port()).parse().unwrap();
+        let cfg = UpstreamTlsCfg { identity: None, ca_bundle: Some(PathBuf::from("testdata/tls_ca.pem")) };
+
+        for &(http2, version) in &[(Http2::Off, Version::HTTP_11), (Http2::Alpn, Version::HTTP_2), (Http2::PriorKnowledge, Version::HTTP_2)] {
+            let client = init_upstream_client(&Metrics::new(), &ClientCfg::default(), Some(&cfg), http2).unwrap();
+            let res = rt.block_on(client.get(uri.clone())).unwrap();
+            assert_eq!(res.version(), version);
+            let body = rt.block_on(hyper::body::to_bytes(res.into_body())).unwrap();
+            assert_eq!(body, format!("{:?}", version));
+        }
+
+        // rustls can't verify the certificate of an address.
+        let uri: hyper::Uri = format!("https://127.0.0.1:{}/", addr.port()).parse().unwrap();
+        let client = init_upstream_client(&Metrics::new(), &ClientCfg::default(), Some(&cfg), Http2::Alpn).unwrap();
+        assert!(rt.block_on(client.get(uri)).is_err());
+    }
+}
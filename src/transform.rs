@@ -0,0 +1,226 @@
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// How a source's value is made of its upstream's JSON: a list of steps, each
+/// applied to what the one before made of it, e.g.
+///
+/// ```toml
+/// transform = [{ extract = "$.data[0]" }, { concat = ["$.title", " (#", "$.id", ")"] }]
+/// ```
+#[derive(Clone, Debug)]
+pub struct Transform(Vec<Step>);
+
+/// A step as in the config.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum StepSection {
+    /// The value at a path like `$.facts[0].text`.
+    Extract(String),
+    /// Renames the fields of an object, from the key to the value. No two
+    /// fields can be renamed alike, nor like a field that's kept.
+    Rename(BTreeMap<String, String>),
+    /// Joins the values at paths, which start with `$`, and the other strings
+    /// into a string. A `$$` at the start of a string stands for a `$`.
+    Concat(Vec<String>),
+}
+
+#[derive(Clone, Debug)]
+enum Step {
+    Extract(Path),
+    Rename(BTreeMap<String, String>),
+    Concat(Vec<Part>),
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Path(Path),
+    Literal(String),
+}
+
+/// A path as in the config, and the JSON pointer it's turned into.
+#[derive(Clone, Debug)]
+struct Path {
+    path: String,
+    pointer: String,
+}
+
+impl Transform {
+    /// The error says which step is invalid and why.
+    pub fn new(steps: Vec<StepSection>) -> Result<Transform, String> {
+        steps.into_iter().enumerate()
+            .map(|(i, step)| Step::parse(step).map_err(|err| format!("step {}: {}", i + 1, err)))
+            .collect::<Result<_, _>>()
+            .map(Transform)
+    }
+
+    /// Extracts the value at `path`, like `$.title`.
+    pub fn extract(path: &str) -> Result<Transform, String> {
+        Transform::new(vec![StepSection::Extract(path.to_owned())])
+    }
+
+    /// The error says which step failed and why.
+    pub fn apply(&self, mut value: Value) -> Result<Value, String> {
+        for (i, step) in self.0.iter().enumerate() {
+            value = step.apply(value).map_err(|err| format!("step {}: {}", i + 1, err))?;
+        }
+        Ok(value)
+    }
+}
+
+impl Step {
+    fn parse(step: StepSection) -> Result<Step, String> {
+        Ok(match step {
+            StepSection::Extract(path) => Step::Extract(Path::parse(&path)?),
+            StepSection::Rename(fields) => {
+                let mut renamed = BTreeMap::new();
+                for (from, to) in &fields {
+                    if let Some(other) = renamed.insert(to, from) {
+                        return Err(format!("{:?} and {:?} are both renamed to {:?}", other, from, to));
+                    }
+                }
+                Step::Rename(fields)
+            }
+            StepSection::Concat(parts) => Step::Concat(parts.into_iter().map(|part| {
+                match part.strip_prefix("$$") {
+                    Some(literal) => Ok(Part::Literal(format!("${}", literal))),
+                    None if part.starts_with('$') => Path::parse(&part).map(Part::Path),
+                    None => Ok(Part::Literal(part)),
+                }
+            }).collect::<Result<_, _>>()?),
+        })
+    }
+
+    fn apply(&self, value: Value) -> Result<Value, String> {
+        match self {
+            Step::Extract(path) => Ok(path.get(&value)?.clone()),
+            Step::Rename(fields) => match value {
+                Value::Object(object) => Ok(Value::Object(rename(object, fields)?)),
+                _ => Err("only objects can have their fields renamed".to_owned()),
+            },
+            Step::Concat(parts) => {
+                let mut joined = String::new();
+                for part in parts {
+                    match part {
+                        Part::Path(path) => joined.push_str(&to_text(path.get(&value)?)),
+                        Part::Literal(literal) => joined.push_str(literal),
+                    }
+                }
+                Ok(Value::String(joined))
+            }
+        }
+    }
+}
+
+/// Strings as they are, anything else as JSON.
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn rename(object: Map<String, Value>, fields: &BTreeMap<String, String>) -> Result<Map<String, Value>, String> {
+    for (from, to) in fields {
+        if object.contains_key(from) && object.contains_key(to) && !fields.contains_key(to) {
+            return Err(format!("renaming {:?} to {:?} would replace the {:?} field", from, to, to));
+        }
+    }
+    Ok(object.into_iter()
+        .map(|(key, value)| match fields.get(&key) {
+            Some(renamed) => (renamed.clone(), value),
+            None => (key, value),
+        })
+        .collect())
+}
+
+impl Path {
+    /// `$`, followed by any number of `.key`s and `[index]`es.
+    fn parse(path: &str) -> Result<Path, String> {
+        let invalid = || format!("{:?} isn't a path like $.facts[0].text", path);
+        let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+        let mut pointer = String::new();
+        while !rest.is_empty() {
+            let (segment, remaining) = if let Some(key) = rest.strip_prefix('.') {
+                let end = key.find(['.', '[']).unwrap_or(key.len());
+                (&key[..end], &key[end..])
+            } else if let Some(index) = rest.strip_prefix('[') {
+                let end = index.find(']').ok_or_else(invalid)?;
+                if !index[..end].bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                (&index[..end], &index[end + 1..])
+            } else {
+                return Err(invalid());
+            };
+            if segment.is_empty() {
+                return Err(invalid());
+            }
+            pointer.push('/');
+            pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+            rest = remaining;
+        }
+        Ok(Path { path: path.to_owned(), pointer })
+    }
+
+    fn get<'a>(&self, value: &'a Value) -> Result<&'a Value, String> {
+        value.pointer(&self.pointer).ok_or_else(|| format!("nothing at {}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Deserialize)]
+    struct Section {
+        transform: Vec<StepSection>,
+    }
+
+    fn transform(toml: &str) -> Result<Transform, String> {
+        Transform::new(toml::from_str::<Section>(toml).unwrap().transform)
+    }
+
+    #[test]
+    fn test_apply() {
+        let todo = json!({ "data": [{ "id": 5, "title": "feed the cat", "a/b": true }] });
+        let t = transform(r##"transform = [{ extract = "$.data[0]" }, { concat = ["#", "$.id", ": ", "$.title", " ", "$$5"] }]"##);
+        assert_eq!(t.unwrap().apply(todo.clone()), Ok(json!("#5: feed the cat $5")));
+
+        let t = transform(r#"transform = [{ extract = "$.data[0]" }, { rename = { title = "name" } }, { extract = "$.name" }]"#);
+        assert_eq!(t.unwrap().apply(todo.clone()), Ok(json!("feed the cat")));
+        assert_eq!(Transform::extract("$.data[0].a/b").unwrap().apply(todo.clone()), Ok(json!(true)));
+        assert_eq!(Transform::extract("$").unwrap().apply(todo.clone()), Ok(todo.clone()));
+
+        let err = Transform::extract("$.data[1]").unwrap().apply(todo.clone()).unwrap_err();
+        assert_eq!(err, "step 1: nothing at $.data[1]");
+        let t = transform(r#"transform = [{ extract = "$.data" }, { rename = { title = "name" } }]"#);
+        assert!(t.unwrap().apply(todo.clone()).is_err());
+
+        // Fields can swap names, but not take that of one that's kept.
+        let t = transform(r#"transform = [{ extract = "$.data[0]" }, { rename = { id = "title", title = "id" } }]"#);
+        assert_eq!(t.unwrap().apply(todo.clone()), Ok(json!({ "id": "feed the cat", "title": 5, "a/b": true })));
+        let t = transform(r#"transform = [{ extract = "$.data[0]" }, { rename = { id = "title" } }]"#);
+        let err = t.unwrap().apply(todo).unwrap_err();
+        assert_eq!(err, r#"step 2: renaming "id" to "title" would replace the "title" field"#);
+    }
+
+    #[test]
+    fn test_invalid() {
+        for path in &["data", "$data", "$.", "$..data", "$.data[x]", "$.data[0", "$[0]]"] {
+            assert!(Transform::extract(path).is_err(), "{}", path);
+        }
+        assert!(transform(r#"transform = [{ concat = ["$.ok", "$oops"] }]"#).is_err());
+        let err = transform(r#"transform = [{ rename = { id = "name", title = "name" } }]"#).unwrap_err();
+        assert_eq!(err, r#"step 1: "id" and "title" are both renamed to "name""#);
+        assert!(toml::from_str::<Section>(r#"transform = [{ upper = "$.title" }]"#).is_err());
+    }
+
+    #[test]
+    fn test_to_text() {
+        assert_eq!(to_text(&json!("cat")), "cat");
+        assert_eq!(to_text(&json!(5)), "5");
+        assert_eq!(to_text(&json!({ "a": [1] })), r#"{"a":[1]}"#);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::client::HttpClient;
+use crate::config::{ServerCfg, VaultCfg, VaultSecretCfg};
+use crate::error::{AppError, BoxError};
+use crate::metrics::Metrics;
+use crate::singleflight::Singleflight;
+use crate::state::AppState;
+use crate::Result;
+use arc_swap::ArcSwap;
+use hyper::body::{to_bytes, Bytes};
+use hyper::header::HeaderValue;
+use hyper::{Body, Method, Request};
+use prometheus::IntCounterVec;
+use serde_derive::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::{delay_for, timeout};
+use tracing::{debug, info, warn};
+
+/// While Vault can't be read, the last value is used and reading it again is
+/// only tried this often.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+struct Credential {
+    /// The header value the credential is sent in.
+    header: HeaderValue,
+    refresh_at: Instant,
+    /// The lease of the secret it was read from, if it has a renewable one.
+    lease_id: Option<String>,
+}
+
+/// The body of a KV v2 read, which nests the secret's fields in `data` twice.
+#[derive(Deserialize)]
+struct ReadResponse {
+    #[serde(default)]
+    lease_id: String,
+    #[serde(default)]
+    renewable: bool,
+    data: SecretData,
+}
+
+#[derive(Deserialize)]
+struct SecretData {
+    data: HashMap<String, serde_json::Value>,
+}
+
+/// Reads upstream credentials from Vault's KV v2 secrets engine and reads
+/// them again every `refresh`, so that rotated credentials are picked up
+/// without a reload. Concurrent requests needing a value share one read.
+pub struct VaultCredentials {
+    /// By Vault address, mount, path, field and prefix, so that values for
+    /// other secrets aren't used after a config reload.
+    credentials: Mutex<HashMap<String, Credential>>,
+    reads: Singleflight<String, std::result::Result<HeaderValue, Arc<AppError>>>,
+    read: IntCounterVec,
+}
+
+impl VaultCredentials {
+    pub fn new(metrics: &Metrics) -> VaultCredentials {
+        VaultCredentials {
+            credentials: Mutex::new(HashMap::new()),
+            reads: Singleflight::new(),
+            read: metrics.upstream_vault_reads.clone(),
+        }
+    }
+
+    /// The value of `cfg.header` for a request to `upstream`. If Vault can't
+    /// be read when the value is due to be read again, the last one is used
+    /// until it can.
+    pub async fn header(&self, upstream: &'static str, cfg: &VaultSecretCfg, client: &HttpClient) -> Result<HeaderValue> {
+        let key = key(cfg);
+        let cached = self.credentials.lock().unwrap().get(&key).cloned();
+        if let Some(credential) = cached.filter(|credential| Instant::now() < credential.refresh_at) {
+            return Ok(credential.header);
+        }
+        let (res, _) = self.reads.run(key.clone(), async {
+            let res = read(cfg, client).await;
+            let result = if res.is_ok() { "ok" } else { "error" };
+            self.read.with_label_values(&[upstream, result]).inc();
+            let mut credentials = self.credentials.lock().unwrap();
+            match res {
+                Ok((header, lease_id)) => {
+                    let refresh_at = Instant::now() + cfg.vault.refresh;
+                    credentials.insert(key.clone(), Credential { header: header.clone(), refresh_at, lease_id });
+                    Ok(header)
+                }
+                Err(source) => match credentials.get_mut(&key) {
+                    Some(stale) => {
+                        warn!(upstream, error = %source, "reading credential from vault failed, using the last one");
+                        stale.refresh_at = Instant::now() + RETRY_DELAY;
+                        Ok(stale.header.clone())
+                    }
+                    None => Err(Arc::new(AppError::UpstreamVault { upstream, source })),
+                },
+            }
+        }).await;
+        res.map_err(|err| Arc::try_unwrap(err).unwrap_or_else(AppError::Coalesced))
+    }
+
+    /// Drops the credential sent in `header` after the upstream rejected it,
+    /// so the next request reads it from Vault again.
+    pub fn invalidate(&self, upstream: &'static str, cfg: &VaultSecretCfg, header: &HeaderValue) {
+        let mut credentials = self.credentials.lock().unwrap();
+        let key = key(cfg);
+        if credentials.get(&key).is_some_and(|credential| credential.header == *header) {
+            warn!(upstream, "upstream rejected credential from vault");
+            credentials.remove(&key);
+        }
+    }
+
+    /// Renews the token of `vault`, and the leases of the credentials read
+    /// from it, so that they don't expire while they're in use. A credential
+    /// whose lease can't be renewed is read again when it's next needed.
+    pub async fn renew(&self, vault: &VaultCfg, client: &HttpClient) {
+        match call(vault, client, Method::POST, "auth/token/renew-self", Body::empty()).await {
+            Ok(_) => debug!("renewed vault token"),
+            Err(err) => warn!(%err, "renewing the vault token failed"),
+        }
+        let prefix = format!("{} ", vault.address);
+        let leases = self.credentials.lock().unwrap().iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, credential)| Some((key.clone(), credential.lease_id.clone()?)))
+            .collect::<Vec<_>>();
+        for (key, lease_id) in leases {
+            let body = Body::from(json!({ "lease_id": lease_id }).to_string());
+            if let Err(err) = call(vault, client, Method::PUT, "sys/leases/renew", body).await {
+                warn!(%err, "renewing a vault lease failed, reading its credential again");
+                if let Some(credential) = self.credentials.lock().unwrap().get_mut(&key) {
+                    credential.refresh_at = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+/// Renews the Vault token and leases every `vault.renew` of the config at the
+/// time, until the server shuts down.
+pub async fn keep_renewed(state: Arc<AppState>, cfg: Arc<ArcSwap<ServerCfg>>) {
+    let drained = state.drained();
+    tokio::pin!(drained);
+    loop {
+        // Without renewals, checks again for them after a reload.
+        let every = cfg.load().vault.as_ref().and_then(|vault| vault.renew).unwrap_or(RETRY_DELAY);
+        tokio::select! {
+            _ = delay_for(every) => {}
+            _ = &mut drained => return,
+        }
+        if let Some(vault) = cfg.load().vault.clone().filter(|vault| vault.renew.is_some()) {
+            state.vault.renew(&vault, &state.client).await;
+        }
+    }
+}
+
+fn key(cfg: &VaultSecretCfg) -> String {
+    format!("{} {} {} {} {:?}", cfg.vault.address, cfg.vault.mount, cfg.path, cfg.key, cfg.prefix)
+}
+
+/// Reads the header value of `cfg`, and the lease of the secret it's read
+/// from if it has a renewable one.
+async fn read(cfg: &VaultSecretCfg, client: &HttpClient) -> std::result::Result<(HeaderValue, Option<String>), BoxError> {
+    let path = format!("{}/data/{}", cfg.vault.mount, cfg.path.trim_matches('/'));
+    let body = call(&cfg.vault, client, Method::GET, &path, Body::empty()).await?;
+    let res = serde_json::from_slice::<ReadResponse>(&body)?;
+    let value = res.data.data.get(&cfg.key)
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| format!("{} has no string field {:?}", path, cfg.key))?;
+    info!(%path, "read credential from vault");
+    let mut header = HeaderValue::from_str(&format!("{}{}", cfg.prefix, value))?;
+    header.set_sensitive(true);
+    let (lease_id, renewable) = (res.lease_id, res.renewable);
+    Ok((header, Some(lease_id).filter(|lease_id| renewable && !lease_id.is_empty())))
+}
+
+/// Sends `body` to the API `path` of `vault` with its token, and reads the
+/// answer, which fails unless it's a success.
+async fn call(vault: &VaultCfg, client: &HttpClient, method: Method, path: &str, body: Body) -> std::result::Result<Bytes, BoxError> {
+    let url = format!("{}/v1/{}", vault.address, path);
+    let req = Request::builder()
+        .method(method)
+        .uri(&url)
+        .header("x-vault-token", vault.token.expose())
+        .body(body)?;
+    let send = async {
+        let res = client.request(req).await?;
+        let status = res.status();
+        let body = to_bytes(res.into_body()).await?;
+        if !status.is_success() {
+            debug!(body = %String::from_utf8_lossy(&body), "vault request failed");
+            return Err(BoxError::from(format!("{} answered {}", url, status)));
+        }
+        Ok(body)
+    };
+    timeout(REQUEST_TIMEOUT, send).await.map_err(|_| format!("request to {} timed out", url))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::init_client;
+    use crate::config::{ClientCfg, VaultCfg};
+    use crate::secret::Secret;
+    use httptest::{mappers::*, responders::*, Expectation};
+    use hyper::header::{HeaderName, AUTHORIZATION};
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+
+    fn cfg(server: &httptest::Server, refresh: Duration) -> VaultSecretCfg {
+        VaultSecretCfg {
+            vault: VaultCfg {
+                address: server.url_str("").trim_end_matches('/').to_owned(),
+                token: Secret::from("vault-t0ken"),
+                mount: "secret".to_owned(),
+                refresh,
+                renew: None,
+            },
+            path: "rust-mockito-example/todo".to_owned(),
+            key: "token".to_owned(),
+            header: AUTHORIZATION,
+            prefix: "Bearer ".to_owned(),
+        }
+    }
+
+    fn secret(token: &str) -> impl httptest::responders::Responder {
+        json_encoded(json!({ "data": { "data": { "token": token }, "metadata": { "version": 1 } } }))
+    }
+
+    #[test]
+    fn test_read() {
+        let server = httptest::Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/v1/secret/data/rust-mockito-example/todo"),
+                request::headers(contains_entry(("x-vault-token", "vault-t0ken"))),
+            ])
+            .times(2)
+            .respond_with(cycle(vec![Box::new(secret("first")), Box::new(secret("second"))])));
+
+        let mut rt = Runtime::new().unwrap();
+        let client = init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        let credentials = VaultCredentials::new(&Metrics::new());
+        let cfg = cfg(&server, Duration::from_secs(300));
+
+        // The value is read once and then reused until it's rejected.
+        for _ in 0..2 {
+            let header = rt.block_on(credentials.header("todo", &cfg, &client)).unwrap();
+            assert_eq!(header, "Bearer first");
+            assert!(header.is_sensitive());
+        }
+        credentials.invalidate("todo", &cfg, &HeaderValue::from_static("Bearer first"));
+        let header = rt.block_on(credentials.header("todo", &cfg, &client)).unwrap();
+        assert_eq!(header, "Bearer second");
+    }
+
+    #[test]
+    fn test_read_failure() {
+        let server = httptest::Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/v1/secret/data/rust-mockito-example/todo"))
+            .times(2)
+            .respond_with(cycle(vec![Box::new(secret("first")), Box::new(status_code(503))])));
+
+        let mut rt = Runtime::new().unwrap();
+        let client = init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        let credentials = VaultCredentials::new(&Metrics::new());
+        // Due to be read again right away.
+        let cfg = cfg(&server, Duration::from_millis(0));
+
+        // The last value is used while Vault is unavailable, and it's only
+        // tried again after the retry delay.
+        for _ in 0..3 {
+            let header = rt.block_on(credentials.header("todo", &cfg, &client)).unwrap();
+            assert_eq!(header, "Bearer first");
+        }
+
+        // Without one there's nothing to fall back to.
+        let other = VaultSecretCfg {
+            path: "rust-mockito-example/cats".to_owned(),
+            header: HeaderName::from_static("x-api-key"),
+            prefix: String::new(),
+            ..cfg
+        };
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/v1/secret/data/rust-mockito-example/cats"))
+            .respond_with(json_encoded(json!({ "data": { "data": { "other": "field" } } }))));
+        let res = rt.block_on(credentials.header("todo", &other, &client));
+        assert!(matches!(res, Err(AppError::UpstreamVault { upstream: "todo", .. })));
+    }
+
+    #[test]
+    fn test_renew() {
+        let server = httptest::Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/v1/secret/data/rust-mockito-example/todo"))
+            .times(2)
+            .respond_with(json_encoded(json!({
+                "lease_id": "secret/lease/1",
+                "renewable": true,
+                "data": { "data": { "token": "first" } },
+            }))));
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/v1/auth/token/renew-self"),
+                request::headers(contains_entry(("x-vault-token", "vault-t0ken"))),
+            ])
+            .times(2)
+            .respond_with(json_encoded(json!({ "auth": { "lease_duration": 3600 } }))));
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("PUT", "/v1/sys/leases/renew"),
+                request::body(json_decoded(eq(json!({ "lease_id": "secret/lease/1" })))),
+            ])
+            .times(2)
+            .respond_with(cycle(vec![Box::new(status_code(200)), Box::new(status_code(400))])));
+
+        let mut rt = Runtime::new().unwrap();
+        let client = init_client(&Metrics::new(), &ClientCfg::default()).unwrap();
+        let credentials = VaultCredentials::new(&Metrics::new());
+        let cfg = cfg(&server, Duration::from_secs(300));
+
+        // Renewing the lease keeps the credential, failing to reads it again.
+        for _ in 0..2 {
+            let header = rt.block_on(credentials.header("todo", &cfg, &client)).unwrap();
+            assert_eq!(header, "Bearer first");
+            rt.block_on(credentials.renew(&cfg.vault, &client));
+        }
+        rt.block_on(credentials.header("todo", &cfg, &client)).unwrap();
+    }
+}
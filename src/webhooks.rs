@@ -0,0 +1,383 @@
+//! Webhooks: URLs registered at `POST /webhooks` that every cat fact fetched
+//! is POSTed to, as `{"type":"fact","fact":"..."}` like `/ws` sends it.
+//!
+//! Each subscription gets a secret of its own when it's registered, and every
+//! delivery is signed with it: `X-Webhook-Signature` is `sha256=` and the hex
+//! HMAC-SHA256 of the body. Deliveries wait in a queue of
+//! `webhooks.queue_capacity`, and are retried with exponential backoff, up to
+//! `webhooks.max_attempts` in all and at most `webhooks.max_retry_delay_ms`
+//! apart, after connection errors, timeouts, 429s and server errors.
+
+use crate::body;
+use crate::client::{backoff, status_class};
+use crate::config::{self, ServerCfg};
+use crate::error::{AppError, BoxError};
+use crate::handlers::{fetch_random_fact, invalid_json, param, structured};
+use crate::i18n::Message;
+use crate::metrics::Metrics;
+use crate::problem::Problem;
+use crate::state::AppState;
+use crate::ws::Update;
+use crate::Result;
+use arc_swap::ArcSwap;
+use futures::stream::StreamExt;
+use hmac::{Hmac, Mac};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use prometheus::{IntCounterVec, IntGauge};
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::time::{delay_for, timeout};
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+/// How many deliveries are sent at a time.
+const CONCURRENCY: usize = 8;
+
+/// How many of the facts sent last are remembered, so they aren't sent again.
+const RECENT_FACTS: usize = 64;
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Identifies a delivery, the same for all its attempts, so that receivers
+/// can tell a retry from a new fact.
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+
+/// A subscription as it's answered when it's made.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct Subscription {
+    id: String,
+    url: String,
+    secret: String,
+}
+
+/// A subscription as it's listed, without its secret.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Listed<'a> {
+    id: &'a str,
+    url: &'a str,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NewSubscription {
+    url: String,
+}
+
+struct Delivery {
+    id: String,
+    subscription: Subscription,
+    body: Bytes,
+}
+
+pub struct Webhooks {
+    subscriptions: Mutex<Vec<Subscription>>,
+    /// Held while the subscriptions change, so they're saved in order.
+    changing: AsyncMutex<()>,
+    store: Option<PathBuf>,
+    /// The facts sent lately, oldest first.
+    sent: Mutex<VecDeque<String>>,
+    queue: Mutex<mpsc::Sender<Delivery>>,
+    /// Taken by `deliver_queued`.
+    queued: Mutex<Option<mpsc::Receiver<Delivery>>>,
+    subscribed: IntGauge,
+    deliveries: IntCounterVec,
+    attempts: IntCounterVec,
+}
+
+impl Webhooks {
+    /// Loads the subscriptions from `cfg.store`, if it's set and exists.
+    pub fn new(cfg: &config::Webhooks, metrics: &Metrics) -> std::result::Result<Webhooks, BoxError> {
+        let subscriptions = match &cfg.store {
+            Some(path) if path.exists() => {
+                let subscriptions = fs::read(path).map_err(BoxError::from).and_then(|subscriptions| {
+                    serde_json::from_slice::<Vec<Subscription>>(&subscriptions).map_err(BoxError::from)
+                });
+                subscriptions.map_err(|err| format!("loading webhooks {}: {}", path.display(), err))?
+            }
+            _ => Vec::new(),
+        };
+        metrics.webhook_subscriptions.set(subscriptions.len() as i64);
+        let (queue, queued) = mpsc::channel(cfg.queue_capacity);
+        Ok(Webhooks {
+            subscriptions: Mutex::new(subscriptions),
+            changing: AsyncMutex::new(()),
+            store: cfg.store.clone(),
+            sent: Mutex::new(VecDeque::new()),
+            queue: Mutex::new(queue),
+            queued: Mutex::new(Some(queued)),
+            subscribed: metrics.webhook_subscriptions.clone(),
+            deliveries: metrics.webhook_deliveries.clone(),
+            attempts: metrics.webhook_delivery_attempts.clone(),
+        })
+    }
+
+    /// Queues `fact` for every subscriber, unless it's one of the facts sent
+    /// lately.
+    fn notify_new(&self, fact: &str) {
+        {
+            let mut sent = self.sent.lock().unwrap();
+            if sent.iter().any(|sent| sent == fact) {
+                return debug!("not sending a fact to the webhooks again");
+            }
+            if sent.len() == RECENT_FACTS {
+                sent.pop_front();
+            }
+            sent.push_back(fact.to_owned());
+        }
+        self.notify(&Update::Fact { fact: fact.to_owned() });
+    }
+
+    /// Queues `update` for every subscriber, dropping the deliveries there's
+    /// no room for.
+    fn notify(&self, update: &Update) {
+        let body = match serde_json::to_vec(update) {
+            Ok(body) => Bytes::from(body),
+            Err(err) => return warn!(%err, ?update, "encoding a webhook delivery failed"),
+        };
+        let subscriptions = self.subscriptions.lock().unwrap();
+        let mut queue = self.queue.lock().unwrap();
+        for subscription in subscriptions.iter() {
+            let delivery = Delivery { id: random_hex(16), subscription: subscription.clone(), body: body.clone() };
+            if queue.try_send(delivery).is_err() {
+                warn!(url = %subscription.url, "webhook queue is full, dropping a delivery");
+                self.deliveries.with_label_values(&["dropped"]).inc();
+            }
+        }
+    }
+
+    /// Saves the subscriptions changed by `change`, and makes them the
+    /// current ones if that succeeds.
+    async fn change<T>(&self, change: impl FnOnce(&mut Vec<Subscription>) -> Result<T>) -> Result<T> {
+        let _changing = self.changing.lock().await;
+        let mut subscriptions = self.subscriptions.lock().unwrap().clone();
+        let changed = change(&mut subscriptions)?;
+        let subscriptions = self.save(subscriptions).await?;
+        self.subscribed.set(subscriptions.len() as i64);
+        *self.subscriptions.lock().unwrap() = subscriptions;
+        Ok(changed)
+    }
+
+    /// Writes the subscriptions to the store, if there is one, replacing it
+    /// in one go so that a crash halfway doesn't lose them. Only its owner
+    /// may read it, as it has the secrets.
+    async fn save(&self, subscriptions: Vec<Subscription>) -> Result<Vec<Subscription>> {
+        let path = match &self.store {
+            Some(path) => path.clone(),
+            None => return Ok(subscriptions),
+        };
+        tokio::task::spawn_blocking(move || {
+            // Created anew, so that one left behind doesn't keep its mode.
+            let tmp = path.with_extension("tmp");
+            let _ = fs::remove_file(&tmp);
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(&tmp)?.write_all(&serde_json::to_vec_pretty(&subscriptions)?)?;
+            fs::rename(&tmp, &path)?;
+            Ok(subscriptions)
+        })
+        .await?
+    }
+}
+
+/// Subscribes the `url` in the JSON body, and answers with the subscription's
+/// `id`, `url` and the `secret` its deliveries are signed with, which isn't
+/// shown again.
+#[utoipa::path(post, path = "/webhooks", tag = "webhooks", security(("admin" = [])), request_body = NewSubscription, responses(
+        (status = 201, description = "The subscription", headers(("location" = String, description = "The subscription's path")),
+            content((Subscription = "application/json"), (Subscription = "application/msgpack"))),
+        (status = 400, description = "The URL isn't http(s)", body = Problem, content_type = "application/problem+json"),
+))]
+pub async fn subscribe(req: Request<Body>, state: &AppState, cfg: &ServerCfg) -> Result<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    let body = body::read(body, cfg.max_body_bytes).await?;
+    let req = Request::from_parts(parts, Body::empty());
+    let url = callback_url(&body)?;
+    let subscription = Subscription { id: random_hex(8), url, secret: random_hex(32) };
+    state.webhooks.change(|subscriptions| {
+        subscriptions.push(subscription.clone());
+        Ok(())
+    }).await?;
+    info!(id = %subscription.id, url = %subscription.url, "subscribed webhook");
+    let mut res = structured(&req, &subscription)?;
+    *res.status_mut() = StatusCode::CREATED;
+    res.headers_mut().insert(LOCATION, format!("/webhooks/{}", subscription.id).parse()?);
+    Ok(res)
+}
+
+/// The subscriptions, without their secrets.
+#[utoipa::path(get, path = "/webhooks", tag = "webhooks", security(("admin" = [])), responses(
+        (status = 200, description = "The subscriptions", content((Vec<Listed> = "application/json"), (Vec<Listed> = "application/msgpack"))),
+))]
+pub fn subscriptions(req: &Request<Body>, state: &AppState) -> Result<Response<Body>> {
+    let subscriptions = state.webhooks.subscriptions.lock().unwrap();
+    let listed = subscriptions.iter()
+        .map(|subscription| Listed { id: &subscription.id, url: &subscription.url })
+        .collect::<Vec<_>>();
+    structured(req, &listed)
+}
+
+/// Drops the subscription with the `id` in the path. Deliveries already
+/// queued for it are still sent.
+#[utoipa::path(delete, path = "/webhooks/{id}", tag = "webhooks", security(("admin" = [])), params(
+        ("id" = String, Path, description = "The subscription's id"),
+), responses(
+        (status = 204, description = "The subscription was dropped"),
+        (status = 404, description = "There's no such subscription", body = Problem, content_type = "application/problem+json"),
+))]
+pub async fn unsubscribe(req: &Request<Body>, state: &AppState) -> Result<Response<Body>> {
+    let id = param(req, "id").unwrap_or_default();
+    state.webhooks.change(|subscriptions| {
+        let index = subscriptions.iter().position(|subscription| subscription.id == id).ok_or(AppError::NotFound)?;
+        subscriptions.remove(index);
+        Ok(())
+    }).await?;
+    info!(id, "unsubscribed webhook");
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty())?)
+}
+
+/// Fetches a fact every `webhooks.poll_interval` of the config at the time,
+/// while there are subscriptions, and sends it to them unless it was lately,
+/// until the server shuts down.
+pub async fn poll_facts(state: Arc<AppState>, cfg: Arc<ArcSwap<ServerCfg>>) {
+    let drained = state.drained();
+    tokio::pin!(drained);
+    loop {
+        tokio::select! {
+            _ = delay_for(cfg.load().webhooks.poll_interval) => {}
+            _ = &mut drained => return,
+        }
+        if state.webhooks.subscriptions.lock().unwrap().is_empty() {
+            continue;
+        }
+        match fetch_random_fact(&state, &cfg.load().cats).await {
+            Ok(fact) => state.webhooks.notify_new(&fact),
+            Err(err) => warn!(%err, "fetching a fact for the webhooks failed"),
+        }
+    }
+}
+
+/// Sends the queued deliveries, with the webhook settings of the config at
+/// the time, until the server shuts down. Only the first call sends any.
+pub async fn deliver_queued(state: Arc<AppState>, cfg: Arc<ArcSwap<ServerCfg>>) {
+    let queued = match state.webhooks.queued.lock().unwrap().take() {
+        Some(queued) => queued,
+        None => return,
+    };
+    queued
+        .take_until(state.drained())
+        .for_each_concurrent(CONCURRENCY, |delivery| {
+            let (state, cfg) = (state.clone(), cfg.load_full());
+            async move { deliver(&state, &cfg.webhooks, delivery).await }
+        })
+        .await
+}
+
+async fn deliver(state: &AppState, cfg: &config::Webhooks, delivery: Delivery) {
+    let webhooks = &state.webhooks;
+    let url = &delivery.subscription.url;
+    let signature = sign(&delivery.subscription.secret, &delivery.body);
+    for attempt in 1..=cfg.retry.max_attempts {
+        let req = Request::post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(DELIVERY_HEADER, &delivery.id)
+            .body(Body::from(delivery.body.clone()));
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => {
+                warn!(%url, %err, "building a webhook delivery failed");
+                break;
+            }
+        };
+        let res = match timeout(cfg.timeout, state.client.request(req)).await {
+            Ok(Ok(res)) => Ok(res.status()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err("timed out".to_owned()),
+        };
+        let class = res.as_ref().map_or("error", |status| status_class(*status));
+        webhooks.attempts.with_label_values(&[class]).inc();
+        match res {
+            Ok(status) if status.is_success() => {
+                debug!(%url, attempt, "delivered to webhook");
+                webhooks.deliveries.with_label_values(&["delivered"]).inc();
+                return;
+            }
+            Ok(status) if !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS => {
+                warn!(%url, %status, "webhook rejected a delivery");
+                break;
+            }
+            Ok(status) => debug!(%url, attempt, %status, "webhook delivery failed"),
+            Err(err) => debug!(%url, attempt, %err, "webhook delivery failed"),
+        }
+        if attempt < cfg.retry.max_attempts {
+            delay_for(backoff(&cfg.retry, attempt)).await;
+        }
+    }
+    warn!(%url, "giving up on a webhook delivery");
+    webhooks.deliveries.with_label_values(&["failed"]).inc();
+}
+
+/// The `url` of a request to subscribe, if it's an absolute HTTP(S) URL, or
+/// a 400 saying what's wrong with it.
+fn callback_url(body: &[u8]) -> Result<String> {
+    let new: NewSubscription = serde_json::from_slice(body).map_err(|err| invalid_json(&err))?;
+    let uri = new.url.parse::<Uri>().map_err(|err| {
+        AppError::BadRequest(Message::new("invalid_url", "invalid url: {error}").arg("error", err))
+    })?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") if uri.host().is_some() => Ok(new.url),
+        _ => Err(AppError::BadRequest(Message::new("url_not_http", "the url must be an absolute http or https URL"))),
+    }
+}
+
+/// The `X-Webhook-Signature` of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+/// `len` random bytes in hex.
+pub(crate) fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill(&mut bytes[..]);
+    hex(&bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_callback_url() {
+        assert_eq!(callback_url(br#"{"url":"https://example.com/hook"}"#).unwrap(), "https://example.com/hook");
+        assert_eq!(callback_url(br#"{"url":"http://127.0.0.1:8080/"}"#).unwrap(), "http://127.0.0.1:8080/");
+        for body in &[&br#"{"url":"/hook"}"#[..], br#"{"url":"ftp://example.com/"}"#, br#"{"url":5}"#, br#"{}"#, b"url"] {
+            assert!(callback_url(body).is_err(), "{}", String::from_utf8_lossy(body));
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        // From RFC 4231's second test case.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+    }
+}
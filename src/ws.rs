@@ -0,0 +1,186 @@
+use crate::config::ServerCfg;
+use crate::error::AppError;
+use crate::problem::Problem;
+use crate::state::AppState;
+use crate::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, CONTENT_LENGTH, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
+    TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_derive::Serialize;
+use sha1::{Digest, Sha1};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::time::interval;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, warn};
+
+/// How many updates a slow client can fall behind before it misses some.
+pub const UPDATES_CAPACITY: usize = 64;
+
+/// Appended to a client's key to make the `Sec-WebSocket-Accept`, as per
+/// RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// What `/ws` pushes to its clients, as JSON text messages like
+/// `{"type":"fact","fact":"..."}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    /// A cat fact that was just fetched.
+    Fact { fact: String },
+    /// A todo that was created, changed or deleted through this service;
+    /// new todos have no id, since the upstream picks it.
+    Todo { method: String, id: Option<u64>, status: u16 },
+}
+
+/// The channel updates are sent to every connected client through.
+pub fn channel() -> broadcast::Sender<String> {
+    broadcast::channel(UPDATES_CAPACITY).0
+}
+
+/// Sends `update` to the clients connected right now, if any.
+pub fn publish(state: &AppState, update: Update) {
+    match serde_json::to_string(&update) {
+        // Failing just means no one's connected.
+        Ok(update) => drop(state.updates.send(update)),
+        Err(err) => warn!(%err, ?update, "encoding an update failed"),
+    }
+}
+
+/// Upgrades the connection to a WebSocket that gets every `Update` from
+/// then on. It's pinged every `websocket.ping_interval`, and closed if it
+/// hasn't answered the last ping by the next one, or once the server shuts
+/// down. Anything the client sends is ignored.
+#[utoipa::path(get, path = "/ws", tag = "facts", responses(
+        (status = 101, description = "Switched to a WebSocket that gets JSON updates"),
+        (status = 426, description = "The request isn't a WebSocket handshake", body = Problem, content_type = "application/problem+json"),
+))]
+pub fn websocket(req: Request<Body>, state: Arc<AppState>, cfg: &ServerCfg) -> Result<Response<Body>> {
+    let key = handshake_key(req.headers()).ok_or(AppError::UpgradeRequired)?;
+    let accept = STANDARD.encode(Sha1::new().chain_update(key).chain_update(ACCEPT_GUID).finalize());
+    // Subscribed before answering, so no update sent from then on is missed.
+    let updates = state.updates.subscribe();
+    let ping_interval = cfg.websocket.ping_interval;
+    tokio::spawn(async move {
+        let upgraded = match req.into_body().on_upgrade().await {
+            Ok(upgraded) => upgraded,
+            Err(err) => return warn!(%err, "upgrading to a WebSocket failed"),
+        };
+        let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        debug!("WebSocket client connected");
+        match serve(socket, updates, ping_interval, &state).await {
+            Ok(()) => debug!("WebSocket client disconnected"),
+            Err(err) => debug!(%err, "WebSocket connection failed"),
+        }
+    });
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())?)
+}
+
+/// Whether `req` opens a WebSocket at `/ws`: a GET without a body that asks
+/// to upgrade to one.
+pub(crate) fn is_handshake(req: &Request<Body>) -> bool {
+    let headers = req.headers();
+    let bodiless = !headers.contains_key(TRANSFER_ENCODING) && headers.get(CONTENT_LENGTH).is_none_or(|len| len == "0");
+    req.method() == Method::GET && req.uri().path() == "/ws" && bodiless && handshake_key(headers).is_some()
+}
+
+/// The `Sec-WebSocket-Key` of a request to open a version 13 WebSocket, or
+/// `None` if it isn't one.
+fn handshake_key(headers: &HeaderMap) -> Option<&[u8]> {
+    let has_token = |name, token: &str| {
+        headers.get_all(name).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    let version = headers.get(SEC_WEBSOCKET_VERSION).is_some_and(|version| version == "13");
+    if !(has_token(UPGRADE, "websocket") && has_token(CONNECTION, "upgrade") && version) {
+        return None;
+    }
+    headers.get(SEC_WEBSOCKET_KEY).map(HeaderValue::as_bytes)
+}
+
+async fn serve<S>(
+    socket: WebSocketStream<S>,
+    mut updates: broadcast::Receiver<String>,
+    ping_interval: Duration,
+    state: &AppState,
+) -> std::result::Result<(), WsError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut sink, mut messages) = socket.split();
+    let mut pings = interval(ping_interval);
+    // The first tick is right away.
+    pings.tick().await;
+    let drained = state.drained();
+    tokio::pin!(drained);
+    let mut awaiting_pong = false;
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => sink.send(Message::Text(update)).await?,
+                Err(RecvError::Lagged(missed)) => debug!(missed, "WebSocket client fell behind"),
+                Err(RecvError::Closed) => break,
+            },
+            // Pings are answered by the socket itself as it reads them.
+            message = messages.next() => match message {
+                Some(Ok(Message::Pong(_))) => awaiting_pong = false,
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err),
+            },
+            _ = pings.tick() => {
+                if awaiting_pong {
+                    debug!("WebSocket client stopped answering pings");
+                    break;
+                }
+                sink.send(Message::Ping(Vec::new())).await?;
+                awaiting_pong = true;
+            }
+            _ = &mut drained => break,
+        }
+    }
+    sink.send(Message::Close(None)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_key() {
+        let mut headers = HeaderMap::new();
+        headers.insert(UPGRADE, HeaderValue::from_static("WebSocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        headers.insert(SEC_WEBSOCKET_KEY, HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert_eq!(handshake_key(&headers), Some(&b"dGhlIHNhbXBsZSBub25jZQ=="[..]));
+
+        headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("8"));
+        assert_eq!(handshake_key(&headers), None);
+        headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        headers.remove(CONNECTION);
+        assert_eq!(handshake_key(&headers), None);
+    }
+
+    #[test]
+    fn test_update() {
+        let update = Update::Todo { method: "PATCH".to_owned(), id: Some(5), status: 200 };
+        assert_eq!(serde_json::to_string(&update).unwrap(), r#"{"type":"todo","method":"PATCH","id":5,"status":200}"#);
+    }
+}
@@ -0,0 +1,2 @@
+// A stand-in for the bundle of swagger-ui-dist, for the tests.
+window.SwaggerUIBundle = () => {};